@@ -41,6 +41,131 @@
  */
 #define SHOREBIRD_UPDATE_IS_BAD_PATCH 3
 
+/**
+ * No update check was made because the last one was within the configured
+ * check interval.
+ */
+#define SHOREBIRD_UPDATE_THROTTLED 4
+
+/**
+ * An update is available but was not downloaded because the download policy
+ * doesn't allow downloading over the current connection.
+ */
+#define SHOREBIRD_UPDATE_DEFERRED 5
+
+/**
+ * The update was cancelled by [shorebird_cancel_update] before it finished.
+ */
+#define SHOREBIRD_UPDATE_CANCELLED 6
+
+/**
+ * Checking the server for a patch.
+ */
+#define SHOREBIRD_PHASE_CHECKING 0
+
+/**
+ * Downloading a patch.
+ */
+#define SHOREBIRD_PHASE_DOWNLOADING 1
+
+/**
+ * Applying a downloaded patch to the release.
+ */
+#define SHOREBIRD_PHASE_INFLATING 2
+
+/**
+ * Checking the patched result's hash.
+ */
+#define SHOREBIRD_PHASE_VERIFYING 3
+
+/**
+ * Installing the patch for the next launch.
+ */
+#define SHOREBIRD_PHASE_INSTALLING 4
+
+/**
+ * The host doesn't know what kind of connection the device has.
+ */
+#define SHOREBIRD_CONNECTIVITY_UNKNOWN 0
+
+/**
+ * The device has a connection without data charges, e.g. Wi-Fi.
+ */
+#define SHOREBIRD_CONNECTIVITY_UNMETERED 1
+
+/**
+ * The device has a connection which may cost the user money, e.g. cellular.
+ */
+#define SHOREBIRD_CONNECTIVITY_METERED 2
+
+/**
+ * The update didn't fail.
+ */
+#define SHOREBIRD_ERROR_NONE 0
+
+/**
+ * The update failed for a reason not covered by the other SHOREBIRD_ERROR_*
+ * values.
+ */
+#define SHOREBIRD_ERROR_UNKNOWN 1
+
+/**
+ * The server couldn't be reached or responded with an error status.
+ */
+#define SHOREBIRD_ERROR_NETWORK 2
+
+/**
+ * The server's response didn't make sense.
+ */
+#define SHOREBIRD_ERROR_BAD_SERVER_RESPONSE 3
+
+/**
+ * The downloaded patch couldn't be applied to the release.
+ */
+#define SHOREBIRD_ERROR_INFLATE_FAILED 4
+
+/**
+ * The patched release didn't have the hash the server said it would.
+ */
+#define SHOREBIRD_ERROR_HASH_MISMATCH 5
+
+/**
+ * The patch's signature was missing or invalid.
+ */
+#define SHOREBIRD_ERROR_SIGNATURE_INVALID 6
+
+/**
+ * The device ran out of storage.
+ */
+#define SHOREBIRD_ERROR_DISK_FULL 7
+
+/**
+ * Reading or writing the updater's files failed.
+ */
+#define SHOREBIRD_ERROR_STORAGE 8
+
+/**
+ * shorebird_init hasn't been called (or failed).
+ */
+#define SHOREBIRD_ERROR_NOT_INITIALIZED 9
+
+/**
+ * Another update was already running.
+ */
+#define SHOREBIRD_ERROR_ALREADY_IN_PROGRESS 10
+
+/**
+ * Gave up waiting for a running update to finish.
+ */
+#define SHOREBIRD_ERROR_TIMED_OUT 11
+
+/**
+ * A response being reported by the host. Opaque to C, which only passes it
+ * back to `shorebird_transport_response_set_status` and
+ * `shorebird_transport_response_write`.
+ */
+typedef struct TransportResponse TransportResponse;
+
 /**
  * Struct containing configuration parameters for the updater.
  * Passed to all updater functions.
@@ -99,8 +224,95 @@ typedef struct FileCallbacks {
 typedef struct UpdateResult {
   int32_t status;
   const char *message;
+  /**
+   * Why the update failed, one of the SHOREBIRD_ERROR_* values. Only set if
+   * `status` is SHOREBIRD_UPDATE_ERROR, otherwise SHOREBIRD_ERROR_NONE.
+   */
+  int32_t error;
 } UpdateResult;
 
+/**
+ * Called with the result of an update started by [shorebird_update_async].
+ * The callback owns `result` and must free it with
+ * [shorebird_free_update_result].
+ */
+typedef void (*UpdateCallback)(void *user_data,
+                               const struct UpdateResult *result);
+
+/**
+ * Describes the update in progress, see [shorebird_running_update].
+ */
+typedef struct RunningUpdateStatus {
+  /**
+   * Whether an update is running. The other fields are only meaningful if
+   * this is true.
+   */
+  bool in_progress;
+  /**
+   * One of the SHOREBIRD_PHASE_* values.
+   */
+  int32_t phase;
+  /**
+   * When the update started, in seconds since the Unix epoch.
+   */
+  uint64_t started_at;
+  /**
+   * The patch being installed, or -1 if the server hasn't told us yet.
+   */
+  int64_t patch_number;
+} RunningUpdateStatus;
+
+/**
+ * Callbacks for routing the updater's network requests through the host app.
+ * Registered with `shorebird_set_transport`.
+ *
+ * Each callback is given the `user_data` pointer passed to
+ * `shorebird_set_transport`, the extra `headers` to send as a JSON object of
+ * header names to values, and a `response` handle. Before writing any of
+ * the response body with `shorebird_transport_response_write`, the host must
+ * report the response status with `shorebird_transport_response_set_status`
+ * (and, before that, its length with
+ * `shorebird_transport_response_set_content_length` if known). The
+ * `response` handle is only valid until the callback returns.
+ */
+typedef struct TransportCallbacks {
+  /**
+   * Sends a POST request to `url` with the JSON `body` (Content-Type
+   * application/json). Returns true if the whole response was received,
+   * false if the request failed (e.g. no internet connection).
+   */
+  bool (*post_json)(void *user_data,
+                    const char *url,
+                    const char *headers,
+                    const char *body,
+                    struct TransportResponse *response);
+  /**
+   * Sends a GET request to `url`. If `range_start` is non-zero the host
+   * should send a `Range: bytes=<range_start>-` header and, if `if_range`
+   * is not null, an `If-Range: <if_range>` header. If the server can't
+   * satisfy the range (status 416), the updater calls this again for the
+   * whole file. Returns true if the whole response was received, false if
+   * the request failed.
+   */
+  bool (*get)(void *user_data,
+              const char *url,
+              const char *headers,
+              uint64_t range_start,
+              const char *if_range,
+              struct TransportResponse *response);
+} TransportCallbacks;
+
+/**
+ * Called with the progress of an update. `phase` is one of the
+ * SHOREBIRD_PHASE_* values, `bytes_received` is how many bytes that phase has
+ * handled so far and `total_bytes` is how many it will handle in total, or
+ * -1 if unknown.
+ */
+typedef void (*ProgressCallback)(void *user_data,
+                                 int32_t phase,
+                                 uint64_t bytes_received,
+                                 int64_t total_bytes);
+
 #ifdef __cplusplus
 extern "C" {
 #endif // __cplusplus
@@ -166,6 +378,22 @@ SHOREBIRD_EXPORT bool shorebird_check_for_update(void);
 SHOREBIRD_EXPORT
 bool shorebird_check_for_downloadable_update(const char *c_channel);
 
+/**
+ * Like [shorebird_check_for_downloadable_update], but returns everything the
+ * server told us about the latest patch as a JSON object, e.g.:
+ *
+ * {"update_available": true, "patch": {"number": 2, "size": 1234,
+ *  "hash": "...", "is_signed": false, "is_known_bad": false,
+ *  "is_already_installed": false, "metadata": null},
+ *  "rolled_back_patch_numbers": []}
+ *
+ * `patch` is null if there is no patch for this release. Returns NULL if the
+ * check failed. The caller must free the returned string with
+ * [shorebird_free_string].
+ */
+SHOREBIRD_EXPORT
+char *shorebird_check_for_update_details(const char *c_channel);
+
 /**
  * Synchronously download an update if one is available.
  */
@@ -182,11 +410,112 @@ SHOREBIRD_EXPORT void shorebird_update(void);
 SHOREBIRD_EXPORT
 const struct UpdateResult *shorebird_update_with_result(const char *c_channel);
 
+/**
+ * Like [shorebird_update_with_result], but checks for an update even if the
+ * last check was within the configured check interval. Intended for checks
+ * the user asked for.
+ */
+SHOREBIRD_EXPORT
+const struct UpdateResult *shorebird_force_update_with_result(const char *c_channel);
+
+/**
+ * Like [shorebird_update_with_result], but if an update is already running
+ * (e.g. one started by [shorebird_start_update_thread]), waits for it to
+ * finish and returns its result instead of an error. Waits at most
+ * `timeout_ms` milliseconds, or forever if `timeout_ms` is negative.
+ */
+SHOREBIRD_EXPORT
+const struct UpdateResult *shorebird_update_or_join_with_result(const char *c_channel,
+                                                                int64_t timeout_ms);
+
+/**
+ * Like [shorebird_update_with_result], but runs the update on a thread owned
+ * by the updater and returns immediately. `callback`, if not null, is called
+ * from that thread with the result and `user_data` once the update finishes,
+ * so both must be safe to use from any thread.
+ * Returns false, without calling `callback`, if the update could not be
+ * started.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_update_async(const char *c_channel,
+                            UpdateCallback callback,
+                            void *user_data);
+
+/**
+ * Install a patch delivered by something other than Shorebird's servers,
+ * e.g. a device management system. `c_path` is the patch file as the servers
+ * would serve it, `c_hash` the hex-encoded sha256 hash of the patched
+ * release, and `c_signature` the patch's signature, or NULL if unsigned. The
+ * patch is checked and recorded just as if it had been downloaded.
+ *
+ * Returns an [UpdateResult]: SHOREBIRD_UPDATE_INSTALLED if the patch was
+ * installed, SHOREBIRD_NO_UPDATE if it was already installed, or
+ * SHOREBIRD_UPDATE_IS_BAD_PATCH if it previously failed to launch.
+ */
+SHOREBIRD_EXPORT
+const struct UpdateResult *shorebird_install_patch_from_file(const char *c_path,
+                                                             uintptr_t patch_number,
+                                                             const char *c_hash,
+                                                             const char *c_signature);
+
 /**
  * Start a thread to download an update if one is available.
  */
 SHOREBIRD_EXPORT void shorebird_start_update_thread(void);
 
+/**
+ * Describe the update in progress, if any, e.g. one started by
+ * [shorebird_start_update_thread]. Never waits for the update, so hosts can
+ * use this to avoid starting an update while another is running.
+ */
+SHOREBIRD_EXPORT struct RunningUpdateStatus shorebird_running_update(void);
+
+/**
+ * Ask the update in progress, if any, to stop. The update returns
+ * SHOREBIRD_UPDATE_CANCELLED and removes anything it had downloaded.
+ * Returns false if no update was running.
+ */
+SHOREBIRD_EXPORT bool shorebird_cancel_update(void);
+
+/**
+ * Stop launching a patch installed since the last successful launch, so
+ * that the next launch uses the last successfully launched patch (or the
+ * release, if there is none). If `mark_bad` is true, the abandoned patch is
+ * never installed again. Returns false if rolling back failed.
+ */
+SHOREBIRD_EXPORT bool shorebird_roll_back_to_last_booted_patch(bool mark_bad);
+
+/**
+ * Stop launching any patch, so that the next launch uses the release as
+ * shipped. If `mark_bad` is true, the abandoned patches are never installed
+ * again. Returns false if rolling back failed.
+ */
+SHOREBIRD_EXPORT bool shorebird_roll_back_to_release(bool mark_bad);
+
+/**
+ * Start checking for updates in the background every
+ * `scheduled_check_minutes`, as set in shorebird.yaml, until
+ * [shorebird_stop_update_scheduler] is called. Checks wait for (rather than
+ * overlap with) any other update, and happen less often after failures.
+ * Returns false if the scheduler could not be started, e.g. because
+ * `scheduled_check_minutes` is not set.
+ */
+SHOREBIRD_EXPORT bool shorebird_start_update_scheduler(void);
+
+/**
+ * Stop the scheduler started by [shorebird_start_update_scheduler]. Any
+ * update it already started carries on. Returns false if it wasn't running.
+ */
+SHOREBIRD_EXPORT bool shorebird_stop_update_scheduler(void);
+
+/**
+ * Report the kind of network connection the device currently has, one of
+ * the SHOREBIRD_CONNECTIVITY_* values. Hosts should call this whenever the
+ * connection changes if shorebird.yaml sets a download_policy other than
+ * `any`. Returns false if `connectivity` is not recognized.
+ */
+SHOREBIRD_EXPORT bool shorebird_set_connectivity(int32_t connectivity);
+
 /**
  * Update the base URL for patch checking and downloading.
  * The base_url parameter must be a valid URL string (e.g., "https://api.example.com").
@@ -195,6 +524,98 @@ SHOREBIRD_EXPORT void shorebird_start_update_thread(void);
 SHOREBIRD_EXPORT
 bool shorebird_update_base_url(const char *c_base_url);
 
+/**
+ * Sets a header to send with every request (patch checks, downloads and
+ * events), replacing any previous value for that header. Passing a null
+ * `c_value` removes the header. Must be called after `shorebird_init`.
+ * Returns true if the header was updated, false otherwise (e.g. if the name
+ * or value is not a valid HTTP header).
+ */
+SHOREBIRD_EXPORT
+bool shorebird_set_http_header(const char *c_name,
+                               const char *c_value);
+
+/**
+ * Sends `Authorization: Bearer <c_token>` with every request, e.g. after the
+ * user logs in. Passing a null `c_token` stops sending the header.
+ * Must be called after `shorebird_init`. Returns true on success.
+ */
+SHOREBIRD_EXPORT bool shorebird_set_auth_token(const char *c_token);
+
+/**
+ * Routes all of the updater's network requests through the given host
+ * callbacks instead of the updater's built-in HTTP client. Must be called
+ * after `shorebird_init`. `user_data` is passed back to every callback.
+ *
+ * The callbacks may be called from any thread (including the update
+ * thread), so they and `user_data` must be safe to use from any thread.
+ * Returns true if the transport was set, false otherwise.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_set_transport(struct TransportCallbacks callbacks,
+                             void *user_data);
+
+/**
+ * Registers `callback` to be told how updates are progressing, or with a
+ * null `callback`, removes any registered callback. Must be called after
+ * `shorebird_init`. `user_data` is passed back to every call.
+ *
+ * The callback is called from whichever thread is running the update, so it
+ * and `user_data` must be safe to use from any thread. It should return
+ * quickly, as the update waits for it.
+ * Returns true if the callback was set, false otherwise.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_set_progress_callback(ProgressCallback callback,
+                                     void *user_data);
+
+/**
+ * Reports the Content-Length of a response, if the host knows it, so that
+ * download progress has a total. Must be called before
+ * `shorebird_transport_response_set_status`. Returns false if the updater no
+ * longer wants the response, in which case the host should cancel the
+ * request.
+ *
+ * # Safety
+ *
+ * `response` must be the handle passed to the currently running transport
+ * callback.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_transport_response_set_content_length(struct TransportResponse *response,
+                                                     uint64_t content_length);
+
+/**
+ * Reports the HTTP status of a response, along with its ETag header if it
+ * has one (otherwise null). Must be called before writing any of the body.
+ * Returns false if the updater no longer wants the response, in which case
+ * the host should cancel the request.
+ *
+ * # Safety
+ *
+ * `response` must be the handle passed to the currently running transport
+ * callback. `etag` must be null or a valid C string.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_transport_response_set_status(struct TransportResponse *response,
+                                             int32_t status,
+                                             const char *etag);
+
+/**
+ * Writes the next `length` bytes of a response's body. Returns false if the
+ * host should stop sending the body (e.g. because the download has grown
+ * past its maximum size), in which case the host should cancel the request.
+ *
+ * # Safety
+ *
+ * `response` must be the handle passed to the currently running transport
+ * callback. `data` must point to at least `length` readable bytes.
+ */
+SHOREBIRD_EXPORT
+bool shorebird_transport_response_write(struct TransportResponse *response,
+                                        const uint8_t *data,
+                                        uintptr_t length);
+
 /**
  * Tell the updater that we're launching from what it told us was the
  * next patch to boot from. This will copy the next boot patch to be the
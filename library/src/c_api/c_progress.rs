@@ -0,0 +1,90 @@
+// This file's job is to pass update progress to an observer registered
+// through the C API.
+
+use crate::{ProgressObserver, UpdatePhase, UpdateProgress};
+
+use super::{
+    ProgressCallback, SHOREBIRD_PHASE_CHECKING, SHOREBIRD_PHASE_DOWNLOADING,
+    SHOREBIRD_PHASE_INFLATING, SHOREBIRD_PHASE_INSTALLING, SHOREBIRD_PHASE_VERIFYING,
+};
+
+/// A `ProgressObserver` which calls a callback registered by the host.
+#[derive(Debug)]
+pub struct CProgressObserver {
+    pub callback: ProgressCallback,
+    pub user_data: *mut libc::c_void,
+}
+
+// The host promises (see `shorebird_set_progress_callback`) that its callback
+// and user_data can be used from any thread.
+unsafe impl Send for CProgressObserver {}
+unsafe impl Sync for CProgressObserver {}
+
+pub(super) fn phase_to_c(phase: UpdatePhase) -> i32 {
+    match phase {
+        UpdatePhase::Checking => SHOREBIRD_PHASE_CHECKING,
+        UpdatePhase::Downloading => SHOREBIRD_PHASE_DOWNLOADING,
+        UpdatePhase::Inflating => SHOREBIRD_PHASE_INFLATING,
+        UpdatePhase::Verifying => SHOREBIRD_PHASE_VERIFYING,
+        UpdatePhase::Installing => SHOREBIRD_PHASE_INSTALLING,
+    }
+}
+
+impl ProgressObserver for CProgressObserver {
+    fn on_progress(&self, progress: UpdateProgress) {
+        let total_bytes = progress
+            .total_bytes
+            .and_then(|total| i64::try_from(total).ok())
+            .unwrap_or(-1);
+        if let Some(callback) = self.callback {
+            callback(
+                self.user_data,
+                phase_to_c(progress.phase),
+                progress.bytes_received,
+                total_bytes,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::CProgressObserver;
+    use crate::{ProgressObserver, UpdatePhase, UpdateProgress};
+
+    static CALLS: Mutex<Vec<(usize, i32, u64, i64)>> = Mutex::new(Vec::new());
+
+    extern "C" fn record(user_data: *mut libc::c_void, phase: i32, bytes: u64, total: i64) {
+        CALLS
+            .lock()
+            .unwrap()
+            .push((user_data as usize, phase, bytes, total));
+    }
+
+    #[test]
+    fn passes_progress_to_callback() {
+        let observer = CProgressObserver {
+            callback: Some(record),
+            user_data: 42 as *mut libc::c_void,
+        };
+        observer.on_progress(UpdateProgress {
+            phase: UpdatePhase::Downloading,
+            bytes_received: 10,
+            total_bytes: Some(100),
+        });
+        observer.on_progress(UpdateProgress {
+            phase: UpdatePhase::Inflating,
+            bytes_received: 0,
+            total_bytes: None,
+        });
+        assert_eq!(
+            *CALLS.lock().unwrap(),
+            vec![
+                (42, super::SHOREBIRD_PHASE_DOWNLOADING, 10, 100),
+                (42, super::SHOREBIRD_PHASE_INFLATING, 0, -1),
+            ]
+        );
+    }
+}
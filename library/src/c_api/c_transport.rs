@@ -0,0 +1,605 @@
+// This file lets the host app perform the updater's network requests, so that
+// updater traffic can go through the app's own HTTP stack (e.g. OkHttp or
+// URLSession) and whatever interceptors it has configured.
+
+use std::ffi::{CStr, CString};
+use std::io::Write;
+
+use anyhow::{bail, Context};
+
+use crate::network::{
+    CreatePatchEventRequest, DownloadRequest, DownloadResponse, DownloadSink, HttpHeaders,
+    NetworkError, PatchCheckRequest, PatchCheckResponse, Transport,
+};
+
+use super::TransportCallbacks;
+
+/// Where the body of a response from the host is written.
+enum ResponseBody<'a> {
+    /// Small (JSON) responses are collected in memory.
+    Buffer(Vec<u8>),
+    /// Downloads are streamed to their sink.
+    Sink(&'a mut dyn DownloadSink),
+}
+
+/// A response being reported by the host. Opaque to C, which only passes it
+/// back to `shorebird_transport_response_set_status` and
+/// `shorebird_transport_response_write`.
+pub struct TransportResponse<'a> {
+    status: Option<u16>,
+    /// The response's Content-Length, if the host reported it.
+    content_length: Option<u64>,
+    /// Where in the file a download's body starts.
+    range_start: u64,
+    body: ResponseBody<'a>,
+    /// The first error hit while handling the response. Once set, further
+    /// writes are rejected.
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> TransportResponse<'a> {
+    fn new(body: ResponseBody<'a>) -> Self {
+        Self {
+            status: None,
+            content_length: None,
+            range_start: 0,
+            body,
+            error: None,
+        }
+    }
+
+    /// Called by the host, before [Self::set_status], if it knows the
+    /// length of the response's body. Returns false if the updater no longer
+    /// wants the response.
+    pub(super) fn set_content_length(&mut self, content_length: u64) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        if self.status.is_some() {
+            self.error = Some(anyhow::anyhow!(
+                "Transport set the content length after the status"
+            ));
+            return false;
+        }
+        self.content_length = Some(content_length);
+        true
+    }
+
+    fn is_success(&self) -> bool {
+        self.status.is_some_and(|status| (200..300).contains(&status))
+    }
+
+    /// Called by the host once it knows the response's status (and before
+    /// writing any of its body). Returns false if the updater no longer wants
+    /// the response.
+    pub(super) fn set_status(&mut self, status: u16, etag: Option<String>) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        self.status = Some(status);
+        if !self.is_success() {
+            // We don't need the body of an error response.
+            return true;
+        }
+        let is_partial = status == 206;
+        let total_bytes = self.content_length.map(|length| {
+            if is_partial {
+                self.range_start + length
+            } else {
+                length
+            }
+        });
+        if let ResponseBody::Sink(sink) = &mut self.body {
+            let result = sink.begin(&DownloadResponse {
+                total_bytes,
+                is_partial,
+                etag,
+            });
+            if let Err(e) = result {
+                self.error = Some(e);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Called by the host with each chunk of the response body. Returns false
+    /// if the host should stop sending the body, e.g. because the download
+    /// has exceeded its maximum size.
+    pub(super) fn write(&mut self, data: &[u8]) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        if self.status.is_none() {
+            self.error = Some(anyhow::anyhow!(
+                "Transport wrote a response body before setting its status"
+            ));
+            return false;
+        }
+        if !self.is_success() {
+            return true;
+        }
+        let result = match &mut self.body {
+            ResponseBody::Buffer(buffer) => buffer.write_all(data),
+            ResponseBody::Sink(sink) => sink.write_all(data),
+        };
+        if let Err(e) = result {
+            self.error = Some(e.into());
+            return false;
+        }
+        true
+    }
+
+    /// Turns what the host reported into the body of a successful response or
+    /// an error. `completed` is what the host's callback returned.
+    fn finish(self, completed: bool) -> anyhow::Result<Vec<u8>> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let Some(status) = self.status else {
+            // The host never heard back from the server.
+            bail!(NetworkError::Connect);
+        };
+        if !self.is_success() {
+            bail!(NetworkError::HttpStatus {
+                status: reqwest::StatusCode::from_u16(status)
+                    .context("Transport reported an invalid status")?,
+                retry_after: None,
+            });
+        }
+        if !completed {
+            bail!(NetworkError::ConnectionLost(
+                std::io::ErrorKind::ConnectionAborted.into()
+            ));
+        }
+        match self.body {
+            ResponseBody::Buffer(buffer) => Ok(buffer),
+            ResponseBody::Sink(_) => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A `Transport` which hands requests to callbacks registered by the host.
+#[derive(Debug)]
+pub struct CTransport {
+    pub callbacks: TransportCallbacks,
+    pub user_data: *mut libc::c_void,
+}
+
+// The host promises (see `shorebird_set_transport`) that its callbacks and
+// user_data can be used from any thread.
+unsafe impl Send for CTransport {}
+unsafe impl Sync for CTransport {}
+
+/// Headers are passed to the host as a JSON object of names to values.
+fn headers_to_c(headers: &HttpHeaders) -> anyhow::Result<CString> {
+    let map: serde_json::Map<String, serde_json::Value> = headers
+        .iter()
+        .map(|(name, value)| (name.to_owned(), value.into()))
+        .collect();
+    Ok(CString::new(serde_json::to_string(&map)?)?)
+}
+
+impl CTransport {
+    fn post_json(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        body: &impl serde::Serialize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let c_url = CString::new(url)?;
+        let c_headers = headers_to_c(headers)?;
+        let c_body = CString::new(serde_json::to_string(body)?)?;
+        let mut response = TransportResponse::new(ResponseBody::Buffer(Vec::new()));
+        let completed = (self.callbacks.post_json)(
+            self.user_data,
+            c_url.as_ptr(),
+            c_headers.as_ptr(),
+            c_body.as_ptr(),
+            &mut response,
+        );
+        response.finish(completed)
+    }
+
+    fn get(
+        &self,
+        url: &CStr,
+        headers: &CStr,
+        range_start: u64,
+        if_range: Option<&CStr>,
+        sink: &mut dyn DownloadSink,
+    ) -> anyhow::Result<()> {
+        let mut response = TransportResponse::new(ResponseBody::Sink(sink));
+        response.range_start = range_start;
+        let completed = (self.callbacks.get)(
+            self.user_data,
+            url.as_ptr(),
+            headers.as_ptr(),
+            range_start,
+            if_range.map_or(std::ptr::null(), |if_range| if_range.as_ptr()),
+            &mut response,
+        );
+        response.finish(completed)?;
+        Ok(())
+    }
+}
+
+/// Whether `result` failed because the server couldn't satisfy our range.
+fn is_range_not_satisfiable(result: &anyhow::Result<()>) -> bool {
+    matches!(
+        result.as_ref().map_err(|e| e.downcast_ref::<NetworkError>()),
+        Err(Some(NetworkError::HttpStatus { status, .. }))
+            if *status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+    )
+}
+
+impl Transport for CTransport {
+    fn patch_check(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &PatchCheckRequest,
+    ) -> anyhow::Result<PatchCheckResponse> {
+        shorebird_info!("Sending patch check request via host: {:?}", request);
+        let body = self.post_json(url, headers, request)?;
+        let response = serde_json::from_slice(&body)?;
+        shorebird_debug!("Patch check response: {:?}", response);
+        Ok(response)
+    }
+
+    fn download_file(
+        &self,
+        request: &DownloadRequest,
+        headers: &HttpHeaders,
+        sink: &mut dyn DownloadSink,
+    ) -> anyhow::Result<()> {
+        let c_url = CString::new(request.url.as_str())?;
+        let c_headers = headers_to_c(headers)?;
+        let c_if_range = request.if_range.as_deref().map(CString::new).transpose()?;
+        let result = self.get(
+            &c_url,
+            &c_headers,
+            request.range_start,
+            c_if_range.as_deref(),
+            sink,
+        );
+        // The file may have shrunk or our partial download may be bogus, in
+        // which case the server can't satisfy our range. Just ask for the
+        // whole file, as ReqwestTransport does.
+        if request.range_start > 0 && is_range_not_satisfiable(&result) {
+            shorebird_info!("Server could not satisfy range request, downloading full file");
+            return self.get(&c_url, &c_headers, 0, None, sink);
+        }
+        result
+    }
+
+    fn report_event(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &CreatePatchEventRequest,
+    ) -> anyhow::Result<()> {
+        self.post_json(url, headers, request)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+
+    use super::*;
+    use crate::c_api::{
+        shorebird_transport_response_set_content_length, shorebird_transport_response_set_status,
+        shorebird_transport_response_write,
+    };
+
+    fn transport(callbacks: TransportCallbacks) -> CTransport {
+        CTransport {
+            callbacks,
+            user_data: std::ptr::null_mut(),
+        }
+    }
+
+    extern "C" fn unused_post(
+        _user_data: *mut libc::c_void,
+        _url: *const c_char,
+        _headers: *const c_char,
+        _body: *const c_char,
+        _response: *mut TransportResponse,
+    ) -> bool {
+        panic!("Unexpected post");
+    }
+
+    extern "C" fn unused_get(
+        _user_data: *mut libc::c_void,
+        _url: *const c_char,
+        _headers: *const c_char,
+        _range_start: u64,
+        _if_range: *const c_char,
+        _response: *mut TransportResponse,
+    ) -> bool {
+        panic!("Unexpected get");
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        response: Option<DownloadResponse>,
+        bytes: Vec<u8>,
+    }
+
+    impl Write for RecordingSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.bytes.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl DownloadSink for RecordingSink {
+        fn begin(&mut self, response: &DownloadResponse) -> anyhow::Result<()> {
+            self.response = Some(response.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn patch_check_posts_json_and_parses_response() {
+        extern "C" fn post(
+            _user_data: *mut libc::c_void,
+            url: *const c_char,
+            headers: *const c_char,
+            body: *const c_char,
+            response: *mut TransportResponse,
+        ) -> bool {
+            let url = unsafe { CStr::from_ptr(url) }.to_str().unwrap();
+            let headers = unsafe { CStr::from_ptr(headers) }.to_str().unwrap();
+            let body = unsafe { CStr::from_ptr(body) }.to_str().unwrap();
+            assert_eq!(url, "https://example.com/api/v1/patches/check");
+            assert_eq!(headers, "{\"x-api-key\":\"secret\"}");
+            assert!(body.contains("\"app_id\":\"app\""));
+            let response_body = b"{\"patch_available\":false}";
+            unsafe {
+                assert!(shorebird_transport_response_set_status(
+                    response,
+                    200,
+                    std::ptr::null()
+                ));
+                assert!(shorebird_transport_response_write(
+                    response,
+                    response_body.as_ptr(),
+                    response_body.len()
+                ));
+            }
+            true
+        }
+
+        let transport = transport(TransportCallbacks {
+            post_json: post,
+            get: unused_get,
+        });
+        let mut headers = HttpHeaders::default();
+        headers.set("X-Api-Key", "secret").unwrap();
+        let response = transport
+            .patch_check(
+                "https://example.com/api/v1/patches/check",
+                &headers,
+                &PatchCheckRequest {
+                    app_id: "app".to_string(),
+                    channel: "stable".to_string(),
+                    release_version: "1.0.0".to_string(),
+                    platform: "android".to_string(),
+                    arch: "aarch64".to_string(),
+                },
+            )
+            .unwrap();
+        assert!(!response.patch_available);
+    }
+
+    #[test]
+    fn download_streams_to_sink() {
+        extern "C" fn get(
+            _user_data: *mut libc::c_void,
+            _url: *const c_char,
+            _headers: *const c_char,
+            range_start: u64,
+            if_range: *const c_char,
+            response: *mut TransportResponse,
+        ) -> bool {
+            assert_eq!(range_start, 5);
+            let if_range = unsafe { CStr::from_ptr(if_range) }.to_str().unwrap();
+            assert_eq!(if_range, "\"abc\"");
+            let etag = CString::new("\"abc\"").unwrap();
+            unsafe {
+                assert!(shorebird_transport_response_set_status(
+                    response,
+                    206,
+                    etag.as_ptr()
+                ));
+                for chunk in [b" wor".as_slice(), b"ld".as_slice()] {
+                    assert!(shorebird_transport_response_write(
+                        response,
+                        chunk.as_ptr(),
+                        chunk.len()
+                    ));
+                }
+            }
+            true
+        }
+
+        let transport = transport(TransportCallbacks {
+            post_json: unused_post,
+            get,
+        });
+        let mut sink = RecordingSink::default();
+        transport
+            .download_file(
+                &DownloadRequest {
+                    url: "https://example.com/patch/1".to_string(),
+                    range_start: 5,
+                    if_range: Some("\"abc\"".to_string()),
+                },
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap();
+        assert_eq!(sink.bytes, b" world");
+        assert_eq!(
+            sink.response,
+            Some(DownloadResponse {
+                total_bytes: None,
+                is_partial: true,
+                etag: Some("\"abc\"".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn download_reports_total_bytes() {
+        extern "C" fn get(
+            _user_data: *mut libc::c_void,
+            _url: *const c_char,
+            _headers: *const c_char,
+            _range_start: u64,
+            _if_range: *const c_char,
+            response: *mut TransportResponse,
+        ) -> bool {
+            unsafe {
+                assert!(shorebird_transport_response_set_content_length(response, 6));
+                assert!(shorebird_transport_response_set_status(
+                    response,
+                    206,
+                    std::ptr::null()
+                ));
+            }
+            true
+        }
+
+        let transport = transport(TransportCallbacks {
+            post_json: unused_post,
+            get,
+        });
+        let mut sink = RecordingSink::default();
+        transport
+            .download_file(
+                &DownloadRequest {
+                    url: "https://example.com/patch/1".to_string(),
+                    range_start: 5,
+                    if_range: None,
+                },
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap();
+        // The length of the range plus where it starts.
+        assert_eq!(sink.response.unwrap().total_bytes, Some(11));
+    }
+
+    #[test]
+    fn download_restarts_if_range_not_satisfiable() {
+        extern "C" fn get(
+            _user_data: *mut libc::c_void,
+            _url: *const c_char,
+            _headers: *const c_char,
+            range_start: u64,
+            if_range: *const c_char,
+            response: *mut TransportResponse,
+        ) -> bool {
+            unsafe {
+                if range_start > 0 {
+                    assert!(!if_range.is_null());
+                    shorebird_transport_response_set_status(response, 416, std::ptr::null());
+                    return true;
+                }
+                assert!(if_range.is_null());
+                assert!(shorebird_transport_response_set_status(
+                    response,
+                    200,
+                    std::ptr::null()
+                ));
+                let body = b"hello world";
+                assert!(shorebird_transport_response_write(
+                    response,
+                    body.as_ptr(),
+                    body.len()
+                ));
+            }
+            true
+        }
+
+        let transport = transport(TransportCallbacks {
+            post_json: unused_post,
+            get,
+        });
+        let mut sink = RecordingSink::default();
+        transport
+            .download_file(
+                &DownloadRequest {
+                    url: "https://example.com/patch/1".to_string(),
+                    range_start: 5,
+                    if_range: Some("\"abc\"".to_string()),
+                },
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap();
+        assert_eq!(sink.bytes, b"hello world");
+        assert!(!sink.response.unwrap().is_partial);
+    }
+
+    #[test]
+    fn reports_http_and_connection_errors() {
+        extern "C" fn not_found(
+            _user_data: *mut libc::c_void,
+            _url: *const c_char,
+            _headers: *const c_char,
+            _body: *const c_char,
+            response: *mut TransportResponse,
+        ) -> bool {
+            unsafe { shorebird_transport_response_set_status(response, 404, std::ptr::null()) };
+            true
+        }
+        extern "C" fn offline(
+            _user_data: *mut libc::c_void,
+            _url: *const c_char,
+            _headers: *const c_char,
+            _range_start: u64,
+            _if_range: *const c_char,
+            _response: *mut TransportResponse,
+        ) -> bool {
+            false
+        }
+
+        let transport = transport(TransportCallbacks {
+            post_json: not_found,
+            get: offline,
+        });
+        let request = PatchCheckRequest {
+            app_id: "app".to_string(),
+            channel: "stable".to_string(),
+            release_version: "1.0.0".to_string(),
+            platform: "android".to_string(),
+            arch: "aarch64".to_string(),
+        };
+        let error = transport
+            .patch_check("url", &HttpHeaders::default(), &request)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Request failed with status: 404 Not Found");
+
+        let mut sink = RecordingSink::default();
+        let error = transport
+            .download_file(
+                &DownloadRequest::default(),
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<NetworkError>(),
+            Some(NetworkError::Connect)
+        ));
+    }
+}
@@ -12,13 +12,21 @@
 /// <https://github.com/shorebirdtech/engine/blob/shorebird/dev/shell/common/shorebird.cc>
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::{updater, UpdateStatus};
+use anyhow::bail;
+
+use crate::network::Connectivity;
+use crate::{updater, UpdateFailure, UpdateStatus};
 
 use self::c_file::CFileProvider;
+use self::c_progress::{phase_to_c, CProgressObserver};
+use self::c_transport::{CTransport, TransportResponse};
 
 mod c_file;
+mod c_progress;
+mod c_transport;
 
 /// Struct containing configuration parameters for the updater.
 /// Passed to all updater functions.
@@ -63,10 +71,100 @@ pub const SHOREBIRD_UPDATE_HAD_ERROR: i32 = 2;
 /// The downloaded patch was not installed because it was invalid.
 pub const SHOREBIRD_UPDATE_IS_BAD_PATCH: i32 = 3;
 
+/// No update check was made because the last one was within the configured
+/// check interval.
+pub const SHOREBIRD_UPDATE_THROTTLED: i32 = 4;
+
+/// An update is available but was not downloaded because the download policy
+/// doesn't allow downloading over the current connection.
+pub const SHOREBIRD_UPDATE_DEFERRED: i32 = 5;
+
+/// The update was cancelled by [shorebird_cancel_update] before it finished.
+pub const SHOREBIRD_UPDATE_CANCELLED: i32 = 6;
+
+/// Checking the server for a patch.
+pub const SHOREBIRD_PHASE_CHECKING: i32 = 0;
+
+/// Downloading a patch.
+pub const SHOREBIRD_PHASE_DOWNLOADING: i32 = 1;
+
+/// Applying a downloaded patch to the release.
+pub const SHOREBIRD_PHASE_INFLATING: i32 = 2;
+
+/// Checking the patched result's hash.
+pub const SHOREBIRD_PHASE_VERIFYING: i32 = 3;
+
+/// Installing the patch for the next launch.
+pub const SHOREBIRD_PHASE_INSTALLING: i32 = 4;
+
+/// The host doesn't know what kind of connection the device has.
+pub const SHOREBIRD_CONNECTIVITY_UNKNOWN: i32 = 0;
+
+/// The device has a connection without data charges, e.g. Wi-Fi.
+pub const SHOREBIRD_CONNECTIVITY_UNMETERED: i32 = 1;
+
+/// The device has a connection which may cost the user money, e.g. cellular.
+pub const SHOREBIRD_CONNECTIVITY_METERED: i32 = 2;
+
+/// The update didn't fail.
+pub const SHOREBIRD_ERROR_NONE: i32 = 0;
+
+/// The update failed for a reason not covered by the other SHOREBIRD_ERROR_*
+/// values.
+pub const SHOREBIRD_ERROR_UNKNOWN: i32 = 1;
+
+/// The server couldn't be reached or responded with an error status.
+pub const SHOREBIRD_ERROR_NETWORK: i32 = 2;
+
+/// The server's response didn't make sense.
+pub const SHOREBIRD_ERROR_BAD_SERVER_RESPONSE: i32 = 3;
+
+/// The downloaded patch couldn't be applied to the release.
+pub const SHOREBIRD_ERROR_INFLATE_FAILED: i32 = 4;
+
+/// The patched release didn't have the hash the server said it would.
+pub const SHOREBIRD_ERROR_HASH_MISMATCH: i32 = 5;
+
+/// The patch's signature was missing or invalid.
+pub const SHOREBIRD_ERROR_SIGNATURE_INVALID: i32 = 6;
+
+/// The device ran out of storage.
+pub const SHOREBIRD_ERROR_DISK_FULL: i32 = 7;
+
+/// Reading or writing the updater's files failed.
+pub const SHOREBIRD_ERROR_STORAGE: i32 = 8;
+
+/// shorebird_init hasn't been called (or failed).
+pub const SHOREBIRD_ERROR_NOT_INITIALIZED: i32 = 9;
+
+/// Another update was already running.
+pub const SHOREBIRD_ERROR_ALREADY_IN_PROGRESS: i32 = 10;
+
+/// Gave up waiting for a running update to finish.
+pub const SHOREBIRD_ERROR_TIMED_OUT: i32 = 11;
+
 #[repr(C)]
 pub struct UpdateResult {
     pub status: i32,
     pub message: *const libc::c_char,
+    /// Why the update failed, one of the SHOREBIRD_ERROR_* values. Only set if
+    /// `status` is SHOREBIRD_UPDATE_ERROR, otherwise SHOREBIRD_ERROR_NONE.
+    pub error: i32,
+}
+
+/// Describes the update in progress, see [shorebird_running_update].
+#[derive(Debug, PartialEq)]
+#[repr(C)]
+pub struct RunningUpdateStatus {
+    /// Whether an update is running. The other fields are only meaningful if
+    /// this is true.
+    pub in_progress: bool,
+    /// One of the SHOREBIRD_PHASE_* values.
+    pub phase: i32,
+    /// When the update started, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// The patch being installed, or -1 if the server hasn't told us yet.
+    pub patch_number: i64,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -88,6 +186,61 @@ pub struct FileCallbacks {
     pub close: extern "C" fn(file_handle: *mut libc::c_void),
 }
 
+/// Callbacks for routing the updater's network requests through the host app.
+/// Registered with `shorebird_set_transport`.
+///
+/// Each callback is given the `user_data` pointer passed to
+/// `shorebird_set_transport`, the extra `headers` to send as a JSON object of
+/// header names to values, and a `response` handle. Before writing any of
+/// the response body with `shorebird_transport_response_write`, the host must
+/// report the response status with `shorebird_transport_response_set_status`
+/// (and, before that, its length with
+/// `shorebird_transport_response_set_content_length` if known). The
+/// `response` handle is only valid until the callback returns.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TransportCallbacks {
+    /// Sends a POST request to `url` with the JSON `body` (Content-Type
+    /// application/json). Returns true if the whole response was received,
+    /// false if the request failed (e.g. no internet connection).
+    pub post_json: extern "C" fn(
+        user_data: *mut libc::c_void,
+        url: *const c_char,
+        headers: *const c_char,
+        body: *const c_char,
+        response: *mut TransportResponse,
+    ) -> bool,
+
+    /// Sends a GET request to `url`. If `range_start` is non-zero the host
+    /// should send a `Range: bytes=<range_start>-` header and, if `if_range`
+    /// is not null, an `If-Range: <if_range>` header. If the server can't
+    /// satisfy the range (status 416), the updater calls this again for the
+    /// whole file. Returns true if the whole response was received, false if
+    /// the request failed.
+    pub get: extern "C" fn(
+        user_data: *mut libc::c_void,
+        url: *const c_char,
+        headers: *const c_char,
+        range_start: u64,
+        if_range: *const c_char,
+        response: *mut TransportResponse,
+    ) -> bool,
+}
+
+/// Called with the progress of an update. `phase` is one of the
+/// SHOREBIRD_PHASE_* values, `bytes_received` is how many bytes that phase has
+/// handled so far and `total_bytes` is how many it will handle in total, or
+/// -1 if unknown.
+pub type ProgressCallback = Option<
+    extern "C" fn(user_data: *mut libc::c_void, phase: i32, bytes_received: u64, total_bytes: i64),
+>;
+
+/// Called with the result of an update started by [shorebird_update_async].
+/// The callback owns `result` and must free it with
+/// [shorebird_free_update_result].
+pub type UpdateCallback =
+    Option<extern "C" fn(user_data: *mut libc::c_void, result: *const UpdateResult)>;
+
 /// Converts a C string to a Rust string, does not free the C string.
 fn to_rust(c_string: *const libc::c_char) -> anyhow::Result<String> {
     anyhow::ensure!(!c_string.is_null(), "Null string passed to to_rust");
@@ -213,6 +366,22 @@ fn path_to_c_string(path: Option<PathBuf>) -> anyhow::Result<*mut c_char> {
     })
 }
 
+fn failure_to_c(failure: UpdateFailure) -> i32 {
+    match failure {
+        UpdateFailure::Unknown => SHOREBIRD_ERROR_UNKNOWN,
+        UpdateFailure::Network => SHOREBIRD_ERROR_NETWORK,
+        UpdateFailure::BadServerResponse => SHOREBIRD_ERROR_BAD_SERVER_RESPONSE,
+        UpdateFailure::InflateFailed => SHOREBIRD_ERROR_INFLATE_FAILED,
+        UpdateFailure::HashMismatch => SHOREBIRD_ERROR_HASH_MISMATCH,
+        UpdateFailure::SignatureInvalid => SHOREBIRD_ERROR_SIGNATURE_INVALID,
+        UpdateFailure::DiskFull => SHOREBIRD_ERROR_DISK_FULL,
+        UpdateFailure::Storage => SHOREBIRD_ERROR_STORAGE,
+        UpdateFailure::NotInitialized => SHOREBIRD_ERROR_NOT_INITIALIZED,
+        UpdateFailure::AlreadyInProgress => SHOREBIRD_ERROR_ALREADY_IN_PROGRESS,
+        UpdateFailure::TimedOut => SHOREBIRD_ERROR_TIMED_OUT,
+    }
+}
+
 fn to_update_result(status: anyhow::Result<UpdateStatus>) -> UpdateResult {
     let result = match status {
         Ok(status) => {
@@ -221,11 +390,13 @@ fn to_update_result(status: anyhow::Result<UpdateStatus>) -> UpdateResult {
                 status: status as i32,
                 message: allocate_c_string(message.as_str())
                     .unwrap_or_else(|_| std::ptr::null_mut()),
+                error: SHOREBIRD_ERROR_NONE,
             };
         }
         Err(err) => UpdateResult {
             status: SHOREBIRD_UPDATE_ERROR,
             message: allocate_c_string(&err.to_string()).unwrap_or_else(|_| std::ptr::null_mut()),
+            error: failure_to_c(UpdateFailure::from_error(&err)),
         },
     };
     return result;
@@ -302,6 +473,30 @@ pub extern "C" fn shorebird_check_for_downloadable_update(c_channel: *const c_ch
     )
 }
 
+/// Like [shorebird_check_for_downloadable_update], but returns everything the
+/// server told us about the latest patch as a JSON object, e.g.:
+///
+/// {"update_available": true, "patch": {"number": 2, "size": 1234,
+///  "hash": "...", "is_signed": false, "is_known_bad": false,
+///  "is_already_installed": false, "metadata": null},
+///  "rolled_back_patch_numbers": []}
+///
+/// `patch` is null if there is no patch for this release. Returns NULL if the
+/// check failed. The caller must free the returned string with
+/// [shorebird_free_string].
+#[no_mangle]
+pub extern "C" fn shorebird_check_for_update_details(c_channel: *const c_char) -> *mut c_char {
+    log_on_error(
+        || {
+            let channel = to_rust_option(c_channel)?;
+            let details = updater::check_for_update_details(channel.as_deref())?;
+            allocate_c_string(&serde_json::to_string(&details)?)
+        },
+        "checking for update details",
+        std::ptr::null_mut(),
+    )
+}
+
 /// Synchronously download an update if one is available.
 #[no_mangle]
 pub extern "C" fn shorebird_update() {
@@ -328,12 +523,221 @@ pub extern "C" fn shorebird_update_with_result(c_channel: *const c_char) -> *con
     return Box::into_raw(Box::new(result));
 }
 
+/// Like [shorebird_update_with_result], but checks for an update even if the
+/// last check was within the configured check interval. Intended for checks
+/// the user asked for.
+#[no_mangle]
+pub extern "C" fn shorebird_force_update_with_result(
+    c_channel: *const c_char,
+) -> *const UpdateResult {
+    let channel = to_rust_option(c_channel);
+    let result = match channel {
+        Ok(channel) => to_update_result(updater::force_update(channel.as_deref())),
+        Err(err) => to_update_result(Err(err)),
+    };
+    Box::into_raw(Box::new(result))
+}
+
+/// Like [shorebird_update_with_result], but if an update is already running
+/// (e.g. one started by [shorebird_start_update_thread]), waits for it to
+/// finish and returns its result instead of an error. Waits at most
+/// `timeout_ms` milliseconds, or forever if `timeout_ms` is negative.
+#[no_mangle]
+pub extern "C" fn shorebird_update_or_join_with_result(
+    c_channel: *const c_char,
+    timeout_ms: i64,
+) -> *const UpdateResult {
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+    let channel = to_rust_option(c_channel);
+    let result = match channel {
+        Ok(channel) => to_update_result(updater::update_or_join(channel.as_deref(), timeout)),
+        Err(err) => to_update_result(Err(err)),
+    };
+    Box::into_raw(Box::new(result))
+}
+
+/// Like [shorebird_update_with_result], but runs the update on a thread owned
+/// by the updater and returns immediately. `callback`, if not null, is called
+/// from that thread with the result and `user_data` once the update finishes,
+/// so both must be safe to use from any thread.
+/// Returns false, without calling `callback`, if the update could not be
+/// started.
+#[no_mangle]
+pub extern "C" fn shorebird_update_async(
+    c_channel: *const c_char,
+    callback: UpdateCallback,
+    user_data: *mut libc::c_void,
+) -> bool {
+    /// Lets `user_data` travel to the update thread. The host promises it can
+    /// be used from any thread.
+    struct UserData(*mut libc::c_void);
+    unsafe impl Send for UserData {}
+
+    log_on_error(
+        || {
+            let channel = to_rust_option(c_channel)?;
+            let user_data = UserData(user_data);
+            // The update reports back through `callback`, so we don't need
+            // the handle.
+            updater::update_async_then(channel, move |result| {
+                // Move all of user_data, not just the (non-Send) pointer.
+                let user_data = user_data;
+                let result = Box::into_raw(Box::new(to_update_result(result)));
+                match callback {
+                    Some(callback) => callback(user_data.0, result),
+                    None => unsafe { shorebird_free_update_result(result) },
+                }
+            })?;
+            Ok(true)
+        },
+        "starting async update",
+        false,
+    )
+}
+
+/// Install a patch delivered by something other than Shorebird's servers,
+/// e.g. a device management system. `c_path` is the patch file as the servers
+/// would serve it, `c_hash` the hex-encoded sha256 hash of the patched
+/// release, and `c_signature` the patch's signature, or NULL if unsigned. The
+/// patch is checked and recorded just as if it had been downloaded.
+///
+/// Returns an [UpdateResult]: SHOREBIRD_UPDATE_INSTALLED if the patch was
+/// installed, SHOREBIRD_NO_UPDATE if it was already installed, or
+/// SHOREBIRD_UPDATE_IS_BAD_PATCH if it previously failed to launch.
+#[no_mangle]
+pub extern "C" fn shorebird_install_patch_from_file(
+    c_path: *const c_char,
+    patch_number: usize,
+    c_hash: *const c_char,
+    c_signature: *const c_char,
+) -> *const UpdateResult {
+    let result = (|| {
+        let path = to_rust(c_path)?;
+        let hash = to_rust(c_hash)?;
+        let signature = to_rust_option(c_signature)?;
+        updater::install_patch_from_file(
+            Path::new(&path),
+            patch_number,
+            &hash,
+            signature.as_deref(),
+        )
+    })();
+    Box::into_raw(Box::new(to_update_result(result)))
+}
+
 /// Start a thread to download an update if one is available.
 #[no_mangle]
 pub extern "C" fn shorebird_start_update_thread() {
     updater::start_update_thread();
 }
 
+/// Describe the update in progress, if any, e.g. one started by
+/// [shorebird_start_update_thread]. Never waits for the update, so hosts can
+/// use this to avoid starting an update while another is running.
+#[no_mangle]
+pub extern "C" fn shorebird_running_update() -> RunningUpdateStatus {
+    match updater::running_update() {
+        Some(update) => RunningUpdateStatus {
+            in_progress: true,
+            phase: phase_to_c(update.phase),
+            started_at: update.started_at,
+            patch_number: update.patch_number.map_or(-1, |number| number as i64),
+        },
+        None => RunningUpdateStatus {
+            in_progress: false,
+            phase: SHOREBIRD_PHASE_CHECKING,
+            started_at: 0,
+            patch_number: -1,
+        },
+    }
+}
+
+/// Ask the update in progress, if any, to stop. The update returns
+/// SHOREBIRD_UPDATE_CANCELLED and removes anything it had downloaded.
+/// Returns false if no update was running.
+#[no_mangle]
+pub extern "C" fn shorebird_cancel_update() -> bool {
+    updater::cancel_update()
+}
+
+/// Stop launching a patch installed since the last successful launch, so
+/// that the next launch uses the last successfully launched patch (or the
+/// release, if there is none). If `mark_bad` is true, the abandoned patch is
+/// never installed again. Returns false if rolling back failed.
+#[no_mangle]
+pub extern "C" fn shorebird_roll_back_to_last_booted_patch(mark_bad: bool) -> bool {
+    log_on_error(
+        || {
+            updater::roll_back_to_last_booted_patch(mark_bad)?;
+            Ok(true)
+        },
+        "rolling back to last booted patch",
+        false,
+    )
+}
+
+/// Stop launching any patch, so that the next launch uses the release as
+/// shipped. If `mark_bad` is true, the abandoned patches are never installed
+/// again. Returns false if rolling back failed.
+#[no_mangle]
+pub extern "C" fn shorebird_roll_back_to_release(mark_bad: bool) -> bool {
+    log_on_error(
+        || {
+            updater::roll_back_to_release(mark_bad)?;
+            Ok(true)
+        },
+        "rolling back to release",
+        false,
+    )
+}
+
+/// Start checking for updates in the background every
+/// `scheduled_check_minutes`, as set in shorebird.yaml, until
+/// [shorebird_stop_update_scheduler] is called. Checks wait for (rather than
+/// overlap with) any other update, and happen less often after failures.
+/// Returns false if the scheduler could not be started, e.g. because
+/// `scheduled_check_minutes` is not set.
+#[no_mangle]
+pub extern "C" fn shorebird_start_update_scheduler() -> bool {
+    log_on_error(
+        || {
+            updater::start_update_scheduler()?;
+            Ok(true)
+        },
+        "starting update scheduler",
+        false,
+    )
+}
+
+/// Stop the scheduler started by [shorebird_start_update_scheduler]. Any
+/// update it already started carries on. Returns false if it wasn't running.
+#[no_mangle]
+pub extern "C" fn shorebird_stop_update_scheduler() -> bool {
+    updater::stop_update_scheduler()
+}
+
+/// Report the kind of network connection the device currently has, one of
+/// the SHOREBIRD_CONNECTIVITY_* values. Hosts should call this whenever the
+/// connection changes if shorebird.yaml sets a download_policy other than
+/// `any`. Returns false if `connectivity` is not recognized.
+#[no_mangle]
+pub extern "C" fn shorebird_set_connectivity(connectivity: i32) -> bool {
+    log_on_error(
+        || {
+            let connectivity = match connectivity {
+                SHOREBIRD_CONNECTIVITY_UNKNOWN => Connectivity::Unknown,
+                SHOREBIRD_CONNECTIVITY_UNMETERED => Connectivity::Unmetered,
+                SHOREBIRD_CONNECTIVITY_METERED => Connectivity::Metered,
+                other => bail!("Unknown connectivity: {}", other),
+            };
+            crate::config::set_connectivity(connectivity)?;
+            Ok(true)
+        },
+        "setting connectivity",
+        false,
+    )
+}
+
 /// Update the base URL for patch checking and downloading.
 /// The base_url parameter must be a valid URL string (e.g., "https://api.example.com").
 /// Returns true if the base URL was updated successfully, false otherwise.
@@ -350,6 +754,177 @@ pub extern "C" fn shorebird_update_base_url(c_base_url: *const c_char) -> bool {
     )
 }
 
+/// Sets a header to send with every request (patch checks, downloads and
+/// events), replacing any previous value for that header. Passing a null
+/// `c_value` removes the header. Must be called after `shorebird_init`.
+/// Returns true if the header was updated, false otherwise (e.g. if the name
+/// or value is not a valid HTTP header).
+#[no_mangle]
+pub extern "C" fn shorebird_set_http_header(
+    c_name: *const c_char,
+    c_value: *const c_char,
+) -> bool {
+    log_on_error(
+        || {
+            let name = to_rust(c_name)?;
+            let value = to_rust_option(c_value)?;
+            crate::config::set_http_header(&name, value.as_deref())?;
+            Ok(true)
+        },
+        "setting HTTP header",
+        false,
+    )
+}
+
+/// Sends `Authorization: Bearer <c_token>` with every request, e.g. after the
+/// user logs in. Passing a null `c_token` stops sending the header.
+/// Must be called after `shorebird_init`. Returns true on success.
+#[no_mangle]
+pub extern "C" fn shorebird_set_auth_token(c_token: *const c_char) -> bool {
+    log_on_error(
+        || {
+            let token = to_rust_option(c_token)?;
+            let value = token.map(|token| format!("Bearer {token}"));
+            crate::config::set_http_header("authorization", value.as_deref())?;
+            Ok(true)
+        },
+        "setting auth token",
+        false,
+    )
+}
+
+/// Routes all of the updater's network requests through the given host
+/// callbacks instead of the updater's built-in HTTP client. Must be called
+/// after `shorebird_init`. `user_data` is passed back to every callback.
+///
+/// The callbacks may be called from any thread (including the update
+/// thread), so they and `user_data` must be safe to use from any thread.
+/// Returns true if the transport was set, false otherwise.
+#[no_mangle]
+pub extern "C" fn shorebird_set_transport(
+    callbacks: TransportCallbacks,
+    user_data: *mut libc::c_void,
+) -> bool {
+    log_on_error(
+        || {
+            crate::config::set_transport(std::sync::Arc::new(CTransport {
+                callbacks,
+                user_data,
+            }))?;
+            Ok(true)
+        },
+        "setting transport",
+        false,
+    )
+}
+
+/// Registers `callback` to be told how updates are progressing, or with a
+/// null `callback`, removes any registered callback. Must be called after
+/// `shorebird_init`. `user_data` is passed back to every call.
+///
+/// The callback is called from whichever thread is running the update, so it
+/// and `user_data` must be safe to use from any thread. It should return
+/// quickly, as the update waits for it.
+/// Returns true if the callback was set, false otherwise.
+#[no_mangle]
+pub extern "C" fn shorebird_set_progress_callback(
+    callback: ProgressCallback,
+    user_data: *mut libc::c_void,
+) -> bool {
+    log_on_error(
+        || {
+            let observer = callback.map(|_| -> std::sync::Arc<dyn crate::ProgressObserver> {
+                std::sync::Arc::new(CProgressObserver {
+                    callback,
+                    user_data,
+                })
+            });
+            updater::set_progress_observer(observer)?;
+            Ok(true)
+        },
+        "setting progress callback",
+        false,
+    )
+}
+
+/// Reports the Content-Length of a response, if the host knows it, so that
+/// download progress has a total. Must be called before
+/// `shorebird_transport_response_set_status`. Returns false if the updater no
+/// longer wants the response, in which case the host should cancel the
+/// request.
+///
+/// # Safety
+///
+/// `response` must be the handle passed to the currently running transport
+/// callback.
+#[no_mangle]
+pub unsafe extern "C" fn shorebird_transport_response_set_content_length(
+    response: *mut TransportResponse,
+    content_length: u64,
+) -> bool {
+    if response.is_null() {
+        return false;
+    }
+    (*response).set_content_length(content_length)
+}
+
+/// Reports the HTTP status of a response, along with its ETag header if it
+/// has one (otherwise null). Must be called before writing any of the body.
+/// Returns false if the updater no longer wants the response, in which case
+/// the host should cancel the request.
+///
+/// # Safety
+///
+/// `response` must be the handle passed to the currently running transport
+/// callback. `etag` must be null or a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn shorebird_transport_response_set_status(
+    response: *mut TransportResponse,
+    status: i32,
+    etag: *const c_char,
+) -> bool {
+    if response.is_null() {
+        return false;
+    }
+    let Ok(status) = u16::try_from(status) else {
+        shorebird_error!("Transport reported invalid status: {}", status);
+        return false;
+    };
+    let etag = match to_rust_option(etag) {
+        Ok(etag) => etag,
+        Err(e) => {
+            shorebird_error!("Transport reported invalid ETag: {:?}", e);
+            None
+        }
+    };
+    (*response).set_status(status, etag)
+}
+
+/// Writes the next `length` bytes of a response's body. Returns false if the
+/// host should stop sending the body (e.g. because the download has grown
+/// past its maximum size), in which case the host should cancel the request.
+///
+/// # Safety
+///
+/// `response` must be the handle passed to the currently running transport
+/// callback. `data` must point to at least `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn shorebird_transport_response_write(
+    response: *mut TransportResponse,
+    data: *const u8,
+    length: usize,
+) -> bool {
+    if response.is_null() || (data.is_null() && length > 0) {
+        return false;
+    }
+    let data = if length == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, length)
+    };
+    (*response).write(data)
+}
+
 /// Tell the updater that we're launching from what it told us was the
 /// next patch to boot from. This will copy the next boot patch to be the
 /// `current_boot` patch.
@@ -589,17 +1164,20 @@ mod test {
                         hash: hash.to_owned(),
                         download_url: "ignored".to_owned(),
                         hash_signature: None,
+                        size: None,
+                        metadata: None,
                     }),
                     rolled_back_patch_numbers: None,
                 })
             },
-            |_url| {
+            |_request, sink| {
                 // Generated by `string_patch "hello world" "hello tests"`
                 let patch_bytes: Vec<u8> = vec![
                     40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
                     0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
                 ];
-                Ok(patch_bytes)
+                sink.write_all(&patch_bytes)?;
+                Ok(())
             },
             |_url, _event| Ok(()),
         );
@@ -652,17 +1230,20 @@ mod test {
                         hash: hash.to_owned(),
                         download_url: "ignored".to_owned(),
                         hash_signature: None,
+                        size: None,
+                        metadata: None,
                     }),
                     rolled_back_patch_numbers: None,
                 })
             },
-            |_url| {
+            |_request, sink| {
                 // Generated by `string_patch "hello world" "hello tests"`
                 let patch_bytes: Vec<u8> = vec![
                     40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
                     0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
                 ];
-                Ok(patch_bytes)
+                sink.write_all(&patch_bytes)?;
+                Ok(())
             },
             |_url, _event| Ok(()),
         );
@@ -718,7 +1299,7 @@ mod test {
                     rolled_back_patch_numbers: None,
                 })
             },
-            |_url| Err(anyhow::anyhow!("Error")),
+            |_request, _sink| Err(anyhow::anyhow!("Error")),
             |_url, _event| Ok(()),
         );
 
@@ -752,13 +1333,14 @@ mod test {
         // set up the network hooks to return a patch.
         testing_set_network_hooks(
             |_url, _request| Err(anyhow::anyhow!("Error")),
-            |_url| {
+            |_request, sink| {
                 // Generated by `string_patch "hello world" "hello tests"`
                 let patch_bytes: Vec<u8> = vec![
                     40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
                     0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
                 ];
-                Ok(patch_bytes)
+                sink.write_all(&patch_bytes)?;
+                Ok(())
             },
             |_url, _event| Ok(()),
         );
@@ -806,11 +1388,13 @@ mod test {
                         hash: hash.to_owned(),
                         download_url: "ignored".to_owned(),
                         hash_signature: None,
+                        size: None,
+                        metadata: None,
                     }),
                     rolled_back_patch_numbers: None,
                 })
             },
-            |_url| Err(anyhow::anyhow!("Error")),
+            |_request, _sink| Err(anyhow::anyhow!("Error")),
             |_url, _event| Ok(()),
         );
 
@@ -857,17 +1441,20 @@ mod test {
                         hash: hash.to_owned(),
                         download_url: "ignored".to_owned(),
                         hash_signature: None,
+                        size: None,
+                        metadata: None,
                     }),
                     rolled_back_patch_numbers: None,
                 })
             },
-            |_url| {
+            |_request, sink| {
                 // Generated by `string_patch "hello world" "hello tests"`
                 let patch_bytes: Vec<u8> = vec![
                     40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
                     0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
                 ];
-                Ok(patch_bytes)
+                sink.write_all(&patch_bytes)?;
+                Ok(())
             },
             |_url, _event| Ok(()),
         );
@@ -902,6 +1489,249 @@ mod test {
         assert_eq!(shorebird_next_boot_patch_path(), null_mut());
     }
 
+    #[serial]
+    #[test]
+    fn joined_update_reports_why_it_failed() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        static CHECKS: AtomicUsize = AtomicUsize::new(0);
+        static RELEASE: AtomicBool = AtomicBool::new(false);
+
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let fake_libapp_path = tmp_dir.path().join("lib/arch/ignored.so");
+        let c_params = parameters(&tmp_dir, fake_libapp_path.to_str().unwrap());
+        let c_yaml = c_string("app_id: foo\nbase_url: https://example.com");
+        assert!(shorebird_init(&c_params, FileCallbacks::new(), c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+        testing_set_network_hooks(
+            |_url, _request| {
+                CHECKS.fetch_add(1, Ordering::SeqCst);
+                while !RELEASE.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                // Not worth retrying.
+                anyhow::bail!(crate::network::NetworkError::HttpStatus {
+                    status: reqwest::StatusCode::NOT_FOUND,
+                    retry_after: None,
+                })
+            },
+            |_request, _sink| Ok(()),
+            |_url, _event| Ok(()),
+        );
+
+        let update = std::thread::spawn(|| {
+            let result = shorebird_update_with_result(std::ptr::null());
+            unsafe { shorebird_free_update_result(result as *mut UpdateResult) };
+        });
+        while CHECKS.load(Ordering::SeqCst) == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        std::thread::spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            RELEASE.store(true, Ordering::SeqCst);
+        });
+        let result = shorebird_update_or_join_with_result(std::ptr::null(), -1);
+        update.join().unwrap();
+
+        // We joined the running update rather than checking again.
+        assert_eq!(CHECKS.load(Ordering::SeqCst), 1);
+        unsafe {
+            assert_eq!(result.read().status, SHOREBIRD_UPDATE_ERROR);
+            assert_eq!(result.read().error, SHOREBIRD_ERROR_NETWORK);
+            shorebird_free_update_result(result as *mut UpdateResult);
+        }
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn update_result_reports_why_update_failed() -> anyhow::Result<()> {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+        let fake_libapp_path = tmp_dir.path().join("lib/arch/ignored.so");
+        let c_params = parameters(&tmp_dir, fake_libapp_path.to_str().unwrap());
+        let c_yaml = c_string("app_id: foo\nbase_url: https://example.com");
+        assert!(shorebird_init(&c_params, FileCallbacks::new(), c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+        testing_set_network_hooks(
+            |_url, _request| {
+                Ok(PatchCheckResponse {
+                    patch_available: true,
+                    patch: Some(crate::Patch {
+                        number: 1,
+                        hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                            .to_owned(),
+                        download_url: "https://example.com/patch/1".to_owned(),
+                        hash_signature: None,
+                        size: None,
+                        metadata: None,
+                    }),
+                    rolled_back_patch_numbers: None,
+                })
+            },
+            |_request, sink| {
+                // Not a patch.
+                sink.write_all(b"garbage")?;
+                Ok(())
+            },
+            |_url, _event| Ok(()),
+        );
+
+        let result = shorebird_update_with_result(std::ptr::null());
+        unsafe {
+            assert_eq!(result.read().status, SHOREBIRD_UPDATE_ERROR);
+            assert_eq!(result.read().error, SHOREBIRD_ERROR_INFLATE_FAILED);
+            shorebird_free_update_result(result as *mut UpdateResult);
+        }
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn check_for_update_details_returns_json() {
+        testing_reset_config();
+        // Fails before init.
+        assert!(shorebird_check_for_update_details(std::ptr::null()).is_null());
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, FileCallbacks::new(), c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+        testing_set_network_hooks(
+            |_url, _request| {
+                Ok(PatchCheckResponse {
+                    patch_available: false,
+                    patch: None,
+                    rolled_back_patch_numbers: None,
+                })
+            },
+            |_request, _sink| Ok(()),
+            |_url, _event| Ok(()),
+        );
+
+        let c_details = shorebird_check_for_update_details(std::ptr::null());
+        let details: serde_json::Value =
+            serde_json::from_str(&to_rust(c_details).unwrap()).unwrap();
+        unsafe { shorebird_free_string(c_details) };
+        assert_eq!(
+            details,
+            serde_json::json!({
+                "update_available": false,
+                "patch": null,
+                "rolled_back_patch_numbers": [],
+            })
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn update_async_calls_callback_with_result() {
+        testing_reset_config();
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, FileCallbacks::new(), c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+        testing_set_network_hooks(
+            |_url, _request| {
+                Ok(PatchCheckResponse {
+                    patch_available: false,
+                    patch: None,
+                    rolled_back_patch_numbers: None,
+                })
+            },
+            |_request, _sink| Ok(()),
+            |_url, _event| Ok(()),
+        );
+
+        extern "C" fn callback(user_data: *mut libc::c_void, result: *const UpdateResult) {
+            let sender =
+                unsafe { Box::from_raw(user_data as *mut std::sync::mpsc::Sender<i32>) };
+            sender.send(unsafe { (*result).status }).unwrap();
+            unsafe { shorebird_free_update_result(result as *mut UpdateResult) };
+        }
+        let (sender, receiver) = std::sync::mpsc::channel::<i32>();
+        assert!(shorebird_update_async(
+            std::ptr::null(),
+            Some(callback),
+            Box::into_raw(Box::new(sender)) as *mut libc::c_void,
+        ));
+        let status = receiver
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        // Let the update thread finish before the next test starts.
+        while updater::running_update().is_some() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(status, SHOREBIRD_NO_UPDATE);
+    }
+
+    #[serial]
+    #[test]
+    fn running_update_when_idle() {
+        assert_eq!(
+            shorebird_running_update(),
+            RunningUpdateStatus {
+                in_progress: false,
+                phase: SHOREBIRD_PHASE_CHECKING,
+                started_at: 0,
+                patch_number: -1,
+            }
+        );
+    }
+
+    #[serial]
+    #[test]
+    fn roll_back_to_release() {
+        testing_reset_config();
+        // Fails before init.
+        assert!(!shorebird_roll_back_to_release(false));
+        assert!(!shorebird_roll_back_to_last_booted_patch(false));
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let c_params = parameters(&tmp_dir, "/dir/lib/arm64/libapp.so");
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, FileCallbacks::new(), c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        crate::test_utils::install_fake_patch(1).unwrap();
+        assert_eq!(shorebird_next_boot_patch_number(), 1);
+        assert!(shorebird_roll_back_to_release(true));
+        assert_eq!(shorebird_next_boot_patch_number(), 0);
+        // Nothing left to roll back isn't a failure.
+        assert!(shorebird_roll_back_to_last_booted_patch(true));
+    }
+
+    #[serial]
+    #[test]
+    fn set_connectivity() {
+        testing_reset_config();
+        // Fails before init.
+        assert!(!shorebird_set_connectivity(SHOREBIRD_CONNECTIVITY_UNMETERED));
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let fake_libapp_path = tmp_dir.path().join("lib/arch/libapp.so");
+        let c_params = parameters(&tmp_dir, fake_libapp_path.to_str().unwrap());
+        let c_yaml = c_string("app_id: foo");
+        assert!(shorebird_init(&c_params, FileCallbacks::new(), c_yaml));
+        free_c_string(c_yaml);
+        free_parameters(c_params);
+
+        assert!(shorebird_set_connectivity(SHOREBIRD_CONNECTIVITY_METERED));
+        let connectivity = crate::config::with_config(|config| Ok(config.connectivity)).unwrap();
+        assert_eq!(connectivity, Connectivity::Metered);
+        assert!(!shorebird_set_connectivity(42));
+    }
+
     #[serial]
     #[test]
     fn init_twice() {
@@ -966,13 +1796,15 @@ mod test {
                         hash: "ignored".to_owned(),
                         download_url: "ignored".to_owned(),
                         hash_signature: None,
+                        size: None,
+                        metadata: None,
                     }),
                     rolled_back_patch_numbers: None,
                 })
             },
-            |_url| {
+            |_request, _sink| {
                 // Never called.
-                Ok(Vec::new())
+                Ok(())
             },
             |_url, _event| Ok(()),
         );
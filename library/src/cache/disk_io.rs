@@ -1,32 +1,141 @@
 use anyhow::{bail, Context};
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Serialize,
+};
 use std::{
+    ffi::OsStr,
     fs::File,
     io::{BufReader, BufWriter},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+use crate::UpdateFailure;
+
+/// Serializes `serializable` to `path` as JSON. The JSON is written to a
+/// temporary file alongside `path`, synced to disk and then renamed over
+/// `path`, so that a crash or power loss part way through leaves either the
+/// old or the new contents at `path`, never a truncated mix of the two.
 pub fn write<S, P>(serializable: &S, path: &P) -> anyhow::Result<()>
 where
     S: ?Sized + Serialize,
     P: AsRef<Path>,
 {
-    shorebird_debug!("Writing to {:?}", path.as_ref());
+    write_atomically(serializable, path.as_ref(), false)
+}
 
-    let path_as_ref = path.as_ref();
-    let containing_dir = path_as_ref
+/// Like [write], but first moves the file at `path` (if it holds valid JSON)
+/// to [backup_path], so that there is a previous generation to fall back to
+/// if `path` is somehow lost or corrupted.
+pub fn write_keeping_backup<S, P>(serializable: &S, path: &P) -> anyhow::Result<()>
+where
+    S: ?Sized + Serialize,
+    P: AsRef<Path>,
+{
+    write_atomically(serializable, path.as_ref(), true)
+}
+
+/// Where [write_keeping_backup] keeps the previous generation of `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling_path(path, ".bak")
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or(OsStr::new("")).to_os_string();
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+fn write_atomically<S>(serializable: &S, path: &Path, keep_backup: bool) -> anyhow::Result<()>
+where
+    S: ?Sized + Serialize,
+{
+    shorebird_debug!("Writing to {:?}", path);
+
+    let containing_dir = path
         .parent()
-        .with_context(|| format!("Failed to get parent dir for {:?}", path_as_ref))?;
+        .with_context(|| format!("Failed to get parent dir for {:?}", path))?;
+    if path.file_name().is_none() {
+        bail!("Failed to get file name for {:?}", path);
+    }
 
     // Because File::create can sometimes fail if the full directory path doesn't exist,
     // we create the directories in its path first.
     std::fs::create_dir_all(containing_dir)
-        .with_context(|| format!("Failed to create dir {:?}", path_as_ref))?;
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("Failed to create dir {:?}", path))?;
+
+    // The temporary file must be in the same directory (and so on the same
+    // filesystem) as the target for the rename to be atomic. A temporary file
+    // left behind by an earlier crash is simply overwritten.
+    let temp_path = sibling_path(path, ".tmp");
+
+    let result = write_synced(serializable, &temp_path).and_then(|()| {
+        // A crash between these renames leaves only the backup, which
+        // readers fall back to.
+        if keep_backup && holds_valid_json(path) {
+            let backup_path = backup_path(path);
+            std::fs::rename(path, &backup_path)
+                .context(UpdateFailure::Storage)
+                .with_context(|| format!("Failed to rename {:?} to {:?}", path, backup_path))?;
+        }
+        std::fs::rename(&temp_path, path)
+            .context(UpdateFailure::Storage)
+            .with_context(|| format!("Failed to rename {:?} to {:?}", temp_path, path))
+    });
+    if result.is_err() {
+        // Don't leave a partial file behind. The target is untouched.
+        let _ = std::fs::remove_file(&temp_path);
+        return result;
+    }
+
+    sync_dir(containing_dir);
+    Ok(())
+}
 
-    let file = File::create(path).with_context(|| format!("File::create for {:?}", path_as_ref))?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, serializable)
-        .with_context(|| format!("failed to serialize to {:?}", path_as_ref))
+/// Whether `path` exists and parses as JSON. We only back up files which do,
+/// so that a corrupt file never replaces a good backup.
+fn holds_valid_json(path: &Path) -> bool {
+    File::open(path)
+        .map(|file| serde_json::from_reader::<_, IgnoredAny>(BufReader::new(file)).is_ok())
+        .unwrap_or(false)
+}
+
+/// Writes `serializable` to `path` and waits for it to reach the disk.
+fn write_synced<S>(serializable: &S, path: &Path) -> anyhow::Result<()>
+where
+    S: ?Sized + Serialize,
+{
+    let file = File::create(path)
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("File::create for {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, serializable)
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("failed to serialize to {:?}", path))?;
+    // Dropping a BufWriter ignores write errors (e.g. a full disk), so flush
+    // explicitly.
+    let file = writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("failed to write {:?}", path))?;
+    file.sync_all()
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("failed to sync {:?}", path))
+}
+
+/// Makes a rename within `dir` durable. Failing to do so isn't fatal: the
+/// rename has already happened, and at worst is lost in a power cut.
+fn sync_dir(dir: &Path) {
+    // Windows can't open directories as files, and doesn't need this.
+    #[cfg(unix)]
+    if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+        shorebird_warn!("Failed to sync dir {:?}: {}", dir, e);
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
 }
 
 pub fn read<D, P>(path: &P) -> anyhow::Result<D>
@@ -44,6 +153,7 @@ where
     let file = File::open(path_as_ref)?;
     let reader = BufReader::new(file);
     serde_json::from_reader(reader)
+        .context(UpdateFailure::Storage)
         .with_context(|| format!("failed to deserialize from {:?}", &path_as_ref))
 }
 
@@ -78,6 +188,109 @@ mod test {
         Ok(())
     }
 
+    /// Serializes a field and then fails, like a write interrupted part way.
+    struct Interrupted;
+
+    impl Serialize for Interrupted {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{Error, SerializeStruct};
+            let mut state = serializer.serialize_struct("TestStruct", 2)?;
+            state.serialize_field("a", &2)?;
+            Err(S::Error::custom("interrupted"))
+        }
+    }
+
+    #[test]
+    fn interrupted_write_leaves_previous_contents() -> Result<()> {
+        let test_struct = TestStruct {
+            a: 1,
+            b: "hello".to_string(),
+        };
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        super::write(&test_struct, &path)?;
+
+        assert!(super::write(&Interrupted, &path).is_err());
+
+        let read_struct: TestStruct = super::read(&path)?;
+        assert!(test_struct == read_struct);
+        // The partial write is cleaned up.
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_replaces_file_left_by_crash() -> Result<()> {
+        let test_struct = TestStruct {
+            a: 1,
+            b: "hello".to_string(),
+        };
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        super::write(&test_struct, &path)?;
+        // A crash between creating the temporary file and renaming it leaves
+        // a truncated temporary file, which must not affect the target.
+        std::fs::write(temp_dir.path().join("test.json.tmp"), "{\"a\": 2")?;
+        let read_struct: TestStruct = super::read(&path)?;
+        assert!(test_struct == read_struct);
+
+        let new_struct = TestStruct {
+            a: 2,
+            b: "world".to_string(),
+        };
+        super::write(&new_struct, &path)?;
+        let read_struct: TestStruct = super::read(&path)?;
+        assert!(new_struct == read_struct);
+        assert!(!temp_dir.path().join("test.json.tmp").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_previous_good_generation_as_backup() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        let backup_path = super::backup_path(&path);
+        assert_eq!(backup_path, temp_dir.path().join("test.json.bak"));
+
+        let first = TestStruct {
+            a: 1,
+            b: "first".to_string(),
+        };
+        super::write_keeping_backup(&first, &path)?;
+        assert!(!backup_path.exists());
+
+        let second = TestStruct {
+            a: 2,
+            b: "second".to_string(),
+        };
+        super::write_keeping_backup(&second, &path)?;
+        assert!(super::read::<TestStruct, _>(&path)? == second);
+        assert!(super::read::<TestStruct, _>(&backup_path)? == first);
+
+        // A corrupt file doesn't replace the backup.
+        std::fs::write(&path, "{\"a\": 2")?;
+        super::write_keeping_backup(&second, &path)?;
+        assert!(super::read::<TestStruct, _>(&backup_path)? == first);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_does_not_keep_backup() -> Result<()> {
+        let test_struct = TestStruct {
+            a: 1,
+            b: "hello".to_string(),
+        };
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        super::write(&test_struct, &path)?;
+        super::write(&test_struct, &path)?;
+        assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 1);
+        Ok(())
+    }
+
     #[test]
     fn read_errs_if_file_does_not_exist() {
         assert!(super::read::<TestStruct, _>(&Path::new("nonexistent.json")).is_err());
@@ -1,9 +1,13 @@
-mod disk_io;
+pub(crate) mod disk_io;
 mod patch_manager;
+mod schema;
 mod signing;
+mod storage_lock;
+mod store;
 pub mod updater_state;
 
-pub use updater_state::UpdaterState;
+pub use storage_lock::StorageLock;
+pub use updater_state::{CachedPatchCheck, UpdaterState};
 
 /// The public interface for talking about patches to the Cache.
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -1,10 +1,12 @@
-use super::{disk_io, signing, PatchInfo};
+use super::{disk_io, schema::Schema, signing, store::Store, PatchInfo};
+use crate::UpdateFailure;
 use anyhow::{bail, Context, Result};
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 #[cfg(test)]
@@ -13,9 +15,17 @@ use mockall::automock;
 use tempdir::TempDir;
 
 const PATCHES_DIR_NAME: &str = "patches";
-const PATCHES_STATE_FILE_NAME: &str = "patches_state.json";
+pub(super) const PATCHES_STATE_FILE_NAME: &str = "patches_state.json";
 const PATCH_ARTIFACT_FILENAME: &str = "dlc.vmcode";
 
+/// Migrations for PATCHES_STATE_FILE_NAME. See [Schema].
+const PATCHES_STATE_SCHEMA: Schema = Schema {
+    migrations: &[
+        // Version 1 only adds the version number.
+        |_| Ok(()),
+    ],
+};
+
 /// Information about a patch that is persisted to disk.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 struct PatchMetadata {
@@ -111,6 +121,17 @@ pub trait ManagePatches {
     /// If the patch is the next_boot_patch, it is cleared.
     fn remove_patch(&mut self, patch_number: usize) -> Result<()>;
 
+    /// Stops booting a patch newer than the last successfully booted patch,
+    /// falling back to the last booted patch (or the release, if none). If
+    /// `mark_bad` is true, the abandoned patch is recorded as known-bad so it
+    /// is never installed again. Returns the abandoned patch number, if any.
+    fn roll_back_to_last_booted_patch(&mut self, mark_bad: bool) -> Result<Option<usize>>;
+
+    /// Stops booting any patch, falling back to the release. If `mark_bad` is
+    /// true, the abandoned patches are recorded as known-bad. Returns the
+    /// abandoned patch numbers, newest first.
+    fn roll_back_to_release(&mut self, mark_bad: bool) -> Result<Vec<usize>>;
+
     /// Resets the patch manager to its initial state, removing all patches. This is
     /// intended to be used when a new release version is installed.
     fn reset(&mut self) -> Result<()>;
@@ -136,6 +157,12 @@ pub struct PatchManager {
     ///      dlc.vmcode
     root_dir: PathBuf,
 
+    /// Whether we couldn't read the patches state and loaded its backup instead.
+    recovered_from_backup: bool,
+
+    /// Commits our changes to disk, along with those of whoever shares it.
+    store: Rc<Store>,
+
     /// Metadata about the patches we have downloaded that is persisted to disk.
     patches_state: PatchesState,
 
@@ -145,23 +172,34 @@ pub struct PatchManager {
 }
 
 impl PatchManager {
-    /// Creates a new PatchManager with the given root directory. This directory is
-    /// assumed to exist. The PatchManager will use this directory to store its
-    /// state and patch binaries.
-    pub fn new(root_dir: PathBuf, patch_public_key: Option<&str>) -> Self {
-        let patches_state = Self::load_patches_state(&root_dir).unwrap_or_default();
+    /// Creates a new PatchManager which keeps its state and patch binaries in
+    /// the directory of `store`. This directory is assumed to exist.
+    pub fn new(store: Rc<Store>, patch_public_key: Option<&str>) -> Self {
+        let root_dir = store.dir().to_owned();
+        let (patches_state, recovered_from_backup) =
+            Self::load_patches_state(&root_dir).unwrap_or_default();
 
         Self {
             root_dir,
+            recovered_from_backup,
+            store,
             patches_state,
             patch_public_key: patch_public_key.map(|s| s.to_owned()),
         }
     }
 
-    fn load_patches_state(root_dir: &Path) -> Option<PatchesState> {
+    /// Whether the patches state on disk was unreadable and we fell back to
+    /// the backup of its previous generation.
+    pub fn recovered_from_backup(&self) -> bool {
+        self.recovered_from_backup
+    }
+
+    /// Loads the patches state, falling back to its backup, and returns it
+    /// along with whether it came from the backup.
+    fn load_patches_state(root_dir: &Path) -> Option<(PatchesState, bool)> {
         let path = root_dir.join(PATCHES_STATE_FILE_NAME);
-        match disk_io::read(&path) {
-            Ok(maybe_state) => maybe_state,
+        match PATCHES_STATE_SCHEMA.read_or_recover(&path) {
+            Ok(loaded) => Some(loaded),
             Err(e) => {
                 shorebird_debug!(
                     "Failed to load patches state from {}: {}",
@@ -175,7 +213,8 @@ impl PatchManager {
 
     fn save_patches_state(&self) -> Result<()> {
         let path = self.root_dir.join(PATCHES_STATE_FILE_NAME);
-        disk_io::write(&self.patches_state, &path)
+        self.store
+            .write(&path, PATCHES_STATE_SCHEMA.to_value(&self.patches_state)?)
     }
 
     /// The directory where all patch artifacts are stored.
@@ -228,10 +267,10 @@ impl PatchManager {
 
         if let Some(public_key) = &self.patch_public_key {
             // If we have a public key, verify that the patch's hash has a signature.
-            let signature = patch
-                .signature
-                .clone()
-                .context("Patch signature is missing")?;
+            let signature = patch.signature.clone().ok_or_else(|| {
+                anyhow::Error::new(UpdateFailure::SignatureInvalid)
+                    .context("Patch signature is missing")
+            })?;
 
             // Check that the signature is valid.
             let patch_hash = signing::hash_file(&artifact_path)?;
@@ -252,7 +291,8 @@ impl PatchManager {
 
         shorebird_info!("Deleting patch artifacts for patch {}", patch_number);
 
-        std::fs::remove_dir_all(&patch_dir)
+        self.store
+            .remove(&patch_dir)
             .map_err(|e| {
                 shorebird_error!("Failed to delete patch dir {}: {}", patch_dir.display(), e);
                 e
@@ -264,6 +304,12 @@ impl PatchManager {
     /// successfully booted patch. If the last successfully booted patch is not bootable or has the same number
     /// as the patch we're falling back from, we clear it as well.
     fn try_fall_back_from_patch(&mut self, bad_patch_number: usize) -> Result<()> {
+        let store = self.store.clone();
+        store.transaction(|| self.fall_back_from_patch(bad_patch_number))
+    }
+
+    /// try_fall_back_from_patch, within a transaction.
+    fn fall_back_from_patch(&mut self, bad_patch_number: usize) -> Result<()> {
         shorebird_info!("Falling back from patch {}", bad_patch_number);
 
         // Continue even if we fail to delete the patch artifacts. It's more important to not try to
@@ -295,6 +341,59 @@ impl PatchManager {
         self.save_patches_state()
     }
 
+    /// Falls back from a patch the user chose to roll back, optionally making
+    /// sure it is never installed again.
+    fn abandon_patch(&mut self, patch_number: usize, mark_bad: bool) -> Result<()> {
+        if mark_bad {
+            self.patches_state.known_bad_patches.insert(patch_number);
+        }
+        // Don't report a boot success for a patch we won't boot next time.
+        if let Some(ref booting_patch) = self.patches_state.currently_booting_patch {
+            if booting_patch.number == patch_number {
+                self.patches_state.currently_booting_patch = None;
+            }
+        }
+        self.try_fall_back_from_patch(patch_number)
+    }
+
+    /// add_patch, within a transaction.
+    fn add_patch_in_transaction(
+        &mut self,
+        patch_number: usize,
+        file_path: &Path,
+        hash: &str,
+        signature: Option<&str>,
+    ) -> Result<()> {
+        let patch_path = self.patch_artifact_path(patch_number);
+        self.store.move_file(file_path, &patch_path)?;
+
+        let new_patch = PatchMetadata {
+            number: patch_number,
+            // The file is only moved when the transaction commits.
+            size: std::fs::metadata(file_path)?.len(),
+            hash: hash.to_owned(),
+            signature: signature.map(|s| s.to_owned()),
+        };
+
+        // If a patch was never booted (next_boot_patch != last_booted_patch), we should delete
+        // it here before setting next_boot_patch to the new patch.
+        if let (Some(last_boot_patch), Some(next_boot_patch)) = (
+            self.patches_state.next_boot_patch.clone(),
+            self.patches_state.last_booted_patch.clone(),
+        ) {
+            if last_boot_patch.number != next_boot_patch.number {
+                shorebird_info!(
+                    "Patch {} was installed but never booted never booted, deleting artifacts",
+                    next_boot_patch.number
+                );
+                let _ = self.delete_patch_artifacts(next_boot_patch.number);
+            }
+        }
+
+        self.patches_state.next_boot_patch = Some(new_patch);
+        self.save_patches_state()
+    }
+
     /// Deletes all patch artifacts with numbers less than patch_number.
     /// We intentionally only delete older patch artifacts. Consider the case:
     ///
@@ -321,7 +420,7 @@ impl PatchManager {
                     );
                     // Attempt to delete the unrecognized directory, but don't stop
                     // the artifact deletion process if it fails.
-                    let _ = std::fs::remove_dir_all(entry.path());
+                    let _ = self.store.remove(&entry.path());
                 }
             }
         }
@@ -344,38 +443,10 @@ impl ManagePatches for PatchManager {
         if !file_path.exists() {
             bail!("Patch file {} does not exist", file_path.display());
         }
-
-        let patch_path = self.patch_artifact_path(patch_number);
-
-        std::fs::create_dir_all(self.patch_dir(patch_number))
-            .with_context(|| format!("create_dir_all failed for {}", patch_path.display()))?;
-
-        std::fs::rename(file_path, &patch_path)?;
-
-        let new_patch = PatchMetadata {
-            number: patch_number,
-            size: std::fs::metadata(&patch_path)?.len(),
-            hash: hash.to_owned(),
-            signature: signature.map(|s| s.to_owned()),
-        };
-
-        // If a patch was never booted (next_boot_patch != last_booted_patch), we should delete
-        // it here before setting next_boot_patch to the new patch.
-        if let (Some(last_boot_patch), Some(next_boot_patch)) = (
-            self.patches_state.next_boot_patch.clone(),
-            self.patches_state.last_booted_patch.clone(),
-        ) {
-            if last_boot_patch.number != next_boot_patch.number {
-                shorebird_info!(
-                    "Patch {} was installed but never booted never booted, deleting artifacts",
-                    next_boot_patch.number
-                );
-                let _ = self.delete_patch_artifacts(next_boot_patch.number);
-            }
-        }
-
-        self.patches_state.next_boot_patch = Some(new_patch);
-        self.save_patches_state()
+        let store = self.store.clone();
+        // Move the artifact into place and record it together.
+        store
+            .transaction(|| self.add_patch_in_transaction(patch_number, file_path, hash, signature))
     }
 
     fn last_successfully_booted_patch(&self) -> Option<PatchInfo> {
@@ -444,14 +515,17 @@ impl ManagePatches for PatchManager {
 
         self.patches_state.currently_booting_patch = None;
         self.patches_state.last_booted_patch = Some(boot_patch.clone());
-        if let Err(e) = self.delete_patch_artifacts_older_than(boot_patch.number) {
-            shorebird_error!(
-                "Failed to delete patch artifacts older than {}: {}",
-                boot_patch.number,
-                e
-            );
-        }
-        self.save_patches_state()
+        let store = self.store.clone();
+        store.transaction(|| {
+            if let Err(e) = self.delete_patch_artifacts_older_than(boot_patch.number) {
+                shorebird_error!(
+                    "Failed to delete patch artifacts older than {}: {}",
+                    boot_patch.number,
+                    e
+                );
+            }
+            self.save_patches_state()
+        })
     }
 
     fn record_boot_failure_for_patch(&mut self, patch_number: usize) -> Result<()> {
@@ -468,14 +542,57 @@ impl ManagePatches for PatchManager {
         self.try_fall_back_from_patch(patch_number)
     }
 
+    fn roll_back_to_last_booted_patch(&mut self, mark_bad: bool) -> Result<Option<usize>> {
+        let Some(next_boot_patch) = self.patches_state.next_boot_patch.clone() else {
+            return Ok(None);
+        };
+        let last_booted_number = self
+            .patches_state
+            .last_booted_patch
+            .as_ref()
+            .map(|p| p.number);
+        if last_booted_number == Some(next_boot_patch.number) {
+            // We're already booting the last booted patch.
+            return Ok(None);
+        }
+        shorebird_info!("Rolling back patch {}", next_boot_patch.number);
+        self.abandon_patch(next_boot_patch.number, mark_bad)?;
+        Ok(Some(next_boot_patch.number))
+    }
+
+    fn roll_back_to_release(&mut self, mark_bad: bool) -> Result<Vec<usize>> {
+        let patches = [
+            self.patches_state.next_boot_patch.clone(),
+            self.patches_state.last_booted_patch.clone(),
+        ];
+        let store = self.store.clone();
+        store.transaction(|| {
+            let mut abandoned = Vec::new();
+            for patch in patches.into_iter().flatten() {
+                if abandoned.contains(&patch.number) {
+                    continue;
+                }
+                shorebird_info!("Rolling back patch {}", patch.number);
+                self.abandon_patch(patch.number, mark_bad)?;
+                abandoned.push(patch.number);
+            }
+            Ok(abandoned)
+        })
+    }
+
     fn reset(&mut self) -> Result<()> {
         self.patches_state = PatchesState::default();
-        self.save_patches_state()?;
-        std::fs::remove_dir_all(self.patches_dir()).with_context(|| {
-            format!(
-                "Failed to delete patches dir {}",
-                self.patches_dir().display()
-            )
+        let store = self.store.clone();
+        store.transaction(|| {
+            self.save_patches_state()?;
+            // The backup describes patches we no longer have, possibly for
+            // another release, so it must never be recovered. Removals happen
+            // after writes, so this also removes the backup of the state
+            // saved above.
+            store.remove(&disk_io::backup_path(
+                &self.root_dir.join(PATCHES_STATE_FILE_NAME),
+            ))?;
+            store.remove(&self.patches_dir())
         })
     }
 }
@@ -483,7 +600,7 @@ impl ManagePatches for PatchManager {
 #[cfg(test)]
 impl PatchManager {
     pub fn manager_for_test(temp_dir: &TempDir) -> PatchManager {
-        PatchManager::new(temp_dir.path().to_owned(), None)
+        PatchManager::new(Store::open(temp_dir.path()), None)
     }
 
     pub fn add_patch_for_test(&mut self, temp_dir: &TempDir, patch_number: usize) -> Result<()> {
@@ -516,7 +633,7 @@ impl PatchManager {
 mod debug_tests {
     use tempdir::TempDir;
 
-    use super::PatchManager;
+    use super::{PatchManager, Store};
 
     #[test]
     fn manage_patches_is_debug() {
@@ -529,12 +646,120 @@ mod debug_tests {
     #[test]
     fn patch_manager_is_debug() {
         let temp_dir = TempDir::new("patch_manager").unwrap();
-        let patch_manager = PatchManager::new(temp_dir.path().to_owned(), Some("public_key"));
+        let patch_manager = PatchManager::new(Store::open(temp_dir.path()), Some("public_key"));
         let actual = format!("{:?}", patch_manager);
         assert!(actual.contains(r#"patches_state: PatchesState { last_booted_patch: None, next_boot_patch: None, currently_booting_patch: None, known_bad_patches: {} }, patch_public_key: Some("public_key") }"#));
     }
 }
 
+#[cfg(test)]
+mod schema_tests {
+    use super::*;
+    use anyhow::{Ok, Result};
+    use tempdir::TempDir;
+
+    fn write_patches_state(temp_dir: &TempDir, contents: &str) -> Result<()> {
+        std::fs::write(temp_dir.path().join(PATCHES_STATE_FILE_NAME), contents)?;
+        Ok(())
+    }
+
+    #[test]
+    fn loads_unversioned_patches_state() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+
+        write_patches_state(
+            &temp_dir,
+            r#"{
+                "last_booted_patch": null,
+                "next_boot_patch": {"number": 1, "size": 1, "hash": "hash", "signature": null},
+                "currently_booting_patch": null,
+                "known_bad_patches": [2]
+            }"#,
+        )?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        assert_eq!(manager.next_boot_patch().unwrap().number, 1);
+        assert!(manager.is_known_bad_patch(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_unversioned_patches_state_from_before_signing() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+
+        write_patches_state(
+            &temp_dir,
+            r#"{
+                "last_booted_patch": null,
+                "next_boot_patch": {"number": 1, "size": 1, "hash": "hash"},
+                "currently_booting_patch": null,
+                "known_bad_patches": []
+            }"#,
+        )?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        assert_eq!(manager.next_boot_patch().unwrap().number, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_corrupt_patches_state_from_backup() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+        manager.record_boot_start_for_patch(1)?;
+        manager.record_boot_failure_for_patch(1)?;
+        // The backup is the generation before the last write.
+        manager.add_patch_for_test(&temp_dir, 2)?;
+        assert!(!PatchManager::manager_for_test(&temp_dir).recovered_from_backup());
+
+        // Pretend the last write was cut short.
+        write_patches_state(&temp_dir, r#"{"last_booted_patch": nu"#)?;
+        let manager = PatchManager::manager_for_test(&temp_dir);
+        assert!(manager.recovered_from_backup());
+        assert!(manager.is_known_bad_patch(1));
+
+        // The file was restored from the backup.
+        let manager = PatchManager::manager_for_test(&temp_dir);
+        assert!(!manager.recovered_from_backup());
+        assert!(manager.is_known_bad_patch(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_removes_backup() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+        manager.add_patch_for_test(&temp_dir, 2)?;
+        let backup_path = disk_io::backup_path(&temp_dir.path().join(PATCHES_STATE_FILE_NAME));
+        assert!(backup_path.exists());
+
+        manager.reset()?;
+        assert!(!backup_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn saves_schema_version() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+
+        let contents = std::fs::read_to_string(temp_dir.path().join(PATCHES_STATE_FILE_NAME))?;
+        let on_disk: serde_json::Value = serde_json::from_str(&contents)?;
+        assert_eq!(on_disk["schema_version"], PATCHES_STATE_SCHEMA.version());
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod add_patch_tests {
     use super::*;
@@ -802,7 +1027,7 @@ mod next_boot_patch_tests {
     #[test]
     fn returns_none_if_public_key_is_invalid() -> Result<()> {
         let temp_dir = TempDir::new("patch_manager")?;
-        let mut manager = PatchManager::new(temp_dir.path().to_path_buf(), Some("not a valid key"));
+        let mut manager = PatchManager::new(Store::open(temp_dir.path()), Some("not a valid key"));
 
         manager.add_signed_patch_for_test(&temp_dir, 1, INFLATED_PATCH_HASH, Some(SIGNATURE))?;
 
@@ -814,7 +1039,7 @@ mod next_boot_patch_tests {
     #[test]
     fn returns_none_if_patch_is_missing_expected_signature() -> Result<()> {
         let temp_dir = TempDir::new("patch_manager")?;
-        let mut manager = PatchManager::new(temp_dir.path().to_path_buf(), Some(PUBLIC_KEY));
+        let mut manager = PatchManager::new(Store::open(temp_dir.path()), Some(PUBLIC_KEY));
 
         manager.add_signed_patch_for_test(&temp_dir, 1, INFLATED_PATCH_HASH, None)?;
 
@@ -826,7 +1051,7 @@ mod next_boot_patch_tests {
     #[test]
     fn returns_none_if_patch_has_invalid_signature() -> Result<()> {
         let temp_dir = TempDir::new("patch_manager")?;
-        let mut manager = PatchManager::new(temp_dir.path().to_path_buf(), Some(PUBLIC_KEY));
+        let mut manager = PatchManager::new(Store::open(temp_dir.path()), Some(PUBLIC_KEY));
 
         // Using MESSAGE as a signature because it is valid base64, but not a valid signature.
         manager.add_signed_patch_for_test(
@@ -844,7 +1069,7 @@ mod next_boot_patch_tests {
     #[test]
     fn returns_patch_if_patch_has_valid_signature() -> Result<()> {
         let temp_dir = TempDir::new("patch_manager")?;
-        let mut manager = PatchManager::new(temp_dir.path().to_path_buf(), Some(PUBLIC_KEY));
+        let mut manager = PatchManager::new(Store::open(temp_dir.path()), Some(PUBLIC_KEY));
 
         manager.add_signed_patch_for_test(&temp_dir, 1, INFLATED_PATCH_HASH, Some(SIGNATURE))?;
 
@@ -1187,6 +1412,75 @@ mod record_boot_failure_for_patch_tests {
     }
 }
 
+#[cfg(test)]
+mod roll_back_tests {
+    use super::*;
+    use anyhow::{Ok, Result};
+    use tempdir::TempDir;
+
+    #[test]
+    fn does_nothing_if_no_patch_exists() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        assert_eq!(manager.roll_back_to_last_booted_patch(true)?, None);
+        assert!(manager.roll_back_to_release(true)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_last_booted_patch() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+        manager.record_boot_start_for_patch(1)?;
+        manager.record_boot_success()?;
+        manager.add_patch_for_test(&temp_dir, 2)?;
+        let abandoned_artifact_path = manager.patch_artifact_path(2);
+
+        assert_eq!(manager.roll_back_to_last_booted_patch(false)?, Some(2));
+        assert_eq!(manager.next_boot_patch().unwrap().number, 1);
+        assert!(!abandoned_artifact_path.exists());
+        assert!(!manager.is_known_bad_patch(2));
+
+        // Nothing newer than the last booted patch to roll back.
+        assert_eq!(manager.roll_back_to_last_booted_patch(false)?, None);
+        assert_eq!(manager.next_boot_patch().unwrap().number, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn marks_abandoned_patch_as_bad_if_asked() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+        manager.record_boot_start_for_patch(1)?;
+
+        assert_eq!(manager.roll_back_to_last_booted_patch(true)?, Some(1));
+        assert!(manager.next_boot_patch().is_none());
+        assert!(manager.currently_booting_patch().is_none());
+        assert!(manager.is_known_bad_patch(1));
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_release() -> Result<()> {
+        let temp_dir = TempDir::new("patch_manager")?;
+        let mut manager = PatchManager::manager_for_test(&temp_dir);
+        manager.add_patch_for_test(&temp_dir, 1)?;
+        manager.record_boot_start_for_patch(1)?;
+        manager.record_boot_success()?;
+        manager.add_patch_for_test(&temp_dir, 2)?;
+
+        assert_eq!(manager.roll_back_to_release(true)?, vec![2, 1]);
+        assert!(manager.next_boot_patch().is_none());
+        assert!(manager.last_successfully_booted_patch().is_none());
+        assert!(manager.is_known_bad_patch(1));
+        assert!(manager.is_known_bad_patch(2));
+        assert!(!manager.patch_artifact_path(1).exists());
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod reset_tests {
     use super::*;
@@ -0,0 +1,300 @@
+// This file deals with versioning the JSON files we keep on disk, so that an
+// updater which changes their shape can upgrade files written by an older one
+// rather than throwing them (and the patches they describe) away.
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+use super::disk_io;
+
+/// The key under which a file's schema version is stored. Files written
+/// before we versioned them don't have it, and are version 0.
+const VERSION_KEY: &str = "schema_version";
+
+/// Upgrades a file's JSON object from one schema version to the next.
+pub type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// The migrations for one kind of file. `migrations[n]` upgrades version `n`
+/// to version `n + 1`, so the current version is the number of migrations.
+/// Migrations must only ever be appended.
+pub struct Schema {
+    pub migrations: &'static [Migration],
+}
+
+impl Schema {
+    /// The version of the files we write.
+    pub fn version(&self) -> u64 {
+        self.migrations.len() as u64
+    }
+
+    /// Reads the file at `path`, upgrading it (both in memory and on disk)
+    /// from an older schema version if needed. Fails if the file can't be
+    /// read, or was written by a newer version of the updater than this one.
+    pub fn read<D, P>(&self, path: &P) -> Result<D>
+    where
+        D: DeserializeOwned,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut value: Value = disk_io::read(&path)?;
+        let object = value
+            .as_object_mut()
+            .with_context(|| format!("{:?} does not contain a JSON object", path))?;
+        let version = match object.remove(VERSION_KEY) {
+            None => 0,
+            Some(version) => version
+                .as_u64()
+                .with_context(|| format!("Invalid {} in {:?}: {}", VERSION_KEY, path, version))?,
+        };
+        if version > self.version() {
+            bail!(
+                "{:?} has schema version {}, but the newest we understand is {}",
+                path,
+                version,
+                self.version()
+            );
+        }
+
+        for (from, migration) in self.migrations.iter().enumerate().skip(version as usize) {
+            shorebird_info!(
+                "Migrating {:?} from schema version {} to {}",
+                path,
+                from,
+                from + 1
+            );
+            migration(object)
+                .with_context(|| format!("Failed to migrate {:?} from version {}", path, from))?;
+        }
+
+        let migrated = version < self.version();
+        if migrated {
+            object.insert(VERSION_KEY.to_owned(), self.version().into());
+        }
+        let result = serde_json::from_value(value.clone())
+            .with_context(|| format!("failed to deserialize from {:?}", path))?;
+        if migrated {
+            // Not fatal: we'll just migrate again next time.
+            if let Err(e) = disk_io::write(&value, &path) {
+                shorebird_warn!("Failed to save migrated {:?}: {:?}", path, e);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Like [Schema::read], but if the file can't be read, falls back to the
+    /// backup [Schema::write] keeps of the previous generation, and restores
+    /// the file from it. Returns the value read and whether it came from the
+    /// backup. Fails with the error reading the file if there is no usable
+    /// backup.
+    pub fn read_or_recover<D, P>(&self, path: &P) -> Result<(D, bool)>
+    where
+        D: DeserializeOwned + Serialize,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let error = match self.read(&path) {
+            Ok(value) => return Ok((value, false)),
+            Err(error) => error,
+        };
+        let backup_path = disk_io::backup_path(path);
+        if !backup_path.exists() {
+            return Err(error);
+        }
+        shorebird_warn!("Failed to read {:?}, trying backup: {:?}", path, error);
+        let value = match self.read(&backup_path) {
+            Ok(value) => value,
+            Err(backup_error) => {
+                shorebird_error!(
+                    "Failed to read backup {:?}: {:?}",
+                    backup_path,
+                    backup_error
+                );
+                return Err(error);
+            }
+        };
+        // Not fatal: the backup is still there for next time.
+        if let Err(e) = self.write(&value, &path) {
+            shorebird_warn!("Failed to restore {:?} from backup: {:?}", path, e);
+        }
+        Ok((value, true))
+    }
+
+    /// Writes `serializable`, which must serialize to a JSON object, to `path`
+    /// tagged with the current schema version, keeping the previous contents
+    /// as a backup.
+    pub fn write<S, P>(&self, serializable: &S, path: &P) -> Result<()>
+    where
+        S: Serialize,
+        P: AsRef<Path>,
+    {
+        let value = self
+            .to_value(serializable)
+            .with_context(|| format!("failed to serialize to {:?}", path.as_ref()))?;
+        disk_io::write_keeping_backup(&value, path)
+    }
+
+    /// Serializes `serializable`, which must serialize to a JSON object,
+    /// tagged with the current schema version, e.g. to write it through a
+    /// [super::store::Store].
+    pub fn to_value<S: Serialize>(&self, serializable: &S) -> Result<Value> {
+        let mut value = serde_json::to_value(serializable)?;
+        value
+            .as_object_mut()
+            .context("Only JSON objects can be versioned")?
+            .insert(VERSION_KEY.to_owned(), self.version().into());
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Ok, Result};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Map, Value};
+    use tempdir::TempDir;
+
+    use super::Schema;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Current {
+        name: String,
+        count: u32,
+    }
+
+    fn rename_title_to_name(object: &mut Map<String, Value>) -> anyhow::Result<()> {
+        let title = object.remove("title").unwrap_or_default();
+        object.insert("name".to_owned(), title);
+        Ok(())
+    }
+
+    fn add_count(object: &mut Map<String, Value>) -> anyhow::Result<()> {
+        object.insert("count".to_owned(), 0.into());
+        Ok(())
+    }
+
+    const SCHEMA: Schema = Schema {
+        migrations: &[rename_title_to_name, add_count],
+    };
+
+    #[test]
+    fn writes_current_version() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        let current = Current {
+            name: "a".to_owned(),
+            count: 1,
+        };
+        SCHEMA.write(&current, &path)?;
+
+        let on_disk: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(
+            on_disk,
+            json!({"name": "a", "count": 1, "schema_version": 2})
+        );
+        assert_eq!(SCHEMA.read::<Current, _>(&path)?, current);
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_unversioned_file_in_place() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        std::fs::write(&path, r#"{"title": "a"}"#)?;
+
+        let expected = Current {
+            name: "a".to_owned(),
+            count: 0,
+        };
+        assert_eq!(SCHEMA.read::<Current, _>(&path)?, expected);
+        let on_disk: Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+        assert_eq!(
+            on_disk,
+            json!({"name": "a", "count": 0, "schema_version": 2})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_runs_migrations_newer_than_file() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        // rename_title_to_name would clobber name if it ran.
+        std::fs::write(&path, r#"{"name": "a", "schema_version": 1}"#)?;
+
+        let expected = Current {
+            name: "a".to_owned(),
+            count: 0,
+        };
+        assert_eq!(SCHEMA.read::<Current, _>(&path)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn errs_on_newer_version() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        let contents = r#"{"name": "a", "count": 1, "schema_version": 3}"#;
+        std::fs::write(&path, contents)?;
+
+        assert!(SCHEMA.read::<Current, _>(&path).is_err());
+        // The file is left for the newer updater.
+        assert_eq!(std::fs::read_to_string(&path)?, contents);
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_from_backup() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        // No file and no backup.
+        assert!(SCHEMA.read_or_recover::<Current, _>(&path).is_err());
+
+        let first = Current {
+            name: "first".to_owned(),
+            count: 1,
+        };
+        SCHEMA.write(&first, &path)?;
+        assert_eq!(SCHEMA.read_or_recover(&path)?, (first, false));
+        // Corrupt the file and leave a backup from an older updater.
+        std::fs::write(&path, "{\"name\": ")?;
+        std::fs::write(temp_dir.path().join("test.json.bak"), r#"{"title": "old"}"#)?;
+
+        let old = Current {
+            name: "old".to_owned(),
+            count: 0,
+        };
+        assert_eq!(SCHEMA.read_or_recover(&path)?, (old, true));
+        // The file is restored, so we don't need the backup next time.
+        assert_eq!(
+            SCHEMA.read::<Current, _>(&path)?,
+            Current {
+                name: "old".to_owned(),
+                count: 0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn errs_if_file_and_backup_are_unreadable() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        std::fs::write(&path, "junk")?;
+        std::fs::write(temp_dir.path().join("test.json.bak"), "more junk")?;
+        assert!(SCHEMA.read_or_recover::<Current, _>(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn errs_on_unreadable_file() -> Result<()> {
+        let temp_dir = TempDir::new("test")?;
+        let path = temp_dir.path().join("test.json");
+        std::fs::write(&path, "[1, 2]")?;
+        assert!(SCHEMA.read::<Current, _>(&path).is_err());
+        std::fs::write(&path, r#"{"schema_version": "one"}"#)?;
+        assert!(SCHEMA.read::<Current, _>(&path).is_err());
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ use anyhow::{bail, Context, Result};
 use base64::Engine;
 use std::path::Path;
 
+use crate::UpdateFailure;
+
 /// Reads the file at `path` and returns the SHA-256 hash of its contents as a String.
 pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String> {
     use sha2::{Digest, Sha256}; // `Digest` is needed for `Sha256::new()`;
@@ -40,7 +42,10 @@ pub fn check_signature(message: &str, signature: &str, public_key: &str) -> Resu
     );
     let decoded_sig = base64::prelude::BASE64_STANDARD
         .decode(signature)
-        .map_err(|e| anyhow::Error::msg(format!("Failed to decode signature: {:?}", e)))?;
+        .map_err(|e| {
+            anyhow::Error::new(UpdateFailure::SignatureInvalid)
+                .context(format!("Failed to decode signature: {:?}", e))
+        })?;
 
     shorebird_info!("Verifying patch signature...");
     match public_key.verify(message.as_bytes(), &decoded_sig) {
@@ -52,7 +57,7 @@ pub fn check_signature(message: &str, signature: &str, public_key: &str) -> Resu
             // The error provided by `verify` is (by design) not helpful, so we ignore it.
             // See https://docs.rs/ring/latest/ring/error/struct.Unspecified.html
             shorebird_error!("Patch signature is invalid");
-            bail!("Patch signature is invalid")
+            bail!(UpdateFailure::SignatureInvalid)
         }
     }
 }
@@ -0,0 +1,241 @@
+// This file's job is to keep other processes sharing our storage directory
+// (e.g. an Android app's `:remote` service or WorkManager jobs, each with its
+// own copy of the updater) from changing updater state while we are.
+//
+// Threads within one process are already kept apart by the config and updater
+// locks, so the lock here is shared by all threads of a process: the first to
+// acquire it takes the file lock and the last to release it lets go. That also
+// lets an update hold the lock while calling `with_mut_state`, which takes it
+// again.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use crate::UpdateFailure;
+
+/// The (empty) file locked in the storage directory.
+const LOCK_FILE_NAME: &str = "updater.lock";
+
+/// A lock file and how many guards in this process share it. `file` is None
+/// while the first thread to want the lock is waiting to lock it.
+struct Held {
+    file: Option<File>,
+    holders: usize,
+}
+
+/// The lock files this process has locked (or is locking), by path.
+#[derive(Default)]
+struct HeldLocks {
+    map: Mutex<HashMap<PathBuf, Held>>,
+    /// Notified when a lock file finishes locking (or fails to).
+    changed: Condvar,
+}
+
+fn held_locks() -> &'static HeldLocks {
+    use once_cell::sync::OnceCell;
+    static INSTANCE: OnceCell<HeldLocks> = OnceCell::new();
+    INSTANCE.get_or_init(HeldLocks::default)
+}
+
+/// Holds the lock on a storage directory until dropped.
+#[derive(Debug)]
+pub struct StorageLock {
+    path: PathBuf,
+}
+
+impl StorageLock {
+    /// Locks `storage_dir` against other processes, waiting for any which
+    /// hold it to finish.
+    pub fn acquire(storage_dir: &Path) -> Result<Self> {
+        let path = storage_dir.join(LOCK_FILE_NAME);
+        let held_locks = held_locks();
+        let mut map = held_locks
+            .map
+            .lock()
+            .expect("Failed to acquire storage lock map.");
+        loop {
+            match map.get_mut(&path) {
+                Some(Held {
+                    file: Some(_),
+                    holders,
+                }) => {
+                    *holders += 1;
+                    return Ok(Self { path });
+                }
+                // Another thread is waiting for the lock, so wait with it
+                // rather than lock the file a second time.
+                Some(Held { file: None, .. }) => {
+                    map = held_locks
+                        .changed
+                        .wait(map)
+                        .expect("Failed to acquire storage lock map.");
+                }
+                None => break,
+            }
+        }
+        map.insert(
+            path.clone(),
+            Held {
+                file: None,
+                holders: 0,
+            },
+        );
+        // Don't keep other threads from taking or releasing locks while we
+        // wait for another process.
+        drop(map);
+
+        let result = open_and_lock(storage_dir, &path);
+
+        let mut map = held_locks
+            .map
+            .lock()
+            .expect("Failed to acquire storage lock map.");
+        held_locks.changed.notify_all();
+        match result {
+            Ok(file) => {
+                let held = map
+                    .get_mut(&path)
+                    .expect("Storage lock removed while locking.");
+                held.file = Some(file);
+                held.holders += 1;
+                Ok(Self { path })
+            }
+            Err(e) => {
+                // Let the next thread to want the lock try for itself.
+                map.remove(&path);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for StorageLock {
+    fn drop(&mut self) {
+        let mut map = held_locks()
+            .map
+            .lock()
+            .expect("Failed to acquire storage lock map.");
+        if let Some(held) = map.get_mut(&self.path) {
+            held.holders -= 1;
+            if held.holders == 0 {
+                // Closing the file releases the lock.
+                map.remove(&self.path);
+            }
+        }
+    }
+}
+
+/// Opens the lock file at `path` in `storage_dir` and waits to lock it.
+fn open_and_lock(storage_dir: &Path, path: &Path) -> Result<File> {
+    std::fs::create_dir_all(storage_dir)
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("Failed to create dir {:?}", storage_dir))?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    lock_file(&file)
+        .context(UpdateFailure::Storage)
+        .with_context(|| format!("Failed to lock {:?}", path))?;
+    Ok(file)
+}
+
+/// Waits for an exclusive lock on `file`. The lock is advisory: it only
+/// excludes other processes which also lock the file.
+#[cfg(unix)]
+fn lock_file(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    loop {
+        // SAFETY: flock only reads the file descriptor, which `file` keeps
+        // open for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Only Android runs several processes against the same storage, so other
+/// platforms don't lock (yet).
+#[cfg(not(unix))]
+fn lock_file(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    use super::{StorageLock, LOCK_FILE_NAME};
+
+    /// Whether another process could lock the storage dir right now. Locks
+    /// taken through a separately opened file exclude each other even within
+    /// one process, so this behaves like another process would.
+    fn can_lock_from_another_process(storage_dir: &Path) -> bool {
+        let file = File::open(storage_dir.join(LOCK_FILE_NAME)).unwrap();
+        unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+    }
+
+    #[test]
+    fn excludes_other_processes_until_dropped() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let lock = StorageLock::acquire(tmp_dir.path()).unwrap();
+        assert!(!can_lock_from_another_process(tmp_dir.path()));
+        drop(lock);
+        assert!(can_lock_from_another_process(tmp_dir.path()));
+    }
+
+    #[test]
+    fn waiting_for_another_process_does_not_block_other_threads() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let other_dir = TempDir::new("example").unwrap();
+        let other_process = File::create(tmp_dir.path().join(LOCK_FILE_NAME)).unwrap();
+        unsafe { libc::flock(other_process.as_raw_fd(), libc::LOCK_EX) };
+
+        let waiters = (0..2)
+            .map(|_| {
+                let dir = tmp_dir.path().to_owned();
+                std::thread::spawn(move || drop(StorageLock::acquire(&dir).unwrap()))
+            })
+            .collect::<Vec<_>>();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        // Other storage dirs can be locked and unlocked meanwhile.
+        drop(StorageLock::acquire(other_dir.path()).unwrap());
+        assert!(waiters.iter().all(|waiter| !waiter.is_finished()));
+
+        // Both waiters get the lock once the other process lets go.
+        drop(other_process);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert!(can_lock_from_another_process(tmp_dir.path()));
+    }
+
+    #[test]
+    fn is_shared_within_a_process() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let outer = StorageLock::acquire(tmp_dir.path()).unwrap();
+        // Doesn't wait for `outer`, even from another thread.
+        let dir = tmp_dir.path().to_owned();
+        std::thread::spawn(move || drop(StorageLock::acquire(&dir).unwrap()))
+            .join()
+            .unwrap();
+        let inner = StorageLock::acquire(tmp_dir.path()).unwrap();
+        drop(outer);
+        assert!(!can_lock_from_another_process(tmp_dir.path()));
+        drop(inner);
+        assert!(can_lock_from_another_process(tmp_dir.path()));
+    }
+}
@@ -0,0 +1,514 @@
+// This file's job is to make changes to the files in the storage directory
+// (state.json, patches_state.json and the patch artifacts in patches/) all or
+// nothing, so that e.g. a patch artifact is never in place without the
+// metadata describing it, or the other way around.
+//
+// Changes made within a transaction are staged in memory. Committing writes
+// them to a journal first and then applies them. If we crash part way through
+// applying them, the journal is still there the next time the store is opened,
+// and applying it again finishes the job.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::{disk_io, StorageLock};
+use crate::UpdateFailure;
+
+/// Where a commit in progress is recorded.
+const JOURNAL_FILE_NAME: &str = "journal.json";
+
+/// Changes to the storage directory, committed together. Paths are relative
+/// to the storage directory where possible, as it can move between runs.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct Changes {
+    /// Files to move into place, as (from, to) pairs. Done first.
+    moves: Vec<(PathBuf, PathBuf)>,
+    /// The new JSON contents of files, each written with a backup of the
+    /// previous generation. Done second.
+    writes: BTreeMap<PathBuf, serde_json::Value>,
+    /// Files and directories to remove. Done last, so that nothing refers to
+    /// them by then.
+    removals: Vec<PathBuf>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.moves.is_empty() && self.writes.is_empty() && self.removals.is_empty()
+    }
+
+    fn move_file(&mut self, from: PathBuf, to: PathBuf) {
+        // Don't remove what we're moving into place.
+        self.removals.retain(|removal| !to.starts_with(removal));
+        self.moves.push((from, to));
+    }
+
+    fn remove(&mut self, path: PathBuf) {
+        self.moves.retain(|(_, to)| !to.starts_with(&path));
+        self.writes.retain(|write, _| !write.starts_with(&path));
+        self.removals.push(path);
+    }
+
+    /// The first file to move which is gone without having been moved, e.g.
+    /// a download the OS purged from the cache before we could replay the
+    /// journal. The changes can never be made in full once that happens.
+    fn lost_file(&self, dir: &Path) -> Option<PathBuf> {
+        self.moves
+            .iter()
+            .map(|(from, to)| (dir.join(from), dir.join(to)))
+            .find(|(from, to)| !from.exists() && !to.exists())
+            .map(|(from, _)| from)
+    }
+
+    /// Makes the changes to the files in `dir`. Safe to repeat if
+    /// interrupted.
+    fn apply(&self, dir: &Path) -> Result<()> {
+        for (from, to) in &self.moves {
+            let (from, to) = (dir.join(from), dir.join(to));
+            if !from.exists() && to.exists() {
+                // Already moved before we were interrupted.
+                continue;
+            }
+            if let Some(parent) = to.parent() {
+                std::fs::create_dir_all(parent)
+                    .context(UpdateFailure::Storage)
+                    .with_context(|| format!("create_dir_all failed for {}", parent.display()))?;
+            }
+            std::fs::rename(&from, &to)
+                .context(UpdateFailure::Storage)
+                .with_context(|| format!("Failed to move {:?} to {:?}", from, to))?;
+        }
+        for (path, value) in &self.writes {
+            disk_io::write_keeping_backup(value, &dir.join(path))?;
+        }
+        for path in &self.removals {
+            let path = dir.join(path);
+            let result = if path.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            };
+            match result {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    // Not fatal: by now nothing refers to it, it just takes
+                    // up space.
+                    shorebird_error!("Failed to remove {}: {}", path.display(), e);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Commits changes to the files in a storage directory together. Shared (via
+/// `Rc`) by everything which writes to the directory, so that a transaction
+/// can span them.
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+    /// The changes staged by the transaction in progress, if any.
+    staged: RefCell<Changes>,
+    /// How many (nested) transactions are in progress.
+    depth: Cell<usize>,
+    /// Whether the journal holds a commit we haven't finished, which must be
+    /// finished before we commit anything else. Otherwise replaying it later
+    /// would undo what we committed since.
+    journal_pending: Cell<bool>,
+}
+
+impl Store {
+    /// Opens the store for `dir`, first finishing any commit which was
+    /// interrupted (e.g. by a crash) the last time.
+    pub fn open(dir: &Path) -> Rc<Self> {
+        let store = Self {
+            dir: dir.to_owned(),
+            staged: RefCell::new(Changes::default()),
+            depth: Cell::new(0),
+            journal_pending: Cell::new(false),
+        };
+        if store.journal_path().exists() {
+            if let Err(e) = store.replay_journal() {
+                // The journal is left in place, and commits try again.
+                shorebird_error!("Failed to finish interrupted commit: {:?}", e);
+                store.journal_pending.set(true);
+            }
+        }
+        Rc::new(store)
+    }
+
+    /// The storage directory this store writes to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.dir.join(JOURNAL_FILE_NAME)
+    }
+
+    fn replay_journal(&self) -> Result<()> {
+        // Another process may be in the middle of committing, in which case
+        // we wait for it to finish (and remove the journal).
+        let _lock = StorageLock::acquire(&self.dir)?;
+        let journal_path = self.journal_path();
+        if !journal_path.exists() {
+            return Ok(());
+        }
+        shorebird_info!("Finishing interrupted commit.");
+        let changes: Changes = disk_io::read(&journal_path)?;
+        // Moves happen before anything else, so none of the commit has taken
+        // effect, and we can drop it (e.g. a patch install) as a whole.
+        if let Some(lost_file) = changes.lost_file(&self.dir) {
+            shorebird_error!(
+                "Abandoning interrupted commit, {} is gone.",
+                lost_file.display()
+            );
+            return self.remove_journal();
+        }
+        changes.apply(&self.dir)?;
+        self.remove_journal()
+    }
+
+    fn remove_journal(&self) -> Result<()> {
+        let journal_path = self.journal_path();
+        std::fs::remove_file(&journal_path)
+            .context(UpdateFailure::Storage)
+            .with_context(|| format!("Failed to remove {:?}", journal_path))
+    }
+
+    /// Runs `f`, committing the changes it makes through this store together
+    /// once it returns Ok. If it returns an error, they are discarded (though
+    /// any changes `f` made in memory are not undone). Transactions may be
+    /// nested, in which case the outermost one commits. An inner transaction
+    /// which returns an error discards its own changes, even if the outer one
+    /// carries on and commits.
+    ///
+    /// Reads of the file system within `f` don't see its changes, which only
+    /// happen when it commits.
+    pub fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce() -> Result<R>,
+    {
+        let before = self.staged.borrow().clone();
+        self.depth.set(self.depth.get() + 1);
+        let result = f();
+        self.depth.set(self.depth.get() - 1);
+        if result.is_err() {
+            *self.staged.borrow_mut() = before;
+        }
+        if self.depth.get() > 0 {
+            return result;
+        }
+        let changes = self.staged.take();
+        let value = result?;
+        self.commit(changes)?;
+        Ok(value)
+    }
+
+    /// Changes the files in the store with `change`, staging it if in a
+    /// transaction and committing it straight away otherwise.
+    fn stage(&self, change: impl FnOnce(&mut Changes)) -> Result<()> {
+        change(&mut self.staged.borrow_mut());
+        if self.depth.get() == 0 {
+            let changes = self.staged.take();
+            self.commit(changes)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self, changes: Changes) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        if self.journal_pending.get() {
+            self.replay_journal()
+                .context("Failed to finish interrupted commit")?;
+            self.journal_pending.set(false);
+        }
+        if let Some(lost_file) = changes.lost_file(&self.dir) {
+            return Err(anyhow::Error::new(UpdateFailure::Storage))
+                .with_context(|| format!("{} does not exist", lost_file.display()));
+        }
+        // A single write is already atomic.
+        if changes.moves.is_empty() && changes.removals.is_empty() && changes.writes.len() == 1 {
+            return changes.apply(&self.dir);
+        }
+        disk_io::write(&changes, &self.journal_path())?;
+        self.journal_pending.set(true);
+        changes.apply(&self.dir)?;
+        self.remove_journal()?;
+        self.journal_pending.set(false);
+        Ok(())
+    }
+
+    /// Where `path` is recorded in the journal.
+    fn relative_path(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.dir).unwrap_or(path).to_owned()
+    }
+
+    /// Writes `value` as JSON to `path`, keeping a backup of the previous
+    /// contents.
+    pub fn write(&self, path: &Path, value: serde_json::Value) -> Result<()> {
+        let path = self.relative_path(path);
+        self.stage(|changes| {
+            changes.writes.insert(path, value);
+        })
+    }
+
+    /// Moves the file at `from` to `to`, creating `to`'s directory if needed.
+    pub fn move_file(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (self.relative_path(from), self.relative_path(to));
+        self.stage(|changes| changes.move_file(from, to))
+    }
+
+    /// Removes the file or directory (and everything in it) at `path`, if it
+    /// exists.
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        let path = self.relative_path(path);
+        self.stage(|changes| changes.remove(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{bail, Result};
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use super::{Changes, Store, JOURNAL_FILE_NAME};
+    use crate::cache::disk_io;
+
+    #[test]
+    fn commits_changes_outside_a_transaction_immediately() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let store = Store::open(tmp_dir.path());
+        let path = tmp_dir.path().join("state.json");
+        store.write(&path, json!({"a": 1}))?;
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&path)?,
+            json!({"a": 1})
+        );
+        store.remove(&path)?;
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn commits_transaction_together() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let store = Store::open(tmp_dir.path());
+        let download = tmp_dir.path().join("download");
+        std::fs::write(&download, "patch")?;
+        let artifact = tmp_dir.path().join("patches/1/dlc.vmcode");
+        let old_artifact = tmp_dir.path().join("patches/0");
+        std::fs::create_dir_all(&old_artifact)?;
+        let state = tmp_dir.path().join("state.json");
+
+        store.transaction(|| {
+            store.move_file(&download, &artifact)?;
+            store.write(&state, json!({"next": 1}))?;
+            store.transaction(|| store.remove(&old_artifact))?;
+            // Nothing happens until the outermost transaction commits.
+            assert!(download.exists());
+            assert!(!state.exists());
+            assert!(old_artifact.exists());
+            Ok(())
+        })?;
+
+        assert_eq!(std::fs::read_to_string(&artifact)?, "patch");
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&state)?,
+            json!({"next": 1})
+        );
+        assert!(!old_artifact.exists());
+        assert!(!tmp_dir.path().join(JOURNAL_FILE_NAME).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn discards_failed_transaction() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let store = Store::open(tmp_dir.path());
+        let state = tmp_dir.path().join("state.json");
+        let result: Result<()> = store.transaction(|| {
+            store.write(&state, json!({"next": 1}))?;
+            bail!("failed");
+        });
+        assert!(result.is_err());
+        assert!(!state.exists());
+
+        // The next transaction starts afresh.
+        store.transaction(|| Ok(()))?;
+        assert!(!state.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn discards_failed_inner_transaction() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let store = Store::open(tmp_dir.path());
+        let state = tmp_dir.path().join("state.json");
+        let patches_state = tmp_dir.path().join("patches_state.json");
+
+        store.transaction(|| {
+            store.write(&state, json!({"next": 1}))?;
+            let inner: Result<()> = store.transaction(|| {
+                store.write(&patches_state, json!({"next": 1}))?;
+                store.write(&state, json!({"next": 2}))?;
+                bail!("failed");
+            });
+            // Carry on regardless.
+            assert!(inner.is_err());
+            Ok(())
+        })?;
+
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&state)?,
+            json!({"next": 1})
+        );
+        assert!(!patches_state.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn finishes_interrupted_commit_when_opened() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let download = tmp_dir.path().join("download");
+        std::fs::write(&download, "patch")?;
+        let state = tmp_dir.path().join("state.json");
+        let artifact = tmp_dir.path().join("patches/1/dlc.vmcode");
+
+        // Pretend we crashed just after writing the journal.
+        let mut changes = Changes::default();
+        changes.move_file(download.clone(), "patches/1/dlc.vmcode".into());
+        changes
+            .writes
+            .insert("state.json".into(), json!({"next": 1}));
+        disk_io::write(&changes, &tmp_dir.path().join(JOURNAL_FILE_NAME))?;
+        // ...and after moving the artifact.
+        std::fs::create_dir_all(artifact.parent().unwrap())?;
+        std::fs::rename(&download, &artifact)?;
+
+        let _store = Store::open(tmp_dir.path());
+        assert_eq!(std::fs::read_to_string(&artifact)?, "patch");
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&state)?,
+            json!({"next": 1})
+        );
+        assert!(!tmp_dir.path().join(JOURNAL_FILE_NAME).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn finishes_interrupted_commit_before_committing_more() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let blocker = tmp_dir.path().join("patches");
+        let state = tmp_dir.path().join("state.json");
+
+        // An interrupted commit which can't be finished yet, as a file is in
+        // the way of the directory it writes to.
+        let mut changes = Changes::default();
+        changes
+            .writes
+            .insert("patches/1/state.json".into(), json!({"next": 1}));
+        changes
+            .writes
+            .insert("state.json".into(), json!({"next": 1}));
+        disk_io::write(&changes, &tmp_dir.path().join(JOURNAL_FILE_NAME))?;
+        std::fs::write(&blocker, "")?;
+
+        let store = Store::open(tmp_dir.path());
+        assert!(tmp_dir.path().join(JOURNAL_FILE_NAME).exists());
+        // Committing anything else fails until the journal can be replayed.
+        assert!(store.write(&state, json!({"next": 2})).is_err());
+        assert!(!state.exists());
+
+        std::fs::remove_file(&blocker)?;
+        store.write(&state, json!({"next": 2}))?;
+        assert!(tmp_dir.path().join("patches/1/state.json").exists());
+        assert!(!tmp_dir.path().join(JOURNAL_FILE_NAME).exists());
+
+        // The newer write survives reopening the store.
+        let _store = Store::open(tmp_dir.path());
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&state)?,
+            json!({"next": 2})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn abandons_interrupted_commit_whose_file_is_gone() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let state = tmp_dir.path().join("state.json");
+        let patches_state = tmp_dir.path().join("patches_state.json");
+        disk_io::write(&json!({"next": 0}), &patches_state)?;
+
+        // An interrupted install whose download has since been purged.
+        let mut changes = Changes::default();
+        changes.move_file("download".into(), "patches/1/dlc.vmcode".into());
+        changes
+            .writes
+            .insert("patches_state.json".into(), json!({"next": 1}));
+        disk_io::write(&changes, &tmp_dir.path().join(JOURNAL_FILE_NAME))?;
+
+        let store = Store::open(tmp_dir.path());
+        // None of the install happens.
+        assert!(!tmp_dir.path().join(JOURNAL_FILE_NAME).exists());
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&patches_state)?,
+            json!({"next": 0})
+        );
+
+        // Later commits go ahead, including ones which span files.
+        store.write(&state, json!({"next": 2}))?;
+        store.transaction(|| {
+            store.write(&state, json!({"next": 3}))?;
+            store.write(&patches_state, json!({"next": 3}))
+        })?;
+        let _store = Store::open(tmp_dir.path());
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&state)?,
+            json!({"next": 3})
+        );
+        assert_eq!(
+            disk_io::read::<serde_json::Value, _>(&patches_state)?,
+            json!({"next": 3})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_commit_move_of_missing_file() -> Result<()> {
+        let tmp_dir = TempDir::new("store")?;
+        let store = Store::open(tmp_dir.path());
+        let state = tmp_dir.path().join("state.json");
+        let result = store.transaction(|| {
+            store.move_file(
+                &tmp_dir.path().join("download"),
+                &tmp_dir.path().join("patches/1/dlc.vmcode"),
+            )?;
+            store.write(&state, json!({"next": 1}))
+        });
+        assert!(result.is_err());
+        // No journal is left behind to hold up later commits.
+        assert!(!tmp_dir.path().join(JOURNAL_FILE_NAME).exists());
+        store.write(&state, json!({"next": 2}))?;
+        Ok(())
+    }
+
+    #[test]
+    fn removal_cancels_earlier_move_into_removed_dir() -> Result<()> {
+        let mut changes = Changes::default();
+        changes.move_file("download".into(), "patches/1/dlc.vmcode".into());
+        changes.remove("patches/1".into());
+        assert!(changes.moves.is_empty());
+        // ...but a later move into it cancels the removal.
+        changes.move_file("download".into(), "patches/1/dlc.vmcode".into());
+        assert!(changes.removals.is_empty());
+        Ok(())
+    }
+}
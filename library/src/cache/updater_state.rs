@@ -6,18 +6,37 @@
 // PatchInfo can probably go away.
 
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use crate::events::PatchEvent;
+use crate::network::{CacheValidators, PatchCheckRequest, PatchCheckResponse};
 
-use super::patch_manager::{ManagePatches, PatchManager};
-use super::{disk_io, PatchInfo};
+use super::patch_manager::{ManagePatches, PatchManager, PATCHES_STATE_FILE_NAME};
+use super::schema::Schema;
+use super::store::Store;
+use super::PatchInfo;
 
 /// Where the updater state is stored on disk.
 const STATE_FILE_NAME: &str = "state.json";
 
+/// Migrations for STATE_FILE_NAME. See [Schema].
+const STATE_SCHEMA: Schema = Schema {
+    migrations: &[
+        // Version 1 only adds the version number. The fields added before then
+        // (preferred_base_url, cached_patch_check and last_patch_check_time)
+        // are optional.
+        |_| Ok(()),
+        // Version 2 adds recovered_files.
+        |state| {
+            state.insert("recovered_files".to_owned(), Vec::<String>::new().into());
+            Ok(())
+        },
+    ],
+};
+
 /// Records the updater's "state of the world" - which patches we know to be
 /// good or bad, which patches we have downloaded, which patch we're currently
 /// booted from, events that need to be reported to the server, etc.
@@ -34,6 +53,9 @@ pub struct UpdaterState {
     /// between runs of the app.
     cache_dir: PathBuf,
 
+    /// Commits changes to this and the patch manager's files together.
+    store: Rc<Store>,
+
     patch_manager: Box<dyn ManagePatches>,
 
     serialized_state: SerializedState,
@@ -52,6 +74,35 @@ struct SerializedState {
     /// Events that have not yet been sent to the server.
     /// Format could change between releases, so this is per-release state.
     queued_events: Vec<PatchEvent>,
+    /// The base URL (primary or mirror) which last served us successfully.
+    /// Tried first on the next update so we don't keep waiting on a mirror we
+    /// already know to be down.
+    #[serde(default)]
+    preferred_base_url: Option<String>,
+    /// The last patch check response we received, if the server sent it
+    /// with an ETag or Last-Modified header.
+    #[serde(default)]
+    cached_patch_check: Option<CachedPatchCheck>,
+    /// When (in seconds since the Unix epoch) we last heard back from a patch
+    /// check, used to space out automatic checks.
+    #[serde(default)]
+    last_patch_check_time: Option<u64>,
+    /// The names of state files we couldn't read and recovered from their
+    /// backups, which have not yet been queued as events (which needs config
+    /// we don't have when loading).
+    recovered_files: Vec<String>,
+}
+
+/// A patch check response kept so that the server can answer the same check
+/// next time with 304 Not Modified rather than the full response.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CachedPatchCheck {
+    /// The base URL the response came from.
+    pub base_url: String,
+    /// The request the response answered.
+    pub request: PatchCheckRequest,
+    pub response: PatchCheckResponse,
+    pub validators: CacheValidators,
 }
 
 fn is_file_not_found(error: &anyhow::Error) -> bool {
@@ -67,25 +118,57 @@ fn is_file_not_found(error: &anyhow::Error) -> bool {
 impl UpdaterState {
     /// Creates a new `UpdaterState`.
     fn new(cache_dir: PathBuf, release_version: String, patch_public_key: Option<&str>) -> Self {
+        let store = Store::open(&cache_dir);
         Self {
-            cache_dir: cache_dir.clone(),
-            patch_manager: Box::new(PatchManager::new(cache_dir.clone(), patch_public_key)),
+            cache_dir,
+            store: store.clone(),
+            patch_manager: Box::new(PatchManager::new(store, patch_public_key)),
             serialized_state: SerializedState {
                 release_version,
                 queued_events: Vec::new(),
+                preferred_base_url: None,
+                cached_patch_check: None,
+                last_patch_check_time: None,
+                recovered_files: Vec::new(),
             },
         }
     }
 
     /// Loads UpdaterState from disk
     fn load(cache_dir: &Path, patch_public_key: Option<&str>) -> anyhow::Result<Self> {
+        // Opening the store finishes any commit which was interrupted, so it
+        // must happen before we read anything.
+        let store = Store::open(cache_dir);
         let path = cache_dir.join(STATE_FILE_NAME);
-        let serialized_state = disk_io::read(&path)?;
-        Ok(UpdaterState {
+        let (mut serialized_state, recovered): (SerializedState, _) =
+            STATE_SCHEMA.read_or_recover(&path)?;
+        let patch_manager = PatchManager::new(store.clone(), patch_public_key);
+        let newly_recovered = [
+            (recovered, STATE_FILE_NAME),
+            (
+                patch_manager.recovered_from_backup(),
+                PATCHES_STATE_FILE_NAME,
+            ),
+        ]
+        .into_iter()
+        .filter(|(recovered, _)| *recovered)
+        .map(|(_, file_name)| file_name.to_owned())
+        .collect::<Vec<_>>();
+        let should_save = !newly_recovered.is_empty();
+        serialized_state.recovered_files.extend(newly_recovered);
+        let state = UpdaterState {
             cache_dir: cache_dir.to_path_buf(),
-            patch_manager: Box::new(PatchManager::new(cache_dir.to_path_buf(), patch_public_key)),
+            store,
+            patch_manager: Box::new(patch_manager),
             serialized_state,
-        })
+        };
+        if should_save {
+            // Remember to report the recovery even if nothing else saves.
+            if let Err(e) = state.save() {
+                shorebird_warn!("Error saving state {:?}, ignoring.", e);
+            }
+        }
+        Ok(state)
     }
 
     /// Initializes a new UpdaterState and saves it to disk.
@@ -99,11 +182,13 @@ impl UpdaterState {
             release_version.to_owned(),
             patch_public_key,
         );
-        if let Err(e) = state.save() {
+        // Ensure we clear any patch data if we're creating a new state.
+        if let Err(e) = state.transaction(|state| {
+            state.save()?;
+            state.patch_manager.reset()
+        }) {
             shorebird_warn!("Error saving state {:?}, ignoring.", e);
         }
-        // Ensure we clear any patch data if we're creating a new state.
-        let _ = state.patch_manager.reset();
         state
     }
 
@@ -141,7 +226,19 @@ impl UpdaterState {
     /// Saves the updater state to disk.
     pub fn save(&self) -> anyhow::Result<()> {
         let path = Path::new(&self.cache_dir).join(STATE_FILE_NAME);
-        disk_io::write(&self.serialized_state, &path)
+        self.store
+            .write(&path, STATE_SCHEMA.to_value(&self.serialized_state)?)
+    }
+
+    /// Runs `f`, committing the changes it saves to disk (both to this and to
+    /// patches) together once it returns Ok, and discarding them if it
+    /// returns an error. See [Store::transaction].
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Self) -> Result<R>,
+    {
+        let store = self.store.clone();
+        store.transaction(|| f(self))
     }
 }
 
@@ -211,6 +308,17 @@ impl UpdaterState {
         self.patch_manager.remove_patch(patch_number)
     }
 
+    /// Stops booting a patch newer than the last successfully booted patch. See
+    /// [ManagePatches::roll_back_to_last_booted_patch].
+    pub fn roll_back_to_last_booted_patch(&mut self, mark_bad: bool) -> Result<Option<usize>> {
+        self.patch_manager.roll_back_to_last_booted_patch(mark_bad)
+    }
+
+    /// Stops booting any patch. See [ManagePatches::roll_back_to_release].
+    pub fn roll_back_to_release(&mut self, mark_bad: bool) -> Result<Vec<usize>> {
+        self.patch_manager.roll_back_to_release(mark_bad)
+    }
+
     /// Returns true if we have previously failed to boot from patch `patch_number`.
     pub fn is_known_bad_patch(&self, patch_number: usize) -> bool {
         self.patch_manager.is_known_bad_patch(patch_number)
@@ -240,6 +348,63 @@ impl UpdaterState {
         self.serialized_state.queued_events.clear();
         self.save()
     }
+
+    /// Returns the names of the state files recovered from backups since the
+    /// last call, so that the caller can queue events for them.
+    pub fn take_recovered_files(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.serialized_state.recovered_files)
+    }
+}
+
+/// Mirror management
+impl UpdaterState {
+    /// The base URL which last served us successfully, if any.
+    pub fn preferred_base_url(&self) -> Option<&str> {
+        self.serialized_state.preferred_base_url.as_deref()
+    }
+
+    /// Records `base_url` as the one to try first next time.
+    pub fn set_preferred_base_url(&mut self, base_url: &str) -> Result<()> {
+        if self.preferred_base_url() == Some(base_url) {
+            return Ok(());
+        }
+        self.serialized_state.preferred_base_url = Some(base_url.to_owned());
+        self.save()
+    }
+}
+
+/// Patch check caching
+impl UpdaterState {
+    /// The cached response to `request` from `base_url`, if we have one.
+    pub fn cached_patch_check(
+        &self,
+        base_url: &str,
+        request: &PatchCheckRequest,
+    ) -> Option<&CachedPatchCheck> {
+        self.serialized_state
+            .cached_patch_check
+            .as_ref()
+            .filter(|cached| cached.base_url == base_url && &cached.request == request)
+    }
+
+    /// Replaces (or with None, clears) the cached patch check response.
+    pub fn set_cached_patch_check(&mut self, cached: Option<CachedPatchCheck>) -> Result<()> {
+        self.serialized_state.cached_patch_check = cached;
+        self.save()
+    }
+
+    /// When (in seconds since the Unix epoch) we last completed a patch check,
+    /// if ever.
+    pub fn last_patch_check_time(&self) -> Option<u64> {
+        self.serialized_state.last_patch_check_time
+    }
+
+    /// Records that we completed a patch check at `timestamp` (in seconds
+    /// since the Unix epoch).
+    pub fn set_last_patch_check_time(&mut self, timestamp: u64) -> Result<()> {
+        self.serialized_state.last_patch_check_time = Some(timestamp);
+        self.save()
+    }
 }
 
 #[cfg(test)]
@@ -258,10 +423,15 @@ mod tests {
     {
         UpdaterState {
             cache_dir: tmp_dir.path().to_path_buf(),
+            store: Store::open(tmp_dir.path()),
             patch_manager: Box::new(patch_manager),
             serialized_state: SerializedState {
                 release_version: "1.0.0+1".to_string(),
                 queued_events: Vec::new(),
+                preferred_base_url: None,
+                cached_patch_check: None,
+                last_patch_check_time: None,
+                recovered_files: Vec::new(),
             },
         }
     }
@@ -304,15 +474,50 @@ mod tests {
         assert!(super::is_file_not_found(&result.unwrap_err()));
     }
 
+    #[test]
+    fn transaction_commits_state_and_patches_together() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        let patch = fake_patch(&tmp_dir, 1);
+
+        let result: Result<()> = state.transaction(|state| {
+            state.install_patch(&patch, "hash", None)?;
+            state.set_preferred_base_url("https://example.com")?;
+            anyhow::bail!("failed");
+        });
+        assert!(result.is_err());
+        // Neither change reached the disk, and the download is still there.
+        let mut loaded = UpdaterState::load(tmp_dir.path(), None).unwrap();
+        assert!(loaded.next_boot_patch().is_none());
+        assert!(loaded.preferred_base_url().is_none());
+        assert!(patch.path.exists());
+
+        // Changes in memory aren't undone, so carry on from what's on disk.
+        loaded
+            .transaction(|state| {
+                state.install_patch(&patch, "hash", None)?;
+                state.set_preferred_base_url("https://example.com")
+            })
+            .unwrap();
+        let mut loaded = UpdaterState::load(tmp_dir.path(), None).unwrap();
+        assert_eq!(loaded.next_boot_patch().unwrap().number, 1);
+        assert_eq!(loaded.preferred_base_url(), Some("https://example.com"));
+    }
+
     #[test]
     fn does_not_save_cache_dir() {
         let original_tmp_dir = TempDir::new("example").unwrap();
         let original_state = UpdaterState {
             cache_dir: original_tmp_dir.path().to_path_buf(),
+            store: Store::open(original_tmp_dir.path()),
             patch_manager: Box::new(PatchManager::manager_for_test(&original_tmp_dir)),
             serialized_state: SerializedState {
                 release_version: "1.0.0+1".to_string(),
                 queued_events: Vec::new(),
+                preferred_base_url: None,
+                cached_patch_check: None,
+                last_patch_check_time: None,
+                recovered_files: Vec::new(),
             },
         };
         original_state.save().unwrap();
@@ -468,4 +673,75 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn loads_unversioned_state_without_losing_patches() -> Result<()> {
+        let tmp_dir = TempDir::new("example")?;
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        state.install_patch(&fake_patch(&tmp_dir, 1), "hash", None)?;
+
+        // The shape written before any optional fields were added.
+        let state_file = tmp_dir.path().join(STATE_FILE_NAME);
+        std::fs::write(
+            &state_file,
+            r#"{"release_version": "1.0.0+1", "queued_events": []}"#,
+        )?;
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        assert_eq!(state.next_boot_patch().unwrap().number, 1);
+        let on_disk: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&state_file)?)?;
+        assert_eq!(on_disk["schema_version"], STATE_SCHEMA.version());
+
+        // The shape written just before versioning.
+        std::fs::write(
+            &state_file,
+            r#"{
+                "release_version": "1.0.0+1",
+                "queued_events": [],
+                "preferred_base_url": "https://mirror.example.com",
+                "cached_patch_check": null,
+                "last_patch_check_time": 1700000000
+            }"#,
+        )?;
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        assert_eq!(state.next_boot_patch().unwrap().number, 1);
+        assert_eq!(
+            state.preferred_base_url(),
+            Some("https://mirror.example.com")
+        );
+        assert_eq!(state.last_patch_check_time(), Some(1700000000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn load_or_new_on_error_resets_state_from_newer_updater() -> Result<()> {
+        let tmp_dir = TempDir::new("example")?;
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        state.install_patch(&fake_patch(&tmp_dir, 1), "hash", None)?;
+
+        std::fs::write(
+            tmp_dir.path().join(STATE_FILE_NAME),
+            r#"{"release_version": "1.0.0+1", "queued_events": [], "schema_version": 999}"#,
+        )?;
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        assert!(state.next_boot_patch().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn preferred_base_url_is_saved() -> Result<()> {
+        let tmp_dir = TempDir::new("example")?;
+        let mut state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        assert_eq!(state.preferred_base_url(), None);
+        state.set_preferred_base_url("https://mirror.example.com")?;
+
+        let state = UpdaterState::load_or_new_on_error(tmp_dir.path(), "1.0.0+1", None);
+        assert_eq!(
+            state.preferred_base_url(),
+            Some("https://mirror.example.com")
+        );
+        Ok(())
+    }
 }
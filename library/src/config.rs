@@ -1,5 +1,9 @@
 // This file handles the global config for the updater library.
-use crate::network::NetworkHooks;
+use crate::network::{
+    Connectivity, DownloadPolicy, HttpHeaders, NetworkTimeouts, ReqwestTransport, Transport,
+};
+use crate::progress::{ProgressObserver, ProgressReporter};
+use crate::retry::RetryPolicy;
 
 use crate::updater::AppConfig;
 use crate::yaml::YamlConfig;
@@ -8,7 +12,8 @@ use std::path::PathBuf;
 
 use anyhow::{bail, Result};
 use once_cell::sync::OnceCell;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // cbindgen looks for const, ignore these so it doesn't warn about them.
 
@@ -23,6 +28,9 @@ const DEFAULT_BASE_URL: &str = "https://api.shorebird.dev";
 /// cbindgen:ignore
 const DEFAULT_CHANNEL: &str = "stable";
 
+/// cbindgen:ignore
+const DEFAULT_MAX_DOWNLOAD_BYTES: u64 = 100 * 1024 * 1024;
+
 fn global_config() -> &'static Mutex<Option<UpdateConfig>> {
     static INSTANCE: OnceCell<Mutex<Option<UpdateConfig>>> = OnceCell::new();
     INSTANCE.get_or_init(|| Mutex::new(None))
@@ -82,9 +90,44 @@ pub struct UpdateConfig {
     pub release_version: String,
     pub libapp_path: PathBuf,
     pub base_url: String,
-    pub network_hooks: NetworkHooks,
+    /// Fallbacks for `base_url`, in the order they should be tried.
+    pub mirror_urls: Vec<String>,
+    pub transport: Arc<dyn Transport>,
     pub file_provider: Box<dyn ExternalFileProvider>,
     pub patch_public_key: Option<String>,
+    pub max_download_bytes: u64,
+    /// Minimum time between automatic patch checks.
+    pub check_interval: Duration,
+    /// How often the background update scheduler checks, if it may run.
+    pub scheduled_check_interval: Option<Duration>,
+    pub download_policy: DownloadPolicy,
+    /// The connectivity most recently reported by the host.
+    pub connectivity: Connectivity,
+    pub retry_policy: RetryPolicy,
+    pub timeouts: NetworkTimeouts,
+    pub headers: HttpHeaders,
+    pub progress: ProgressReporter,
+}
+
+impl UpdateConfig {
+    /// `base_url` followed by `mirror_urls`, without duplicates, with
+    /// `preferred` (if it is one of them) moved to the front.
+    pub fn base_urls(&self, preferred: Option<&str>) -> Vec<String> {
+        let mut base_urls: Vec<String> = Vec::new();
+        for url in std::iter::once(&self.base_url).chain(&self.mirror_urls) {
+            if !base_urls.contains(url) {
+                base_urls.push(url.clone());
+            }
+        }
+        if let Some(index) = base_urls
+            .iter()
+            .position(|url| Some(url.as_str()) == preferred)
+        {
+            let url = base_urls.remove(index);
+            base_urls.insert(0, url);
+        }
+        base_urls
+    }
 }
 
 /// Update the base URL in the existing config
@@ -100,13 +143,69 @@ pub fn update_base_url(new_base_url: String) -> Result<()> {
     })
 }
 
+/// Set (or with a `value` of None, remove) a header sent with every request.
+pub fn set_http_header(name: &str, value: Option<&str>) -> Result<()> {
+    with_config_mut(|config: &mut Option<UpdateConfig>| {
+        if let Some(ref mut update_config) = config {
+            match value {
+                Some(value) => update_config.headers.set(name, value)?,
+                None => update_config.headers.remove(name),
+            }
+            shorebird_debug!("Headers updated to: {:?}", update_config.headers);
+            Ok(())
+        } else {
+            bail!("Updater not initialized, cannot set HTTP header");
+        }
+    })
+}
+
+/// Record the kind of network connection the device currently has.
+pub fn set_connectivity(connectivity: Connectivity) -> Result<()> {
+    with_config_mut(|config: &mut Option<UpdateConfig>| {
+        if let Some(ref mut update_config) = config {
+            update_config.connectivity = connectivity;
+            shorebird_debug!("Connectivity updated to: {:?}", connectivity);
+            Ok(())
+        } else {
+            bail!("Updater not initialized, cannot set connectivity");
+        }
+    })
+}
+
+/// Set (or with None, remove) the observer told about the progress of updates.
+pub fn set_progress_observer(observer: Option<Arc<dyn ProgressObserver>>) -> Result<()> {
+    with_config_mut(|config: &mut Option<UpdateConfig>| {
+        if let Some(ref mut update_config) = config {
+            update_config.progress = ProgressReporter::new(observer);
+            shorebird_debug!("Progress observer updated to: {:?}", update_config.progress);
+            Ok(())
+        } else {
+            bail!("Updater not initialized, cannot set progress observer");
+        }
+    })
+}
+
+/// Replace the transport used for all network requests.
+pub fn set_transport(transport: Arc<dyn Transport>) -> Result<()> {
+    with_config_mut(|config: &mut Option<UpdateConfig>| {
+        if let Some(ref mut update_config) = config {
+            update_config.transport = transport;
+            shorebird_debug!("Transport updated to: {:?}", update_config.transport);
+            Ok(())
+        } else {
+            bail!("Updater not initialized, cannot set transport");
+        }
+    })
+}
+
 /// Returns Ok if the config was set successfully, Err if it was already set.
+/// Uses a [`ReqwestTransport`] if `transport` is None.
 pub fn set_config(
     app_config: AppConfig,
     file_provider: Box<dyn ExternalFileProvider>,
     libapp_path: PathBuf,
     yaml: &YamlConfig,
-    network_hooks: NetworkHooks,
+    transport: Option<Arc<dyn Transport>>,
 ) -> Result<()> {
     with_config_mut(|config: &mut Option<UpdateConfig>| {
         if config.is_some() {
@@ -120,6 +219,14 @@ pub fn set_config(
         code_cache_path.push("downloads");
         let download_dir = code_cache_path;
 
+        let timeouts = NetworkTimeouts::from_yaml(yaml.timeouts.as_ref());
+        let mut headers = HttpHeaders::default();
+        for (name, value) in yaml.headers.iter().flatten() {
+            // One bad header shouldn't stop the updater from working.
+            if let Err(e) = headers.set(name, value) {
+                shorebird_error!("Ignoring header from shorebird.yaml: {:?}", e);
+            }
+        }
         let new_config = UpdateConfig {
             storage_dir: std::path::PathBuf::from(app_config.app_storage_dir),
             download_dir,
@@ -137,9 +244,21 @@ pub fn set_config(
                 .as_deref()
                 .unwrap_or(DEFAULT_BASE_URL)
                 .to_owned(),
-            network_hooks,
+            mirror_urls: yaml.mirror_urls.clone().unwrap_or_default(),
+            transport: transport.unwrap_or_else(|| Arc::new(ReqwestTransport::new(timeouts))),
             file_provider,
             patch_public_key: yaml.patch_public_key.to_owned(),
+            max_download_bytes: yaml
+                .max_download_bytes
+                .unwrap_or(DEFAULT_MAX_DOWNLOAD_BYTES),
+            check_interval: Duration::from_secs(yaml.check_interval_seconds.unwrap_or(0)),
+            scheduled_check_interval: scheduled_check_interval(yaml.scheduled_check_minutes),
+            download_policy: yaml.download_policy.unwrap_or_default(),
+            connectivity: Connectivity::Unknown,
+            retry_policy: RetryPolicy::from_yaml(yaml.retry.as_ref()),
+            timeouts,
+            headers,
+            progress: ProgressReporter::default(),
         };
         shorebird_debug!("Updater configured with: {:?}", new_config);
         *config = Some(new_config);
@@ -148,6 +267,18 @@ pub fn set_config(
     })
 }
 
+/// The interval for `scheduled_check_minutes` from shorebird.yaml. Zero is
+/// treated as not set, as the scheduler would otherwise check continuously.
+fn scheduled_check_interval(minutes: Option<u64>) -> Option<Duration> {
+    match minutes {
+        Some(0) => {
+            shorebird_warn!("Ignoring scheduled_check_minutes of 0.");
+            None
+        }
+        minutes => minutes.map(|minutes| Duration::from_secs(minutes.saturating_mul(60))),
+    }
+}
+
 // Arch/Platform names need to be kept in sync with the shorebird cli.
 pub fn current_arch() -> &'static str {
     #[cfg(target_arch = "x86")]
@@ -180,7 +311,7 @@ mod tests {
     use std::path::PathBuf;
 
     use super::set_config;
-    use crate::{network::NetworkHooks, testing_reset_config, AppConfig, ExternalFileProvider};
+    use crate::{testing_reset_config, AppConfig, ExternalFileProvider};
     use anyhow::Result;
     use serial_test::serial;
 
@@ -207,7 +338,15 @@ mod tests {
             channel: Some("fake_channel".to_string()),
             auto_update: Some(true),
             base_url: Some("fake_base_url".to_string()),
+            mirror_urls: None,
             patch_public_key: None,
+            max_download_bytes: None,
+            check_interval_seconds: None,
+            scheduled_check_minutes: None,
+            download_policy: None,
+            retry: None,
+            timeouts: None,
+            headers: None,
         }
     }
 
@@ -231,9 +370,29 @@ mod tests {
                 channel: Some("fake_channel".to_string()),
                 auto_update: Some(true),
                 base_url: Some("fake_base_url".to_string()),
+                mirror_urls: Some(vec!["fake_mirror_url".to_string()]),
                 patch_public_key: Some("patch_public_key".to_string()),
+                max_download_bytes: Some(1024),
+                check_interval_seconds: Some(3600),
+                scheduled_check_minutes: Some(30),
+                download_policy: Some(crate::network::DownloadPolicy::UnmeteredOnly),
+                retry: Some(crate::yaml::RetryConfig {
+                    max_attempts: Some(5),
+                    ..Default::default()
+                }),
+                timeouts: Some(crate::yaml::TimeoutConfig {
+                    read_ms: Some(1000),
+                    ..Default::default()
+                }),
+                headers: Some(
+                    [
+                        ("X-Api-Key".to_string(), "secret".to_string()),
+                        ("Bad Header".to_string(), "ignored".to_string()),
+                    ]
+                    .into(),
+                ),
             },
-            NetworkHooks::default(),
+            None,
         )?;
 
         let config = super::with_config(|config| Ok(config.clone())).unwrap();
@@ -248,11 +407,52 @@ mod tests {
         assert_eq!(config.release_version, "1.0.0");
         assert_eq!(config.libapp_path.to_str(), Some("first_path"));
         assert_eq!(config.base_url, "fake_base_url");
+        assert_eq!(config.mirror_urls, vec!["fake_mirror_url".to_string()]);
         // We should also validate network hooks here
         assert_eq!(
             config.patch_public_key,
             Some("patch_public_key".to_string())
         );
+        assert_eq!(config.max_download_bytes, 1024);
+        assert_eq!(config.check_interval, std::time::Duration::from_secs(3600));
+        assert_eq!(
+            config.scheduled_check_interval,
+            Some(std::time::Duration::from_secs(30 * 60))
+        );
+        assert_eq!(
+            config.download_policy,
+            crate::network::DownloadPolicy::UnmeteredOnly
+        );
+        assert_eq!(config.retry_policy.max_attempts, 5);
+        assert_eq!(config.timeouts.read, std::time::Duration::from_secs(1));
+        assert_eq!(config.headers.get("x-api-key"), Some("secret"));
+        assert_eq!(config.headers.iter().count(), 1);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn set_http_header_sets_and_removes_headers() -> Result<()> {
+        testing_reset_config();
+        assert!(super::set_http_header("x-tenant", Some("a")).is_err());
+
+        set_config(
+            fake_app_config(),
+            Box::new(FakeExternalFileProvider {}),
+            "first_path".into(),
+            &fake_yaml(),
+            None,
+        )?;
+        super::set_http_header("X-Tenant", Some("a"))?;
+        super::set_http_header("x-tenant", Some("b"))?;
+        assert!(super::set_http_header("x-tenant", Some("bad\nvalue")).is_err());
+        let headers = super::with_config(|config| Ok(config.headers.clone()))?;
+        assert_eq!(headers.get("X-Tenant"), Some("b"));
+
+        super::set_http_header("X-TENANT", None)?;
+        let headers = super::with_config(|config| Ok(config.headers.clone()))?;
+        assert_eq!(headers.get("x-tenant"), None);
 
         Ok(())
     }
@@ -268,7 +468,7 @@ mod tests {
             Box::new(FakeExternalFileProvider {}),
             "first_path".into(),
             &fake_yaml(),
-            NetworkHooks::default(),
+            None,
         )
         .is_ok());
 
@@ -277,7 +477,7 @@ mod tests {
             Box::new(FakeExternalFileProvider {}),
             "second_path".into(),
             &fake_yaml(),
-            NetworkHooks::default(),
+            None,
         )
         .is_err());
 
@@ -286,4 +486,19 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn scheduled_check_interval_ignores_zero_and_saturates() {
+        use std::time::Duration;
+        assert_eq!(super::scheduled_check_interval(None), None);
+        assert_eq!(super::scheduled_check_interval(Some(0)), None);
+        assert_eq!(
+            super::scheduled_check_interval(Some(2)),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            super::scheduled_check_interval(Some(u64::MAX)),
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
 }
@@ -0,0 +1,364 @@
+// This file's job is to manage patch downloads on disk, including keeping
+// enough information about an interrupted download to resume it later.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::disk_io;
+use crate::network::{DownloadResponse, DownloadSink};
+use crate::progress::{ProgressReporter, UpdatePhase};
+use crate::updater::UpdateError;
+use crate::updater_lock::CancellationToken;
+
+/// How often (in bytes received) we flush the partial download to disk and
+/// update its record, so that progress survives the app being killed.
+const RECORD_CHECKPOINT_BYTES: u64 = 256 * 1024;
+
+/// Attached as context to errors caused by a downloaded patch not being what
+/// the server said it would be (e.g. failing to inflate or a hash mismatch).
+/// Another mirror may have an intact copy.
+#[derive(Debug)]
+pub struct CorruptDownload;
+
+impl std::fmt::Display for CorruptDownload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Downloaded patch is corrupt")
+    }
+}
+
+/// Settings for downloading a patch.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Downloads larger than this many bytes are aborted.
+    pub max_bytes: u64,
+    /// Told how the download is going.
+    pub progress: ProgressReporter,
+    /// Stops the download when cancelled.
+    pub cancellation: CancellationToken,
+}
+
+/// Sidecar record describing a partially downloaded patch. Written next to
+/// the partial download as `<download_path>.download.json` and removed once
+/// the download completes.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct PartialDownload {
+    /// The patch number this download is for.
+    pub patch_number: usize,
+    /// The URL the bytes were downloaded from.
+    pub url: String,
+    /// The ETag the server sent with the bytes we have, if any. Sent back as
+    /// If-Range so the server only honors the Range if the file is unchanged.
+    pub etag: Option<String>,
+    /// The number of bytes we know to have been written to disk.
+    pub bytes_received: u64,
+}
+
+impl PartialDownload {
+    /// The path of the sidecar record for a download to `download_path`.
+    pub fn record_path(download_path: &Path) -> PathBuf {
+        let mut file_name = download_path.file_name().unwrap_or_default().to_owned();
+        file_name.push(".download.json");
+        download_path.with_file_name(file_name)
+    }
+
+    /// Returns the record for a previous, interrupted download of
+    /// `patch_number` from `url` to `download_path` if one exists and the
+    /// partial file on disk still has at least the bytes it claims.
+    pub fn load_resumable(download_path: &Path, patch_number: usize, url: &str) -> Option<Self> {
+        let record: Self = disk_io::read(&Self::record_path(download_path)).ok()?;
+        if record.patch_number != patch_number || record.url != url {
+            shorebird_debug!("Ignoring partial download record for a different download");
+            return None;
+        }
+        let file_len = std::fs::metadata(download_path).ok()?.len();
+        if file_len < record.bytes_received {
+            shorebird_debug!("Partial download is shorter than its record, not resuming");
+            return None;
+        }
+        Some(record)
+    }
+
+    fn save(&self, download_path: &Path) -> anyhow::Result<()> {
+        disk_io::write(self, &Self::record_path(download_path))
+    }
+}
+
+/// A `DownloadSink` which writes to a file, resuming from a previous partial
+/// download if the server honors our Range request and starting over if it
+/// does not.
+pub struct DownloadFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    record: PartialDownload,
+    /// Bytes written since the record was last saved.
+    unrecorded_bytes: u64,
+    max_bytes: u64,
+    has_begun: bool,
+    exceeded_max_bytes: bool,
+    /// The size of the whole file, if the server told us.
+    total_bytes: Option<u64>,
+    progress: ProgressReporter,
+    cancellation: CancellationToken,
+}
+
+impl DownloadFile {
+    /// Opens `path` for writing, keeping the first `record.bytes_received`
+    /// bytes of any existing file so that the download can be resumed.
+    pub fn open(path: &Path, record: PartialDownload, max_bytes: u64) -> anyhow::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open download file {}", path.display()))?;
+        file.set_len(record.bytes_received)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            path: path.to_owned(),
+            writer: BufWriter::new(file),
+            record,
+            unrecorded_bytes: 0,
+            max_bytes,
+            has_begun: false,
+            exceeded_max_bytes: false,
+            total_bytes: None,
+            progress: ProgressReporter::default(),
+            cancellation: CancellationToken::default(),
+        })
+    }
+
+    /// Reports the download's progress to `progress` as it is written.
+    pub fn with_progress(mut self, progress: ProgressReporter) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Stops writing once `cancellation` is cancelled.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    fn report_progress(&self) {
+        self.progress.report(
+            UpdatePhase::Downloading,
+            self.bytes_received(),
+            self.total_bytes,
+        );
+    }
+
+    /// The total number of bytes of the download on disk.
+    pub fn bytes_received(&self) -> u64 {
+        self.record.bytes_received + self.unrecorded_bytes
+    }
+
+    /// Whether the download was aborted for exceeding `max_bytes`.
+    pub fn exceeded_max_bytes(&self) -> bool {
+        self.exceeded_max_bytes
+    }
+
+    /// Flushes the file to disk and records how much of it we have.
+    fn checkpoint(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        self.record.bytes_received += self.unrecorded_bytes;
+        self.unrecorded_bytes = 0;
+        self.record.save(&self.path)
+    }
+
+    /// Marks the download as complete, removing its sidecar record.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        let record_path = PartialDownload::record_path(&self.path);
+        if record_path.exists() {
+            std::fs::remove_file(&record_path)
+                .with_context(|| format!("Failed to remove {}", record_path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Keeps what has been downloaded so far so that a later attempt can
+    /// resume from it.
+    pub fn suspend(mut self) -> anyhow::Result<()> {
+        self.checkpoint()
+    }
+
+    /// Deletes the partial download and its record.
+    pub fn discard(self) {
+        let record_path = PartialDownload::record_path(&self.path);
+        drop(self.writer);
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(record_path);
+    }
+}
+
+impl DownloadSink for DownloadFile {
+    fn begin(&mut self, response: &DownloadResponse) -> anyhow::Result<()> {
+        self.has_begun = true;
+        if !response.is_partial && self.bytes_received() > 0 {
+            shorebird_info!("Server did not resume download, starting from the beginning");
+            self.writer.flush()?;
+            self.writer.get_mut().set_len(0)?;
+            self.writer.get_mut().seek(SeekFrom::Start(0))?;
+            self.record.bytes_received = 0;
+            self.unrecorded_bytes = 0;
+        } else if response.is_partial {
+            shorebird_info!("Resuming download at byte {}", self.bytes_received());
+        }
+        if response.etag.is_some() {
+            self.record.etag = response.etag.clone();
+        }
+        self.total_bytes = response.total_bytes;
+        self.checkpoint()?;
+        self.report_progress();
+        Ok(())
+    }
+}
+
+impl Write for DownloadFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Transports which don't report anything about the response are
+        // assumed to be sending the whole file.
+        if !self.has_begun {
+            self.begin(&DownloadResponse::default())
+                .map_err(std::io::Error::other)?;
+        }
+        if self.cancellation.is_cancelled() {
+            return Err(std::io::Error::other(UpdateError::Cancelled));
+        }
+        if self.bytes_received() + buf.len() as u64 > self.max_bytes {
+            self.exceeded_max_bytes = true;
+            return Err(std::io::Error::other(format!(
+                "Download exceeded maximum size of {} bytes",
+                self.max_bytes
+            )));
+        }
+        let written = self.writer.write(buf)?;
+        self.unrecorded_bytes += written as u64;
+        if self.unrecorded_bytes >= RECORD_CHECKPOINT_BYTES {
+            self.checkpoint().map_err(std::io::Error::other)?;
+        }
+        self.report_progress();
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use super::{DownloadFile, PartialDownload};
+    use crate::network::{DownloadResponse, DownloadSink};
+
+    fn record(bytes_received: u64) -> PartialDownload {
+        PartialDownload {
+            patch_number: 1,
+            url: "https://example.com/patch/1".to_string(),
+            etag: None,
+            bytes_received,
+        }
+    }
+
+    #[test]
+    fn record_path_is_next_to_download() {
+        let path = std::path::Path::new("/downloads/1");
+        assert_eq!(
+            PartialDownload::record_path(path),
+            std::path::Path::new("/downloads/1.download.json")
+        );
+    }
+
+    #[test]
+    fn suspended_download_is_resumable() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new("download")?;
+        let path = tmp_dir.path().join("1");
+
+        let mut file = DownloadFile::open(&path, record(0), 100)?;
+        file.begin(&DownloadResponse {
+            total_bytes: None,
+            is_partial: false,
+            etag: Some("\"abc\"".to_string()),
+        })?;
+        file.write_all(b"hello")?;
+        file.suspend()?;
+
+        let resumable =
+            PartialDownload::load_resumable(&path, 1, "https://example.com/patch/1").unwrap();
+        assert_eq!(resumable.bytes_received, 5);
+        assert_eq!(resumable.etag, Some("\"abc\"".to_string()));
+
+        // A different patch or url is not resumable.
+        assert!(PartialDownload::load_resumable(&path, 2, "https://example.com/patch/1").is_none());
+        assert!(PartialDownload::load_resumable(&path, 1, "https://example.com/patch/2").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn appends_when_server_resumes() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new("download")?;
+        let path = tmp_dir.path().join("1");
+        // Bytes past what the record claims are discarded.
+        std::fs::write(&path, "hello junk")?;
+
+        let mut file = DownloadFile::open(&path, record(5), 100)?;
+        file.begin(&DownloadResponse {
+            total_bytes: None,
+            is_partial: true,
+            etag: None,
+        })?;
+        file.write_all(b" world")?;
+        file.finish()?;
+
+        assert_eq!(std::fs::read_to_string(&path)?, "hello world");
+        assert!(!PartialDownload::record_path(&path).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn starts_over_when_server_sends_full_file() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new("download")?;
+        let path = tmp_dir.path().join("1");
+        std::fs::write(&path, "hello")?;
+
+        let mut file = DownloadFile::open(&path, record(5), 100)?;
+        // Writing without calling begin is treated as a full response.
+        file.write_all(b"hello world")?;
+        file.finish()?;
+
+        assert_eq!(std::fs::read_to_string(&path)?, "hello world");
+
+        Ok(())
+    }
+
+    #[test]
+    fn errs_when_exceeding_max_bytes() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new("download")?;
+        let path = tmp_dir.path().join("1");
+        std::fs::write(&path, "hello")?;
+
+        let mut file = DownloadFile::open(&path, record(5), 8)?;
+        file.begin(&DownloadResponse {
+            total_bytes: None,
+            is_partial: true,
+            etag: None,
+        })?;
+        assert!(file.write_all(b" world").is_err());
+        assert!(file.exceeded_max_bytes());
+
+        file.discard();
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
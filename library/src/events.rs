@@ -13,6 +13,8 @@ pub enum EventType {
     PatchInstallSuccess,
     PatchInstallFailure,
     PatchDownload,
+    /// A state file was unreadable and was recovered from its backup.
+    StateRecovered,
 }
 
 impl Serialize for EventType {
@@ -24,6 +26,7 @@ impl Serialize for EventType {
             EventType::PatchInstallSuccess => "__patch_install__",
             EventType::PatchInstallFailure => "__patch_install_failure__",
             EventType::PatchDownload => "__patch_download__",
+            EventType::StateRecovered => "__state_recovered__",
         })
     }
 }
@@ -38,6 +41,7 @@ impl<'de> Deserialize<'de> for EventType {
             "__patch_install__" => Ok(EventType::PatchInstallSuccess),
             "__patch_install_failure__" => Ok(EventType::PatchInstallFailure),
             "__patch_download__" => Ok(EventType::PatchDownload),
+            "__state_recovered__" => Ok(EventType::StateRecovered),
             _ => Err(serde::de::Error::custom(format!("Unknown event type: {s}"))),
         }
     }
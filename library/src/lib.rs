@@ -11,9 +11,13 @@ pub mod c_api;
 // Declare other .rs file/module exists, but make them private.
 mod cache;
 mod config;
+mod download;
 mod events;
 mod logging;
 mod network;
+mod progress;
+mod retry;
+mod scheduler;
 mod time;
 mod updater;
 mod updater_lock;
@@ -3,14 +3,23 @@
 
 use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Read, Write};
 use std::path::Path;
 use std::string::ToString;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
 use url::Url;
 
 use crate::config::{current_arch, current_platform, UpdateConfig};
+use crate::download::{DownloadFile, DownloadOptions, PartialDownload};
 use crate::events::PatchEvent;
+use crate::retry::{with_retries, RetryPolicy};
+use crate::updater::UpdateError;
+use crate::updater_lock::CancellationToken;
+use crate::yaml::TimeoutConfig;
 
 pub fn patches_check_url(base_url: &str) -> String {
     format!("{base_url}/api/v1/patches/check")
@@ -49,11 +58,144 @@ fn replace_download_url_domain(download_url: &str, base_url: &str) -> anyhow::Re
     Ok(result)
 }
 
+/// Sends the updater's network requests. [`ReqwestTransport`] is used unless
+/// the host provides its own, e.g. to route traffic through the app's HTTP
+/// stack.
+///
+/// Every request must be sent with the given `headers` in addition to any
+/// the transport adds itself.
+pub trait Transport: Debug + Send + Sync {
+    /// Sends a patch check request to `url`.
+    fn patch_check(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &PatchCheckRequest,
+    ) -> anyhow::Result<PatchCheckResponse>;
+
+    /// Sends a patch check request to `url` which the server may answer with
+    /// 304 Not Modified if its answer is the same as the one `cached` came
+    /// with. Transports which don't support conditional requests can leave
+    /// this as is, which always sends a full patch check.
+    fn conditional_patch_check(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &PatchCheckRequest,
+        cached: &CacheValidators,
+    ) -> anyhow::Result<PatchCheckOutcome> {
+        let _ = cached;
+        Ok(PatchCheckOutcome::Modified {
+            response: Box::new(self.patch_check(url, headers, request)?),
+            validators: CacheValidators::default(),
+        })
+    }
+
+    /// Downloads the file described by `request`, streaming the body into
+    /// `sink`.
+    fn download_file(
+        &self,
+        request: &DownloadRequest,
+        headers: &HttpHeaders,
+        sink: &mut dyn DownloadSink,
+    ) -> anyhow::Result<()>;
+
+    /// Reports a patch event to `url`.
+    fn report_event(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &CreatePatchEventRequest,
+    ) -> anyhow::Result<()>;
+}
+
+/// The ETag and Last-Modified headers of a response, sent back with later
+/// requests so the server can tell us nothing has changed.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// The result of a conditional patch check.
+#[derive(Debug)]
+pub enum PatchCheckOutcome {
+    /// The server's answer is unchanged since the response we have cached.
+    NotModified,
+    /// The server sent a new answer.
+    Modified {
+        response: Box<PatchCheckResponse>,
+        validators: CacheValidators,
+    },
+}
+
+/// Extra headers sent with every request, e.g. an API key for a self-hosted
+/// update server. Names are case-insensitive and stored lowercased. Values
+/// are often secrets, so Debug only shows the names.
+#[derive(Clone, Default, PartialEq)]
+pub struct HttpHeaders(BTreeMap<String, String>);
+
+impl HttpHeaders {
+    /// Sets the header `name` to `value`, replacing any existing value.
+    pub fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("Invalid header name: {name}"))?;
+        reqwest::header::HeaderValue::from_str(value)
+            .with_context(|| format!("Invalid value for header {name}"))?;
+        self.0.insert(name.as_str().to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    /// Removes the header `name`, if set.
+    pub fn remove(&mut self, name: &str) {
+        self.0.remove(&name.to_ascii_lowercase());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    fn to_header_map(&self) -> anyhow::Result<reqwest::header::HeaderMap> {
+        let mut map = reqwest::header::HeaderMap::new();
+        for (name, value) in self.iter() {
+            map.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+                reqwest::header::HeaderValue::from_str(value)?,
+            );
+        }
+        Ok(map)
+    }
+}
+
+impl Debug for HttpHeaders {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
 pub type PatchCheckRequestFn = fn(&str, PatchCheckRequest) -> anyhow::Result<PatchCheckResponse>;
-pub type DownloadFileFn = fn(&str) -> anyhow::Result<Vec<u8>>;
+/// Downloads the file described by the request, streaming the body into the
+/// given sink.
+#[cfg(test)]
+pub type DownloadFileFn = fn(&DownloadRequest, &mut dyn DownloadSink) -> anyhow::Result<()>;
+#[cfg(test)]
 pub type ReportEventFn = fn(&str, CreatePatchEventRequest) -> anyhow::Result<()>;
 
-/// A container for network callbacks which can be mocked out for testing.
+/// A transport made of plain functions which can be mocked out for testing.
+#[cfg(test)]
 #[derive(Clone)]
 pub struct NetworkHooks {
     /// The function to call to send a patch check request.
@@ -65,6 +207,7 @@ pub struct NetworkHooks {
 }
 
 // We have to implement Debug by hand since fn types don't implement it.
+#[cfg(test)]
 impl core::fmt::Debug for NetworkHooks {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NetworkHooks")
@@ -75,42 +218,377 @@ impl core::fmt::Debug for NetworkHooks {
     }
 }
 
+#[cfg(test)]
 impl Default for NetworkHooks {
     fn default() -> Self {
         Self {
-            patch_check_request_fn: patch_check_request_default,
-            download_file_fn: download_file_default,
-            report_event_fn: report_event_default,
+            patch_check_request_fn: |url, request| {
+                ReqwestTransport::default().patch_check(url, &HttpHeaders::default(), &request)
+            },
+            download_file_fn: |request, sink| {
+                ReqwestTransport::default().download_file(request, &HttpHeaders::default(), sink)
+            },
+            report_event_fn: |url, request| {
+                ReqwestTransport::default().report_event(url, &HttpHeaders::default(), &request)
+            },
         }
     }
 }
 
-pub fn patch_check_request_default(
-    url: &str,
-    request: PatchCheckRequest,
-) -> anyhow::Result<PatchCheckResponse> {
-    shorebird_info!("Sending patch check request: {:?}", request);
-    let client = reqwest::blocking::Client::new();
-    let result = client.post(url).json(&request).send();
-    let response = handle_network_result(result)?.json()?;
-    shorebird_debug!("Patch check response: {:?}", response);
-    Ok(response)
-}
-
-pub fn download_file_default(url: &str) -> anyhow::Result<Vec<u8>> {
-    let client = reqwest::blocking::Client::new();
-    let result = client.get(url).send();
-    let response = handle_network_result(result)?;
-    let bytes = response.bytes()?;
-    // Patch files are small (e.g. 50kb) so this should be ok to copy into memory.
-    Ok(bytes.to_vec())
-}
-
-pub fn report_event_default(url: &str, request: CreatePatchEventRequest) -> anyhow::Result<()> {
-    let client = reqwest::blocking::Client::new();
-    let result = client.post(url).json(&request).send();
-    handle_network_result(result)?;
-    Ok(())
+// The hooks don't care about headers.
+#[cfg(test)]
+impl Transport for NetworkHooks {
+    fn patch_check(
+        &self,
+        url: &str,
+        _headers: &HttpHeaders,
+        request: &PatchCheckRequest,
+    ) -> anyhow::Result<PatchCheckResponse> {
+        (self.patch_check_request_fn)(url, request.clone())
+    }
+
+    fn download_file(
+        &self,
+        request: &DownloadRequest,
+        _headers: &HttpHeaders,
+        sink: &mut dyn DownloadSink,
+    ) -> anyhow::Result<()> {
+        (self.download_file_fn)(request, sink)
+    }
+
+    fn report_event(
+        &self,
+        url: &str,
+        _headers: &HttpHeaders,
+        request: &CreatePatchEventRequest,
+    ) -> anyhow::Result<()> {
+        (self.report_event_fn)(url, request.clone())
+    }
+}
+
+/// Timeouts applied to requests made by [`ReqwestTransport`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkTimeouts {
+    /// How long to wait for a connection to the server.
+    pub connect: Duration,
+    /// How long to wait for any single read (e.g. the next chunk of a
+    /// download) before giving up on a stalled server.
+    pub read: Duration,
+    /// How long a whole request may take, from connecting until the last
+    /// byte of the response has been received.
+    pub total: Duration,
+}
+
+impl Default for NetworkTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            read: Duration::from_secs(30),
+            total: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl NetworkTimeouts {
+    /// Builds timeouts from the `timeouts` section of shorebird.yaml, using
+    /// the default for anything not set.
+    pub fn from_yaml(yaml: Option<&TimeoutConfig>) -> Self {
+        let default = Self::default();
+        let Some(yaml) = yaml else {
+            return default;
+        };
+        let from_ms = |ms: Option<u64>, default| ms.map(Duration::from_millis).unwrap_or(default);
+        Self {
+            connect: from_ms(yaml.connect_ms, default.connect),
+            read: from_ms(yaml.read_ms, default.read),
+            total: from_ms(yaml.total_ms, default.total),
+        }
+    }
+}
+
+/// The kind of network connection the host app reports the device as having.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    /// The host hasn't told us (or doesn't know).
+    #[default]
+    Unknown,
+    /// A connection without data charges, e.g. Wi-Fi or ethernet.
+    Unmetered,
+    /// A connection which may cost the user money, e.g. cellular.
+    Metered,
+}
+
+/// Which connections patches may be downloaded over.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadPolicy {
+    /// Download over any connection.
+    #[default]
+    Any,
+    /// Only download once the host reports an unmetered connection.
+    UnmeteredOnly,
+}
+
+impl DownloadPolicy {
+    /// Whether a patch may be downloaded over `connectivity`.
+    pub fn allows(&self, connectivity: Connectivity) -> bool {
+        match self {
+            DownloadPolicy::Any => true,
+            DownloadPolicy::UnmeteredOnly => connectivity == Connectivity::Unmetered,
+        }
+    }
+}
+
+/// The default transport, which makes requests with reqwest. The client is
+/// created on first use and then reused, so that connections (and TLS
+/// sessions) are shared between requests.
+#[derive(Debug, Default)]
+pub struct ReqwestTransport {
+    timeouts: NetworkTimeouts,
+    client: OnceCell<reqwest::blocking::Client>,
+}
+
+impl ReqwestTransport {
+    pub fn new(timeouts: NetworkTimeouts) -> Self {
+        Self {
+            timeouts,
+            client: OnceCell::new(),
+        }
+    }
+
+    fn client(&self) -> anyhow::Result<&reqwest::blocking::Client> {
+        self.client.get_or_try_init(|| {
+            reqwest::blocking::Client::builder()
+                .connect_timeout(self.timeouts.connect)
+                // For the blocking client this bounds each read, not the
+                // whole request. Setting a per-request timeout would replace
+                // this rather than add to it, so we enforce the total
+                // ourselves.
+                .timeout(self.timeouts.read)
+                .build()
+                .context("Failed to create HTTP client")
+        })
+    }
+
+    /// Timeout for requests with small responses, for which there is no need
+    /// to tell a stalled read apart from a slow request.
+    fn small_request_timeout(&self) -> Duration {
+        self.timeouts.read.min(self.timeouts.total)
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn patch_check(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &PatchCheckRequest,
+    ) -> anyhow::Result<PatchCheckResponse> {
+        shorebird_info!("Sending patch check request: {:?}", request);
+        let result = self
+            .client()?
+            .post(url)
+            .headers(headers.to_header_map()?)
+            .timeout(self.small_request_timeout())
+            .json(request)
+            .send();
+        let response = handle_network_result(result)?.json()?;
+        shorebird_debug!("Patch check response: {:?}", response);
+        Ok(response)
+    }
+
+    fn conditional_patch_check(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &PatchCheckRequest,
+        cached: &CacheValidators,
+    ) -> anyhow::Result<PatchCheckOutcome> {
+        use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+        use reqwest::StatusCode;
+
+        shorebird_info!("Sending conditional patch check request: {:?}", request);
+        let mut builder = self
+            .client()?
+            .post(url)
+            .headers(headers.to_header_map()?)
+            .timeout(self.small_request_timeout())
+            .json(request);
+        if let Some(etag) = &cached.etag {
+            builder = builder.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            builder = builder.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let result = builder.send();
+        if let Ok(response) = &result {
+            if response.status() == StatusCode::NOT_MODIFIED {
+                shorebird_debug!("Patch check response: not modified");
+                return Ok(PatchCheckOutcome::NotModified);
+            }
+        }
+        let response = handle_network_result(result)?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        let validators = CacheValidators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+        let response = Box::new(response.json()?);
+        shorebird_debug!("Patch check response: {:?}", response);
+        Ok(PatchCheckOutcome::Modified {
+            response,
+            validators,
+        })
+    }
+
+    fn download_file(
+        &self,
+        request: &DownloadRequest,
+        headers: &HttpHeaders,
+        sink: &mut dyn DownloadSink,
+    ) -> anyhow::Result<()> {
+        use reqwest::header::{ETAG, IF_RANGE, RANGE};
+        use reqwest::StatusCode;
+
+        let client = self.client()?;
+        let header_map = headers.to_header_map()?;
+        let deadline = Instant::now() + self.timeouts.total;
+        let mut builder = client.get(&request.url).headers(header_map.clone());
+        if request.range_start > 0 {
+            builder = builder.header(RANGE, format!("bytes={}-", request.range_start));
+            if let Some(etag) = &request.if_range {
+                builder = builder.header(IF_RANGE, etag);
+            }
+        }
+        let mut result = builder.send();
+        // The file may have shrunk or our partial download may be bogus, in
+        // which case the server can't satisfy our range. Just ask for the
+        // whole file.
+        if matches!(&result, Ok(response) if response.status() == StatusCode::RANGE_NOT_SATISFIABLE)
+        {
+            shorebird_info!("Server could not satisfy range request, downloading full file");
+            result = client.get(&request.url).headers(header_map).send();
+        }
+        let mut response = handle_network_result(result)?;
+        let is_partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        sink.begin(&DownloadResponse {
+            total_bytes: response.content_length().map(|length| {
+                if is_partial {
+                    request.range_start + length
+                } else {
+                    length
+                }
+            }),
+            is_partial,
+            etag: response
+                .headers()
+                .get(ETAG)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned()),
+        })?;
+        // Patch files can be several MB, so stream the body to the sink in
+        // chunks rather than holding the whole thing in memory. We don't use
+        // std::io::copy so that we can tell losing the connection (worth
+        // retrying) apart from failing to write (not worth retrying).
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            // Each read is bounded by the read timeout, so this may overshoot
+            // the total by up to that much.
+            if Instant::now() > deadline {
+                bail!(NetworkError::Timeout);
+            }
+            let bytes_read = match response.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) if is_timeout(&e) => bail!(NetworkError::Timeout),
+                Err(e) => bail!(NetworkError::ConnectionLost(e)),
+            };
+            sink.write_all(&buffer[..bytes_read])?;
+        }
+        Ok(())
+    }
+
+    fn report_event(
+        &self,
+        url: &str,
+        headers: &HttpHeaders,
+        request: &CreatePatchEventRequest,
+    ) -> anyhow::Result<()> {
+        let result = self
+            .client()?
+            .post(url)
+            .headers(headers.to_header_map()?)
+            .timeout(self.small_request_timeout())
+            .json(request)
+            .send();
+        handle_network_result(result)?;
+        Ok(())
+    }
+}
+
+/// Network failures which callers (e.g. the retry policy) may want to tell
+/// apart.
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The server responded with a non-success status.
+    HttpStatus {
+        status: reqwest::StatusCode,
+        /// How long the server asked us to wait before trying again, if it
+        /// sent a Retry-After header.
+        retry_after: Option<Duration>,
+    },
+    /// We could not connect to the server, e.g. because there is no internet
+    /// connection.
+    Connect,
+    /// The connection was lost while receiving the response body.
+    ConnectionLost(std::io::Error),
+    /// The server took longer than the configured timeouts allow to connect,
+    /// respond, or send the response body.
+    Timeout,
+}
+
+impl std::error::Error for NetworkError {}
+
+impl Display for NetworkError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            NetworkError::HttpStatus { status, .. } => {
+                write!(f, "Request failed with status: {status}")
+            }
+            NetworkError::Connect => write!(
+                f,
+                "Patch check request failed due to network error. Please check your internet connection."
+            ),
+            NetworkError::ConnectionLost(e) => {
+                write!(f, "Connection lost while receiving response: {e}")
+            }
+            NetworkError::Timeout => write!(f, "Request timed out"),
+        }
+    }
+}
+
+/// Whether a failed read of a response body was due to a timeout.
+fn is_timeout(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::TimedOut
+        || error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+            .is_some_and(|inner| inner.is_timeout())
+}
+
+/// Parses a Retry-After header. Only the delay-seconds form is supported,
+/// an HTTP date is ignored.
+fn parse_retry_after(response: &reqwest::blocking::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    let seconds = value.to_str().ok()?.trim().parse::<u64>().ok()?;
+    Some(Duration::from_secs(seconds))
 }
 
 /// Handles the result of a network request, returning the response if it was
@@ -126,18 +604,57 @@ fn handle_network_result(
             if response.status().is_success() {
                 Ok(response)
             } else {
-                bail!("Request failed with status: {}", response.status())
+                bail!(NetworkError::HttpStatus {
+                    status: response.status(),
+                    retry_after: parse_retry_after(&response),
+                })
             }
         }
+        Err(e) if e.is_timeout() => bail!(NetworkError::Timeout),
         Err(e) => match e.source() {
             Some(source) if source.to_string().contains("client error (Connect)") => {
-                bail!("Patch check request failed due to network error. Please check your internet connection.");
+                bail!(NetworkError::Connect);
             }
             _ => bail!(e),
         },
     }
 }
 
+/// A request to download a file, possibly resuming an earlier partial
+/// download of it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DownloadRequest {
+    /// The URL to download.
+    pub url: String,
+    /// The offset to resume the download from. If non-zero, only the bytes
+    /// from this offset on are requested (via a Range header).
+    pub range_start: u64,
+    /// The ETag of the bytes we already have, if known. Sent as If-Range so
+    /// that the server sends the whole file if it has changed.
+    pub if_range: Option<String>,
+}
+
+/// What a transport learned about a download response before streaming its
+/// body.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DownloadResponse {
+    /// True if the server honored the request's range (i.e. responded with
+    /// 206 Partial Content), false if it is sending the whole file.
+    pub is_partial: bool,
+    /// The ETag of the file being downloaded, if the server sent one.
+    pub etag: Option<String>,
+    /// The size of the whole file (not just the part being sent), if known.
+    pub total_bytes: Option<u64>,
+}
+
+/// Where a download's body is written.
+pub trait DownloadSink: Write {
+    /// Called once a response has been received, before any of its body is
+    /// written. Sinks which are not told otherwise assume the full file is
+    /// being sent.
+    fn begin(&mut self, response: &DownloadResponse) -> anyhow::Result<()>;
+}
+
 #[cfg(test)]
 /// Unit tests can call this to mock out the network calls.
 pub fn testing_set_network_hooks(
@@ -147,11 +664,11 @@ pub fn testing_set_network_hooks(
 ) {
     crate::config::with_config_mut(|maybe_config| match maybe_config {
         Some(config) => {
-            config.network_hooks = NetworkHooks {
+            config.transport = std::sync::Arc::new(NetworkHooks {
                 patch_check_request_fn,
                 download_file_fn,
                 report_event_fn,
-            };
+            });
         }
         None => {
             panic!("testing_set_network_hooks called before config was initialized");
@@ -159,7 +676,7 @@ pub fn testing_set_network_hooks(
     });
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Patch {
     /// The patch number.  Starts at 1 for each new release and increases
     /// monotonically.
@@ -172,13 +689,19 @@ pub struct Patch {
     /// The signature of `hash`, if this patch is signed. None otherwise.
     #[serde(default)]
     pub hash_signature: Option<String>,
+    /// The size in bytes of the (compressed) patch file, if the server sent it.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Anything else the server attached to the patch, e.g. release notes.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
 }
 
 /// Any edits to this struct should be made carefully and in accordance
 /// with our privacy policy:
 /// <https://docs.shorebird.dev/privacy>
 /// The request body for the patch check endpoint.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct PatchCheckRequest {
     /// The Shorebird app_id built into the shorebird.yaml in the app.
     /// app_ids are unique to each app and are used to identify the app
@@ -219,13 +742,13 @@ impl PatchCheckRequest {
 ///
 /// We may want to consider making this more generic if/when we add more events
 /// using something like <https://github.com/dtolnay/typetag>.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CreatePatchEventRequest {
     event: PatchEvent,
 }
 
 /// A response from the server telling us the latest state of patches for this release.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PatchCheckResponse {
     pub patch_available: bool,
     #[serde(default)]
@@ -241,28 +764,72 @@ pub struct PatchCheckResponse {
 pub fn send_patch_event(event: PatchEvent, config: &UpdateConfig) -> anyhow::Result<()> {
     let request = CreatePatchEventRequest { event };
 
-    let report_event_fn = config.network_hooks.report_event_fn;
     let url = &patches_events_url(&config.base_url);
-    report_event_fn(url, request)
+    // Cancelling an update doesn't stop events being reported.
+    let cancellation = CancellationToken::default();
+    with_retries(
+        &config.retry_policy,
+        &cancellation,
+        "Reporting patch event",
+        || {
+            config
+                .transport
+                .report_event(url, &config.headers, &request)
+        },
+    )
 }
 
-/// Downloads the file at `url` to `path`, optionally replacing the domain.
+/// Downloads the file at `url` to `path`, optionally replacing the domain,
+/// using the transport, headers, retry policy, size limit and progress
+/// observer from `config`. Stops early if `cancellation` is cancelled.
 pub fn download_to_path_with_domain_replacement(
-    network_hooks: &NetworkHooks,
+    config: &UpdateConfig,
+    patch_number: usize,
     url: &str,
     path: &Path,
     base_url: Option<&str>,
+    cancellation: &CancellationToken,
 ) -> anyhow::Result<()> {
     let actual_url = if let Some(base) = base_url {
         replace_download_url_domain(url, base)?
     } else {
         url.to_string()
     };
-    
+
     shorebird_info!("Downloading patch from: {} (original: {})", actual_url, url);
-    // Download the file at the given url to the given path.
-    let download_file_hook = network_hooks.download_file_fn;
-    let bytes = download_file_hook(&actual_url)?;
+    download_with_retries(
+        config.transport.as_ref(),
+        &config.retry_policy,
+        &config.headers,
+        patch_number,
+        &actual_url,
+        path,
+        &DownloadOptions {
+            max_bytes: config.max_download_bytes,
+            progress: config.progress.clone(),
+            cancellation: cancellation.clone(),
+        },
+    )
+}
+
+/// Downloads the file at `url` to `path`.
+///
+/// If an earlier download of the same patch from the same URL was
+/// interrupted, this resumes from where it left off. If this download is
+/// interrupted, what was downloaded is kept so that the next call can resume.
+/// The download is aborted (and `path` removed) if it exceeds
+/// `options.max_bytes`.
+/// Failed attempts are retried according to `retry_policy`, each retry
+/// resuming from where the last attempt stopped.
+fn download_with_retries(
+    transport: &dyn Transport,
+    retry_policy: &RetryPolicy,
+    headers: &HttpHeaders,
+    patch_number: usize,
+    url: &str,
+    path: &Path,
+    options: &DownloadOptions,
+) -> anyhow::Result<()> {
     // Ensure the download directory exists.
     if let Some(parent) = path.parent() {
         shorebird_debug!("Creating download directory: {:?}", parent);
@@ -270,10 +837,60 @@ pub fn download_to_path_with_domain_replacement(
             .with_context(|| format!("create_dir_all failed for {}", parent.display()))?;
     }
 
+    with_retries(
+        retry_policy,
+        &options.cancellation,
+        "Downloading patch",
+        || download_attempt(transport, headers, patch_number, url, path, options),
+    )
+}
+
+/// Makes a single attempt to download `url` to `path`, resuming any partial
+/// download already there.
+fn download_attempt(
+    transport: &dyn Transport,
+    headers: &HttpHeaders,
+    patch_number: usize,
+    url: &str,
+    path: &Path,
+    options: &DownloadOptions,
+) -> anyhow::Result<()> {
+    let record = PartialDownload::load_resumable(path, patch_number, url).unwrap_or(
+        PartialDownload {
+            patch_number,
+            url: url.to_string(),
+            etag: None,
+            bytes_received: 0,
+        },
+    );
+    let request = DownloadRequest {
+        url: url.to_string(),
+        range_start: record.bytes_received,
+        if_range: record.etag.clone(),
+    };
+
+    options.cancellation.check()?;
     shorebird_info!("Writing patch to: {:?}", path);
-    let mut file = File::create(path)?;
-    file.write_all(&bytes)?;
-    shorebird_info!("Wrote patch to: {:?}", path);
+    let mut file = DownloadFile::open(path, record, options.max_bytes)?
+        .with_progress(options.progress.clone())
+        .with_cancellation(options.cancellation.clone());
+    // Download the file at the given url directly into the file at path.
+    if let Err(err) = transport.download_file(&request, headers, &mut file) {
+        if options.cancellation.is_cancelled() {
+            // Nobody is going to resume a cancelled download.
+            file.discard();
+            anyhow::bail!(UpdateError::Cancelled);
+        } else if file.exceeded_max_bytes() {
+            // Resuming an oversized download would only fail again.
+            file.discard();
+        } else if let Err(suspend_err) = file.suspend() {
+            shorebird_error!("Failed to save partial download: {:?}", suspend_err);
+        }
+        return Err(err);
+    }
+    let bytes_received = file.bytes_received();
+    file.finish()?;
+    shorebird_info!("Wrote {} bytes to: {:?}", bytes_received, path);
     Ok(())
 }
 
@@ -281,17 +898,37 @@ pub fn download_to_path_with_domain_replacement(
 /// This is the legacy function maintained for backward compatibility.
 #[allow(dead_code)]
 fn download_to_path(
-    network_hooks: &NetworkHooks,
+    transport: &dyn Transport,
+    patch_number: usize,
     url: &str,
     path: &Path,
+    max_bytes: u64,
 ) -> anyhow::Result<()> {
-    download_to_path_with_domain_replacement(network_hooks, url, path, None)
+    download_with_retries(
+        transport,
+        &RetryPolicy::no_retries(),
+        &HttpHeaders::default(),
+        patch_number,
+        url,
+        path,
+        &DownloadOptions {
+            max_bytes,
+            progress: Default::default(),
+            cancellation: Default::default(),
+        },
+    )
 }
 
 #[cfg(test)]
 mod tests {
+    use tempdir::TempDir;
+
     use crate::{network::PatchCheckResponse, time};
     use super::replace_download_url_domain;
+    use super::{
+        CacheValidators, DownloadRequest, DownloadResponse, DownloadSink, HttpHeaders,
+        PartialDownload, PatchCheckOutcome, ReqwestTransport, Transport,
+    };
 
     use super::{patches_events_url, PatchEvent};
     use crate::events::EventType;
@@ -401,10 +1038,493 @@ mod tests {
             },
         );
         assert!(result.is_err());
-        let result = (network_hooks.download_file_fn)("");
+        let tmp_dir = TempDir::new("example").unwrap();
+        let mut file = super::DownloadFile::open(
+            &tmp_dir.path().join("1"),
+            super::PartialDownload {
+                patch_number: 1,
+                url: "".to_string(),
+                etag: None,
+                bytes_received: 0,
+            },
+            100,
+        )
+        .unwrap();
+        let result = (network_hooks.download_file_fn)(&Default::default(), &mut file);
         assert!(result.is_err());
     }
 
+    fn download_request(url: &str, range_start: u64, if_range: Option<&str>) -> DownloadRequest {
+        DownloadRequest {
+            url: url.to_string(),
+            range_start,
+            if_range: if_range.map(|s| s.to_string()),
+        }
+    }
+
+    /// A sink which records what it was told and what was written to it.
+    #[derive(Default)]
+    struct RecordingSink {
+        response: Option<DownloadResponse>,
+        bytes: Vec<u8>,
+    }
+
+    impl std::io::Write for RecordingSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.bytes.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl DownloadSink for RecordingSink {
+        fn begin(&mut self, response: &DownloadResponse) -> anyhow::Result<()> {
+            self.response = Some(response.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn download_file_default_streams_body_to_sink() {
+        let mut server = mockito::Server::new();
+        let body = vec![7u8; 100_000];
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_header("etag", "\"abc\"")
+            .with_body(&body)
+            .create();
+
+        let mut sink = RecordingSink::default();
+        let url = format!("{}/patch/1", server.url());
+        ReqwestTransport::default()
+            .download_file(
+                &download_request(&url, 0, None),
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap();
+        assert_eq!(sink.bytes, body);
+        assert_eq!(
+            sink.response,
+            Some(DownloadResponse {
+                total_bytes: Some(100_000),
+                is_partial: false,
+                etag: Some("\"abc\"".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn reqwest_transport_sends_headers() {
+        let mut server = mockito::Server::new();
+        let check_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .match_header("x-api-key", "secret")
+            .with_status(200)
+            .with_body("{\"patch_available\":false}")
+            .create();
+        let download_mock = server
+            .mock("GET", "/patch/1")
+            .match_header("x-api-key", "secret")
+            .with_status(200)
+            .with_body("hello")
+            .create();
+
+        let mut headers = HttpHeaders::default();
+        headers.set("X-Api-Key", "secret").unwrap();
+        let transport = ReqwestTransport::default();
+        transport
+            .patch_check(
+                &super::patches_check_url(&server.url()),
+                &headers,
+                &super::PatchCheckRequest {
+                    app_id: "".to_string(),
+                    channel: "".to_string(),
+                    release_version: "".to_string(),
+                    platform: "".to_string(),
+                    arch: "".to_string(),
+                },
+            )
+            .unwrap();
+        let mut sink = RecordingSink::default();
+        transport
+            .download_file(
+                &download_request(&format!("{}/patch/1", server.url()), 0, None),
+                &headers,
+                &mut sink,
+            )
+            .unwrap();
+
+        check_mock.assert();
+        download_mock.assert();
+    }
+
+    #[test]
+    fn conditional_patch_check_sends_validators() {
+        let mut server = mockito::Server::new();
+        let modified_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_status(200)
+            .with_header("etag", "\"v1\"")
+            .with_header("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_body("{\"patch_available\":false}")
+            .create();
+        let not_modified_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .match_header("if-none-match", "\"v1\"")
+            .match_header("if-modified-since", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_status(304)
+            .create();
+
+        let transport = ReqwestTransport::default();
+        let url = super::patches_check_url(&server.url());
+        let request = super::PatchCheckRequest {
+            app_id: "".to_string(),
+            channel: "".to_string(),
+            release_version: "".to_string(),
+            platform: "".to_string(),
+            arch: "".to_string(),
+        };
+        let outcome = transport
+            .conditional_patch_check(
+                &url,
+                &HttpHeaders::default(),
+                &request,
+                &CacheValidators::default(),
+            )
+            .unwrap();
+        let validators = match outcome {
+            PatchCheckOutcome::Modified {
+                response,
+                validators,
+            } => {
+                assert!(!response.patch_available);
+                validators
+            }
+            other => panic!("Unexpected outcome: {:?}", other),
+        };
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+
+        let outcome = transport
+            .conditional_patch_check(&url, &HttpHeaders::default(), &request, &validators)
+            .unwrap();
+        assert!(matches!(outcome, PatchCheckOutcome::NotModified));
+        modified_mock.assert();
+        not_modified_mock.assert();
+    }
+
+    #[test]
+    fn http_headers_debug_hides_values() {
+        let mut headers = HttpHeaders::default();
+        headers.set("Authorization", "Bearer secret").unwrap();
+        assert!(headers.set("bad name", "value").is_err());
+        let debug = format!("{:?}", headers);
+        assert!(debug.contains("authorization"));
+        assert!(!debug.contains("secret"));
+    }
+
+    #[test]
+    fn download_file_default_sends_range_request() {
+        let mut server = mockito::Server::new();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .match_header("range", "bytes=5-")
+            .match_header("if-range", "\"abc\"")
+            .with_status(206)
+            .with_body(" world")
+            .create();
+
+        let mut sink = RecordingSink::default();
+        let url = format!("{}/patch/1", server.url());
+        ReqwestTransport::default()
+            .download_file(
+                &download_request(&url, 5, Some("\"abc\"")),
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap();
+        assert_eq!(sink.bytes, b" world");
+        assert!(sink.response.unwrap().is_partial);
+    }
+
+    #[test]
+    fn download_file_default_falls_back_if_range_not_satisfiable() {
+        let mut server = mockito::Server::new();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .match_header("range", "bytes=50-")
+            .with_status(416)
+            .create();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .match_header("range", mockito::Matcher::Missing)
+            .with_status(200)
+            .with_body("hello world")
+            .create();
+
+        let mut sink = RecordingSink::default();
+        let url = format!("{}/patch/1", server.url());
+        ReqwestTransport::default()
+            .download_file(
+                &download_request(&url, 50, None),
+                &HttpHeaders::default(),
+                &mut sink,
+            )
+            .unwrap();
+        assert_eq!(sink.bytes, b"hello world");
+        assert!(!sink.response.unwrap().is_partial);
+    }
+
+    #[test]
+    fn download_to_path_writes_file() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("downloads").join("1");
+        let hooks = super::NetworkHooks {
+            download_file_fn: |_request, sink| {
+                sink.write_all(b"hello")?;
+                Ok(())
+            },
+            ..Default::default()
+        };
+
+        super::download_to_path(&hooks, 1, "ignored", &path, 5).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+        assert!(!PartialDownload::record_path(&path).exists());
+    }
+
+    #[test]
+    fn download_to_path_resumes_interrupted_download() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("1");
+        let interrupted_hooks = super::NetworkHooks {
+            download_file_fn: |_request, sink| {
+                sink.begin(&DownloadResponse {
+                    total_bytes: None,
+                    is_partial: false,
+                    etag: Some("\"abc\"".to_string()),
+                })?;
+                sink.write_all(b"hello")?;
+                anyhow::bail!("Connection reset")
+            },
+            ..Default::default()
+        };
+        assert!(super::download_to_path(&interrupted_hooks, 1, "ignored", &path, 100).is_err());
+        let record = PartialDownload::load_resumable(&path, 1, "ignored").unwrap();
+        assert_eq!(record.bytes_received, 5);
+
+        let resuming_hooks = super::NetworkHooks {
+            download_file_fn: |request, sink| {
+                assert_eq!(request.range_start, 5);
+                assert_eq!(request.if_range.as_deref(), Some("\"abc\""));
+                sink.begin(&DownloadResponse {
+                    total_bytes: None,
+                    is_partial: true,
+                    etag: Some("\"abc\"".to_string()),
+                })?;
+                sink.write_all(b" world")?;
+                Ok(())
+            },
+            ..Default::default()
+        };
+        super::download_to_path(&resuming_hooks, 1, "ignored", &path, 100).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        assert!(!PartialDownload::record_path(&path).exists());
+    }
+
+    #[test]
+    fn download_retries_resume_where_last_attempt_stopped() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("1");
+        let hooks = super::NetworkHooks {
+            download_file_fn: |request, sink| {
+                if ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                    sink.write_all(b"hello")?;
+                    anyhow::bail!(super::NetworkError::ConnectionLost(
+                        std::io::ErrorKind::ConnectionReset.into()
+                    ));
+                }
+                assert_eq!(request.range_start, 5);
+                sink.begin(&DownloadResponse {
+                    total_bytes: None,
+                    is_partial: true,
+                    etag: None,
+                })?;
+                sink.write_all(b" world")?;
+                Ok(())
+            },
+            ..Default::default()
+        };
+        let retry_policy = crate::retry::RetryPolicy {
+            base_delay: std::time::Duration::ZERO,
+            ..Default::default()
+        };
+
+        super::download_with_retries(
+            &hooks,
+            &retry_policy,
+            &Default::default(),
+            1,
+            "ignored",
+            &path,
+            &crate::download::DownloadOptions {
+                max_bytes: 100,
+                progress: Default::default(),
+                cancellation: Default::default(),
+            },
+        )
+        .unwrap();
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 2);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn download_to_path_aborts_oversized_download() {
+        let tmp_dir = TempDir::new("example").unwrap();
+        let path = tmp_dir.path().join("1");
+        let hooks = super::NetworkHooks {
+            download_file_fn: |_request, sink| {
+                // Write in several chunks, as a real download would.
+                for _ in 0..4 {
+                    sink.write_all(b"hello")?;
+                }
+                Ok(())
+            },
+            ..Default::default()
+        };
+
+        let result = super::download_to_path(&hooks, 1, "ignored", &path, 12);
+        assert!(format!("{:#}", result.unwrap_err())
+            .contains("Download exceeded maximum size of 12 bytes"));
+        // The partial download should have been cleaned up.
+        assert!(!path.exists());
+        assert!(!PartialDownload::record_path(&path).exists());
+    }
+
+    #[test]
+    fn handle_network_result_reports_status_and_retry_after() {
+        let mut server = mockito::Server::new();
+        let _ = server
+            .mock("GET", "/busy")
+            .with_status(429)
+            .with_header("retry-after", "7")
+            .create();
+
+        let result = reqwest::blocking::get(format!("{}/busy", server.url()));
+        let error = super::handle_network_result(result).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Request failed with status: 429 Too Many Requests"
+        );
+        match error.downcast_ref::<super::NetworkError>() {
+            Some(super::NetworkError::HttpStatus {
+                status,
+                retry_after,
+            }) => {
+                assert_eq!(status.as_u16(), 429);
+                assert_eq!(*retry_after, Some(std::time::Duration::from_secs(7)));
+            }
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stalled_download_times_out() {
+        let mut server = mockito::Server::new();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_chunked_body(|writer| {
+                writer.write_all(b"hello")?;
+                writer.flush()?;
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                writer.write_all(b" world")
+            })
+            .create();
+
+        let transport = ReqwestTransport::new(super::NetworkTimeouts {
+            read: std::time::Duration::from_millis(100),
+            ..Default::default()
+        });
+        let mut sink = RecordingSink::default();
+        let url = format!("{}/patch/1", server.url());
+        let result = transport.download_file(
+            &download_request(&url, 0, None),
+            &HttpHeaders::default(),
+            &mut sink,
+        );
+
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<super::NetworkError>(),
+            Some(super::NetworkError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn slow_download_hits_total_timeout() {
+        let mut server = mockito::Server::new();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_chunked_body(|writer| {
+                // Never stalls long enough for the read timeout, but takes
+                // longer than the total timeout.
+                for _ in 0..10 {
+                    writer.write_all(b"hello")?;
+                    writer.flush()?;
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+                Ok(())
+            })
+            .create();
+
+        let transport = ReqwestTransport::new(super::NetworkTimeouts {
+            total: std::time::Duration::from_millis(200),
+            ..Default::default()
+        });
+        let mut sink = RecordingSink::default();
+        let url = format!("{}/patch/1", server.url());
+        let result = transport.download_file(
+            &download_request(&url, 0, None),
+            &HttpHeaders::default(),
+            &mut sink,
+        );
+
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<super::NetworkError>(),
+            Some(super::NetworkError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn network_timeouts_from_yaml() {
+        use crate::yaml::TimeoutConfig;
+        use std::time::Duration;
+
+        assert_eq!(
+            super::NetworkTimeouts::from_yaml(None),
+            super::NetworkTimeouts::default()
+        );
+        assert_eq!(
+            super::NetworkTimeouts::from_yaml(Some(&TimeoutConfig {
+                connect_ms: Some(1000),
+                read_ms: None,
+                total_ms: Some(60_000),
+            })),
+            super::NetworkTimeouts {
+                connect: Duration::from_secs(1),
+                total: Duration::from_secs(60),
+                ..Default::default()
+            }
+        );
+    }
+
     #[test]
     fn network_hooks_debug() {
         let network_hooks = super::NetworkHooks::default();
@@ -457,11 +1577,12 @@ mod tests {
             timestamp: time::unix_timestamp(),
             message: None,
         };
-        let result = super::report_event_default(
+        let result = ReqwestTransport::default().report_event(
             // Make the request to a non-existent URL, which will trigger the
             // same error as a lack of internet connection.
             &patches_events_url("http://asdfasdfasdfasdfasdf.asdfasdf"),
-            super::CreatePatchEventRequest { event },
+            &HttpHeaders::default(),
+            &super::CreatePatchEventRequest { event },
         );
 
         assert!(result.is_err());
@@ -471,11 +1592,12 @@ mod tests {
 
     #[test]
     fn handle_network_result_unknown_error() {
-        let result = super::report_event_default(
+        let result = ReqwestTransport::default().report_event(
             // Make the request to an incorrectly formatted URL, which will
             // trigger the same error as a lack of internet connection.
             &patches_events_url("does_not_exist"),
-            super::CreatePatchEventRequest {
+            &HttpHeaders::default(),
+            &super::CreatePatchEventRequest {
                 event: PatchEvent {
                     app_id: "app_id".to_string(),
                     arch: "arch".to_string(),
@@ -0,0 +1,160 @@
+// This file's job is to tell whoever is interested how far along an update
+// is, e.g. so that an app can show a progress bar for a user-requested update.
+
+use std::fmt::Debug;
+use std::io::Read;
+use std::sync::Arc;
+
+/// The stages of an update, in the order they happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdatePhase {
+    /// Asking the server whether there is a patch.
+    Checking,
+    /// Downloading the patch.
+    Downloading,
+    /// Applying the downloaded patch to the release.
+    Inflating,
+    /// Checking the hash of the patched result.
+    Verifying,
+    /// Moving the patch into place for the next boot.
+    Installing,
+}
+
+/// How far along an update is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateProgress {
+    pub phase: UpdatePhase,
+    /// Bytes handled so far in this phase. For `Downloading` this includes
+    /// any bytes from an earlier, interrupted download being resumed.
+    pub bytes_received: u64,
+    /// Total bytes this phase will handle, if known.
+    pub total_bytes: Option<u64>,
+}
+
+/// Receives progress updates. Called from whichever thread is doing the work,
+/// so implementations must be quick and thread-safe.
+pub trait ProgressObserver: Debug + Send + Sync {
+    fn on_progress(&self, progress: UpdateProgress);
+}
+
+/// Passes progress to the registered observer, if there is one.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter(Option<Arc<dyn ProgressObserver>>);
+
+impl ProgressReporter {
+    pub fn new(observer: Option<Arc<dyn ProgressObserver>>) -> Self {
+        Self(observer)
+    }
+
+    pub fn report(&self, phase: UpdatePhase, bytes_received: u64, total_bytes: Option<u64>) {
+        if let Some(observer) = &self.0 {
+            observer.on_progress(UpdateProgress {
+                phase,
+                bytes_received,
+                total_bytes,
+            });
+        }
+    }
+
+    /// Reports that `phase` has started.
+    pub fn start(&self, phase: UpdatePhase) {
+        self.report(phase, 0, None);
+    }
+}
+
+/// A reader which reports how much of `total_bytes` has been read.
+pub struct ProgressReader<R> {
+    inner: R,
+    reporter: ProgressReporter,
+    phase: UpdatePhase,
+    bytes_read: u64,
+    total_bytes: Option<u64>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(
+        inner: R,
+        reporter: ProgressReporter,
+        phase: UpdatePhase,
+        total_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            inner,
+            reporter,
+            phase,
+            bytes_read: 0,
+            total_bytes,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            self.bytes_read += read as u64;
+            self.reporter
+                .report(self.phase, self.bytes_read, self.total_bytes);
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    use super::{ProgressObserver, ProgressReader, ProgressReporter, UpdatePhase, UpdateProgress};
+
+    /// Records every progress update it receives.
+    #[derive(Debug, Default)]
+    pub struct RecordingObserver(pub Mutex<Vec<UpdateProgress>>);
+
+    impl ProgressObserver for RecordingObserver {
+        fn on_progress(&self, progress: UpdateProgress) {
+            self.0.lock().unwrap().push(progress);
+        }
+    }
+
+    impl RecordingObserver {
+        pub fn phases(&self) -> Vec<UpdatePhase> {
+            let mut phases: Vec<UpdatePhase> =
+                self.0.lock().unwrap().iter().map(|p| p.phase).collect();
+            phases.dedup();
+            phases
+        }
+    }
+
+    #[test]
+    fn progress_reader_reports_bytes_read() {
+        let observer = Arc::new(RecordingObserver::default());
+        let reporter = ProgressReporter::new(Some(observer.clone()));
+        let mut reader = ProgressReader::new(
+            &b"hello world"[..],
+            reporter,
+            UpdatePhase::Inflating,
+            Some(11),
+        );
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+
+        let progress = observer.0.lock().unwrap();
+        assert_eq!(
+            progress.last(),
+            Some(&UpdateProgress {
+                phase: UpdatePhase::Inflating,
+                bytes_received: 11,
+                total_bytes: Some(11),
+            })
+        );
+        assert!(progress.windows(2).all(|w| w[0].bytes_received < w[1].bytes_received));
+    }
+
+    #[test]
+    fn reporter_without_observer_does_nothing() {
+        ProgressReporter::default().start(UpdatePhase::Checking);
+    }
+}
@@ -0,0 +1,502 @@
+// This file's job is to decide whether and when a failed network request
+// should be retried, and to retry it.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::download::CorruptDownload;
+use crate::network::NetworkError;
+use crate::updater_lock::{is_cancelled_error, CancellationToken};
+use crate::yaml::RetryConfig;
+
+/// How failed network requests are retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// The total number of attempts to make, including the first. 1 disables
+    /// retries.
+    pub max_attempts: u32,
+    /// The delay before the first retry. Doubles with each further retry.
+    pub base_delay: Duration,
+    /// The longest we will wait between attempts.
+    pub max_delay: Duration,
+    /// The fraction (0.0 - 1.0) of each delay which is randomized, so that
+    /// clients which failed together don't all retry together.
+    pub jitter: f64,
+    /// HTTP status codes which are worth retrying.
+    pub retryable_status_codes: Vec<u16>,
+    /// Whether to retry when we could not reach the server at all, lost the
+    /// connection part way through a response, or timed out.
+    pub retry_connection_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            retryable_status_codes: vec![408, 429, 500, 502, 503, 504],
+            retry_connection_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Builds a policy from the `retry` section of shorebird.yaml, using the
+    /// default for anything not set.
+    pub fn from_yaml(yaml: Option<&RetryConfig>) -> Self {
+        let default = Self::default();
+        let Some(yaml) = yaml else {
+            return default;
+        };
+        Self {
+            // Zero attempts makes no sense, treat it as "don't retry".
+            max_attempts: yaml.max_attempts.unwrap_or(default.max_attempts).max(1),
+            base_delay: yaml
+                .base_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: yaml
+                .max_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_delay),
+            jitter: yaml.jitter.unwrap_or(default.jitter).clamp(0.0, 1.0),
+            retryable_status_codes: yaml
+                .retryable_status_codes
+                .clone()
+                .unwrap_or(default.retryable_status_codes),
+            retry_connection_errors: yaml
+                .retry_connection_errors
+                .unwrap_or(default.retry_connection_errors),
+        }
+    }
+
+    /// Returns how long to wait before retrying after `error` caused attempt
+    /// number `attempt` (starting at 1) to fail, or None if we should give up.
+    fn delay_before_retry(&self, error: &anyhow::Error, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let retry_after = match classify(error)? {
+            Failure::Status {
+                status,
+                retry_after,
+            } => {
+                if !self.retryable_status_codes.contains(&status) {
+                    return None;
+                }
+                retry_after
+            }
+            Failure::Connection => {
+                if !self.retry_connection_errors {
+                    return None;
+                }
+                None
+            }
+        };
+        match retry_after {
+            // The server told us when to come back. If that is further away
+            // than we're willing to wait, give up rather than retry early.
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt, random_fraction())),
+        }
+    }
+
+    /// The exponential backoff delay after attempt number `attempt` failed,
+    /// with `random` (0.0 - 1.0) used to apply jitter.
+    fn backoff(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        delay.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+/// The kinds of failure we know how to retry.
+enum Failure {
+    Status {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    Connection,
+}
+
+fn classify(error: &anyhow::Error) -> Option<Failure> {
+    for cause in error.chain() {
+        if let Some(network_error) = cause.downcast_ref::<NetworkError>() {
+            return match network_error {
+                NetworkError::HttpStatus {
+                    status,
+                    retry_after,
+                } => Some(Failure::Status {
+                    status: status.as_u16(),
+                    retry_after: *retry_after,
+                }),
+                NetworkError::Connect | NetworkError::ConnectionLost(_) | NetworkError::Timeout => {
+                    Some(Failure::Connection)
+                }
+            };
+        }
+        if let Some(reqwest_error) = cause.downcast_ref::<reqwest::Error>() {
+            if reqwest_error.is_connect() || reqwest_error.is_timeout() {
+                return Some(Failure::Connection);
+            }
+        }
+    }
+    None
+}
+
+/// Whether `error` suggests the server we sent the request to is unhealthy,
+/// so the same request is worth sending to a mirror instead.
+pub fn should_fail_over(error: &anyhow::Error) -> bool {
+    if is_cancelled_error(error) {
+        return false;
+    }
+    if error.downcast_ref::<CorruptDownload>().is_some() {
+        return true;
+    }
+    match classify(error) {
+        Some(Failure::Status { status, .. }) => status >= 500,
+        Some(Failure::Connection) => true,
+        None => false,
+    }
+}
+
+/// A random number in [0.0, 1.0). Good enough for jitter, not for anything
+/// which needs real randomness.
+fn random_fraction() -> f64 {
+    // Each RandomState is seeded differently, so hashing anything with a new
+    // one gives a different value each time.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Calls `operation` until it succeeds, fails in a way `policy` says is not
+/// worth retrying, or runs out of attempts. Returns the last result, or an
+/// [`crate::updater::UpdateError::Cancelled`] error as soon as `cancellation` is
+/// cancelled while waiting to retry.
+pub fn with_retries<T, F>(
+    policy: &RetryPolicy,
+    cancellation: &CancellationToken,
+    description: &str,
+    mut operation: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> anyhow::Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match operation() {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        let Some(delay) = policy.delay_before_retry(&error, attempt) else {
+            return Err(error);
+        };
+        shorebird_info!(
+            "{} failed (attempt {} of {}), retrying in {:?}: {}",
+            description,
+            attempt,
+            policy.max_attempts,
+            delay,
+            error
+        );
+        cancellation.sleep(delay)?;
+        attempt += 1;
+    }
+}
+
+/// Calls `operation` with each of `base_urls` in turn until it succeeds or
+/// fails in a way [`should_fail_over`] says another base URL won't fix.
+/// Returns the result along with the base URL which produced it. Stops
+/// without trying the next base URL once `cancellation` is cancelled.
+pub fn with_failover<T, F>(
+    base_urls: &[String],
+    cancellation: &CancellationToken,
+    description: &str,
+    mut operation: F,
+) -> anyhow::Result<(T, String)>
+where
+    F: FnMut(&str) -> anyhow::Result<T>,
+{
+    let mut base_urls = base_urls.iter().peekable();
+    while let Some(base_url) = base_urls.next() {
+        let error = match operation(base_url) {
+            Ok(value) => return Ok((value, base_url.clone())),
+            Err(error) => error,
+        };
+        let Some(next_base_url) = base_urls.peek() else {
+            return Err(error);
+        };
+        if !should_fail_over(&error) {
+            return Err(error);
+        }
+        cancellation.check()?;
+        shorebird_info!(
+            "{} failed using {}, trying {}: {}",
+            description,
+            base_url,
+            next_base_url,
+            error
+        );
+    }
+    anyhow::bail!("{} failed: no base URLs to try", description)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use reqwest::StatusCode;
+
+    use super::{should_fail_over, with_failover, with_retries, RetryPolicy};
+    use crate::download::CorruptDownload;
+    use crate::network::NetworkError;
+    use crate::updater_lock::{is_cancelled_error, CancellationToken};
+    use crate::yaml::RetryConfig;
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    fn status_error(status: u16) -> anyhow::Error {
+        NetworkError::HttpStatus {
+            status: StatusCode::from_u16(status).unwrap(),
+            retry_after: None,
+        }
+        .into()
+    }
+
+    #[test]
+    fn retries_retryable_errors_until_success() {
+        let mut attempts = 0;
+        let result = with_retries(
+            &fast_policy(),
+            &CancellationToken::default(),
+            "test",
+            || {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(status_error(503))
+                } else {
+                    Ok(attempts)
+                }
+            },
+        );
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = with_retries(
+            &fast_policy(),
+            &CancellationToken::default(),
+            "test",
+            || {
+                attempts += 1;
+                Err(NetworkError::Connect.into())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let policy = fast_policy();
+        for error in [status_error(404), anyhow!("Invalid patch")] {
+            let mut attempts = 0;
+            let mut error = Some(error);
+            let result: anyhow::Result<()> =
+                with_retries(&policy, &CancellationToken::default(), "test", || {
+                    attempts += 1;
+                    Err(error.take().unwrap())
+                });
+            assert!(result.is_err());
+            assert_eq!(attempts, 1);
+        }
+    }
+
+    #[test]
+    fn does_not_retry_connection_errors_if_disabled() {
+        let policy = RetryPolicy {
+            retry_connection_errors: false,
+            ..fast_policy()
+        };
+        let mut attempts = 0;
+        let result: anyhow::Result<()> =
+            with_retries(&policy, &CancellationToken::default(), "test", || {
+                attempts += 1;
+                Err(NetworkError::Connect.into())
+            });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn stops_waiting_to_retry_when_cancelled() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let cancellation = CancellationToken::default();
+        let canceller = cancellation.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+
+        let start = std::time::Instant::now();
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = with_retries(&policy, &cancellation, "test", || {
+            attempts += 1;
+            Err(NetworkError::Connect.into())
+        });
+        assert!(is_cancelled_error(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn with_failover_stops_when_cancelled() {
+        let base_urls = vec!["a".to_string(), "b".to_string()];
+        let cancellation = CancellationToken::default();
+        let mut tried = 0;
+        let result: anyhow::Result<((), String)> =
+            with_failover(&base_urls, &cancellation, "test", |_| {
+                tried += 1;
+                cancellation.cancel();
+                Err(status_error(503))
+            });
+        assert!(is_cancelled_error(&result.unwrap_err()));
+        assert_eq!(tried, 1);
+    }
+
+    #[test]
+    fn finds_network_errors_behind_context() {
+        let error = status_error(500).context("Failed to download patch");
+        assert!(fast_policy().delay_before_retry(&error, 1).is_some());
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let policy = RetryPolicy::default();
+        let error = |seconds| -> anyhow::Error {
+            NetworkError::HttpStatus {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                retry_after: Some(Duration::from_secs(seconds)),
+            }
+            .into()
+        };
+        assert_eq!(
+            policy.delay_before_retry(&error(5), 1),
+            Some(Duration::from_secs(5))
+        );
+        // Further away than we're willing to wait.
+        assert_eq!(policy.delay_before_retry(&error(60), 1), None);
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2, 0.0), Duration::from_secs(2));
+        assert_eq!(policy.backoff(3, 0.0), Duration::from_secs(4));
+        assert_eq!(policy.backoff(4, 0.0), Duration::from_secs(5));
+        assert_eq!(policy.backoff(100, 0.0), Duration::from_secs(5));
+        // Jitter takes off up to half the delay.
+        assert_eq!(policy.backoff(2, 1.0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn from_yaml_uses_defaults_for_unset_values() {
+        assert_eq!(RetryPolicy::from_yaml(None), RetryPolicy::default());
+
+        let policy = RetryPolicy::from_yaml(Some(&RetryConfig {
+            max_attempts: Some(5),
+            base_delay_ms: Some(100),
+            max_delay_ms: None,
+            jitter: Some(2.0),
+            retryable_status_codes: Some(vec![503]),
+            retry_connection_errors: None,
+        }));
+        assert_eq!(
+            policy,
+            RetryPolicy {
+                max_attempts: 5,
+                base_delay: Duration::from_millis(100),
+                jitter: 1.0,
+                retryable_status_codes: vec![503],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn fails_over_on_server_and_connection_errors() {
+        assert!(should_fail_over(&status_error(503)));
+        assert!(should_fail_over(&NetworkError::Connect.into()));
+        assert!(should_fail_over(
+            &anyhow!("hash mismatch").context(CorruptDownload)
+        ));
+        assert!(!should_fail_over(&status_error(404)));
+        assert!(!should_fail_over(&anyhow!("Invalid patch")));
+    }
+
+    #[test]
+    fn with_failover_tries_each_base_url_in_turn() {
+        let base_urls = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let mut tried = Vec::new();
+        let result = with_failover(
+            &base_urls,
+            &CancellationToken::default(),
+            "test",
+            |base_url| {
+                tried.push(base_url.to_string());
+                if base_url == "b" {
+                    Ok(1)
+                } else {
+                    Err(status_error(503))
+                }
+            },
+        );
+        assert_eq!(result.unwrap(), (1, "b".to_string()));
+        assert_eq!(tried, vec!["a", "b"]);
+
+        // Errors another server won't fix are returned straight away.
+        let mut tried = 0;
+        let result: anyhow::Result<((), String)> =
+            with_failover(&base_urls, &CancellationToken::default(), "test", |_| {
+                tried += 1;
+                Err(status_error(404))
+            });
+        assert!(result.is_err());
+        assert_eq!(tried, 1);
+    }
+}
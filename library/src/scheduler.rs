@@ -0,0 +1,178 @@
+// This file's job is to check for updates periodically in the background, for
+// apps which stay open for far longer than between releases (e.g. kiosks).
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::config::with_config;
+use crate::updater::{update, UpdateError};
+
+/// The most times the delay between checks is doubled after failed checks,
+/// i.e. we wait at most 16 intervals between checks.
+const MAX_BACKOFF_DOUBLINGS: u32 = 4;
+
+/// Tells the scheduler thread when to stop.
+#[derive(Debug, Default)]
+struct StopSignal {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl StopSignal {
+    fn stop(&self) {
+        *self.stopped.lock().expect("Failed to acquire scheduler lock.") = true;
+        self.condvar.notify_all();
+    }
+
+    /// Waits for `timeout`, returning early (with true) if stopped.
+    fn wait(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().expect("Failed to acquire scheduler lock.");
+        let (stopped, _) = self
+            .condvar
+            .wait_timeout_while(stopped, timeout, |stopped| !*stopped)
+            .expect("Failed to acquire scheduler lock.");
+        *stopped
+    }
+}
+
+struct Scheduler {
+    stop: Arc<StopSignal>,
+    thread: JoinHandle<()>,
+}
+
+fn scheduler() -> &'static Mutex<Option<Scheduler>> {
+    use once_cell::sync::OnceCell;
+    static INSTANCE: OnceCell<Mutex<Option<Scheduler>>> = OnceCell::new();
+    INSTANCE.get_or_init(|| Mutex::new(None))
+}
+
+/// Starts checking for updates every `scheduled_check_minutes` (from
+/// shorebird.yaml) on a background thread. Does nothing if the scheduler is
+/// already running.
+pub fn start_update_scheduler() -> anyhow::Result<()> {
+    let interval = with_config(|config| Ok(config.scheduled_check_interval))?;
+    let Some(interval) = interval else {
+        bail!("scheduled_check_minutes is not set in shorebird.yaml");
+    };
+    start_with_interval(interval)
+}
+
+fn start_with_interval(interval: Duration) -> anyhow::Result<()> {
+    let mut scheduler = scheduler()
+        .lock()
+        .expect("Failed to acquire scheduler lock.");
+    if scheduler
+        .as_ref()
+        .is_some_and(|scheduler| !scheduler.thread.is_finished())
+    {
+        shorebird_info!("Update scheduler already running.");
+        return Ok(());
+    }
+    let stop = Arc::new(StopSignal::default());
+    let thread_stop = stop.clone();
+    let thread = std::thread::Builder::new()
+        .name("shorebird_scheduler".to_string())
+        .spawn(move || run(interval, &thread_stop))
+        .context("Failed to start update scheduler thread")?;
+    shorebird_info!("Update scheduler started, checking every {:?}", interval);
+    *scheduler = Some(Scheduler { stop, thread });
+    Ok(())
+}
+
+/// Stops the scheduler. An update it has already started carries on, but no
+/// more are started. Returns false if the scheduler wasn't running.
+pub fn stop_update_scheduler() -> bool {
+    stop().is_some()
+}
+
+/// Stops the scheduler, returning its thread if it was running.
+fn stop() -> Option<JoinHandle<()>> {
+    let scheduler = scheduler()
+        .lock()
+        .expect("Failed to acquire scheduler lock.")
+        .take()?;
+    scheduler.stop.stop();
+    shorebird_info!("Update scheduler stopped.");
+    Some(scheduler.thread)
+}
+
+fn run(interval: Duration, stop: &StopSignal) {
+    let mut failures = 0;
+    while !stop.wait(delay_before_next_check(interval, failures)) {
+        match update(None) {
+            Ok(status) => {
+                shorebird_info!("Scheduled update finished with status: {}", status);
+                failures = 0;
+            }
+            // Someone else is already updating, which is as good as us doing it.
+            Err(err) if err.downcast_ref() == Some(&UpdateError::UpdateAlreadyInProgress) => {
+                shorebird_debug!("Skipping scheduled update, one is already running.");
+            }
+            Err(err) => {
+                shorebird_error!("Scheduled update failed: {:?}", err);
+                failures += 1;
+            }
+        }
+    }
+}
+
+/// Doubles the delay for each consecutive failure, up to a limit, so that we
+/// don't hammer a server which is having trouble.
+fn delay_before_next_check(interval: Duration, failures: u32) -> Duration {
+    interval.saturating_mul(1 << failures.min(MAX_BACKOFF_DOUBLINGS))
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    use super::{delay_before_next_check, start_with_interval, stop, stop_update_scheduler};
+    use crate::network::PatchCheckResponse;
+
+    #[test]
+    fn backs_off_after_failures() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(delay_before_next_check(interval, 0), interval);
+        assert_eq!(delay_before_next_check(interval, 1), interval * 2);
+        assert_eq!(delay_before_next_check(interval, 3), interval * 8);
+        assert_eq!(delay_before_next_check(interval, 100), interval * 16);
+    }
+
+    #[serial]
+    #[test]
+    fn checks_until_stopped() {
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: false,
+            patch: None,
+            rolled_back_patch_numbers: None,
+        };
+        let check_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .expect_at_least(2)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        crate::updater::tests::init_for_testing(&tmp_dir, Some(&server.url()));
+        // Not configured in shorebird.yaml.
+        assert!(super::start_update_scheduler().is_err());
+
+        start_with_interval(Duration::from_millis(10)).unwrap();
+        // Starting again is harmless.
+        start_with_interval(Duration::from_millis(10)).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while !check_mock.matched() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // Wait for any update in progress so it doesn't affect other tests.
+        stop().unwrap().join().unwrap();
+        assert!(!stop_update_scheduler());
+        check_mock.assert();
+    }
+}
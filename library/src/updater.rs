@@ -4,30 +4,55 @@ use std::fmt::{Debug, Display, Formatter};
 use std::fs::{self};
 use std::io::{Cursor, Read, Seek};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
 use dyn_clone::DynClone;
 
-use crate::cache::{PatchInfo, UpdaterState};
+use crate::cache::{CachedPatchCheck, PatchInfo, StorageLock, UpdaterState};
 use crate::config::{set_config, with_config, UpdateConfig};
+use crate::download::{CorruptDownload, PartialDownload};
 use crate::events::{EventType, PatchEvent};
 use crate::logging::init_logging;
-use crate::network::{download_to_path_with_domain_replacement, patches_check_url, NetworkHooks, PatchCheckRequest};
-use crate::updater_lock::{with_updater_thread_lock, UpdaterLockState};
+use crate::network::{
+    download_to_path_with_domain_replacement, patches_check_url, CacheValidators,
+    PatchCheckOutcome, PatchCheckRequest, PatchCheckResponse,
+};
+use crate::progress::{ProgressReader, ProgressReporter};
+use crate::retry::{with_failover, with_retries};
+use crate::time;
+use crate::updater_lock::{
+    cancel_running_update, is_cancelled_error, running_update_status, wait_for_running_update,
+    with_updater_thread_lock, CancellableReader, CancellationToken, UpdaterLockState,
+};
 use crate::yaml::YamlConfig;
 
+pub use crate::progress::{ProgressObserver, UpdatePhase, UpdateProgress};
+pub use crate::scheduler::{start_update_scheduler, stop_update_scheduler};
+pub use crate::updater_lock::RunningUpdate;
+
 #[cfg(test)]
 // Expose testing_reset_config for integration tests.
 pub use crate::config::testing_reset_config;
 #[cfg(test)]
 pub use crate::network::{DownloadFileFn, Patch, PatchCheckRequestFn};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum UpdateStatus {
     NoUpdate,
     UpdateInstalled,
     UpdateHadError,
     UpdateIsBadPatch,
+    /// The update was skipped because we checked for one within the
+    /// configured check interval.
+    Throttled,
+    /// An update is available, but the download policy doesn't allow
+    /// downloading it over the current connection.
+    UpdateDeferred,
+    /// The update was cancelled before it finished. Nothing was installed.
+    Cancelled,
 }
 
 impl Display for UpdateStatus {
@@ -40,6 +65,15 @@ impl Display for UpdateStatus {
                 f,
                 "Update available but previously failed to install. Not installing."
             ),
+            UpdateStatus::Throttled => write!(
+                f,
+                "Skipped update check, last check was within the check interval."
+            ),
+            UpdateStatus::UpdateDeferred => write!(
+                f,
+                "Update available but not allowed to download over the current connection."
+            ),
+            UpdateStatus::Cancelled => write!(f, "Update cancelled"),
         }
     }
 }
@@ -86,6 +120,8 @@ pub enum UpdateError {
     FailedToSaveState,
     ConfigNotInitialized,
     UpdateAlreadyInProgress,
+    Cancelled,
+    TimedOut,
 }
 
 impl std::error::Error for UpdateError {}
@@ -100,6 +136,99 @@ impl Display for UpdateError {
             UpdateError::UpdateAlreadyInProgress => {
                 write!(f, "Update already in progress")
             }
+            UpdateError::Cancelled => write!(f, "Update cancelled"),
+            UpdateError::TimedOut => {
+                write!(f, "Timed out waiting for the running update to finish")
+            }
+        }
+    }
+}
+
+/// Why an update failed, for callers which need to handle some failures
+/// differently, e.g. to tell the user to free up space. Errors are tagged with
+/// one of these where they happen; see [`UpdateFailure::from_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateFailure {
+    /// None of the other reasons apply.
+    Unknown,
+    /// The server couldn't be reached or responded with an error status.
+    Network,
+    /// The server's response didn't make sense.
+    BadServerResponse,
+    /// The downloaded patch couldn't be applied to the release.
+    InflateFailed,
+    /// The patched release didn't have the hash the server said it would.
+    HashMismatch,
+    /// The patch's signature was missing or invalid.
+    SignatureInvalid,
+    /// The device ran out of storage.
+    DiskFull,
+    /// Reading or writing the updater's files failed.
+    Storage,
+    /// The updater hasn't been initialized.
+    NotInitialized,
+    /// Another update was already running.
+    AlreadyInProgress,
+    /// Gave up waiting for a running update to finish.
+    TimedOut,
+}
+
+impl std::error::Error for UpdateFailure {}
+
+impl Display for UpdateFailure {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            UpdateFailure::Unknown => write!(f, "Update failed"),
+            UpdateFailure::Network => write!(f, "Network request failed"),
+            UpdateFailure::BadServerResponse => write!(f, "Bad server response"),
+            UpdateFailure::InflateFailed => write!(f, "Failed to apply patch"),
+            UpdateFailure::HashMismatch => write!(f, "Patch hash mismatch"),
+            UpdateFailure::SignatureInvalid => write!(f, "Patch signature is invalid"),
+            UpdateFailure::DiskFull => write!(f, "Out of storage"),
+            UpdateFailure::Storage => write!(f, "Failed to read or write updater files"),
+            UpdateFailure::NotInitialized => write!(f, "Updater not initialized"),
+            UpdateFailure::AlreadyInProgress => write!(f, "Update already in progress"),
+            UpdateFailure::TimedOut => write!(f, "Timed out waiting for update"),
+        }
+    }
+}
+
+impl UpdateFailure {
+    /// Works out why `error` happened, preferring the reason it was tagged
+    /// with and otherwise looking at what caused it. Running out of storage
+    /// trumps any tag, since that's what the user can do something about.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        let io_kind = error.chain().find_map(|cause| {
+            if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+                Some(io_error.kind())
+            } else {
+                cause
+                    .downcast_ref::<serde_json::Error>()
+                    .and_then(serde_json::Error::io_error_kind)
+            }
+        });
+        if io_kind == Some(std::io::ErrorKind::StorageFull) {
+            return UpdateFailure::DiskFull;
+        }
+        if let Some(failure) = error.downcast_ref::<UpdateFailure>() {
+            return *failure;
+        }
+        match error.downcast_ref::<UpdateError>() {
+            Some(UpdateError::BadServerResponse) => return UpdateFailure::BadServerResponse,
+            Some(UpdateError::ConfigNotInitialized) => return UpdateFailure::NotInitialized,
+            Some(UpdateError::UpdateAlreadyInProgress) => return UpdateFailure::AlreadyInProgress,
+            Some(UpdateError::TimedOut) => return UpdateFailure::TimedOut,
+            Some(UpdateError::FailedToSaveState) => return UpdateFailure::Storage,
+            _ => {}
+        }
+        if error.chain().any(|cause| {
+            cause.is::<crate::network::NetworkError>() || cause.is::<reqwest::Error>()
+        }) {
+            return UpdateFailure::Network;
+        }
+        match io_kind {
+            Some(_) => UpdateFailure::Storage,
+            None => UpdateFailure::Unknown,
         }
     }
 }
@@ -154,11 +283,29 @@ where
     })
 }
 
+/// Locks the storage directory against other processes. See [StorageLock].
+fn lock_storage() -> anyhow::Result<StorageLock> {
+    // Don't hold the config lock while waiting for another process.
+    let storage_dir = with_config(|config| Ok(config.storage_dir.clone()))?;
+    StorageLock::acquire(&storage_dir)
+}
+
+/// Like [with_config], but holds the storage lock (taken first, see
+/// [lock_storage]) while `f` runs.
+fn with_config_and_storage_lock<F, R>(f: F) -> anyhow::Result<R>
+where
+    F: FnOnce(&UpdateConfig) -> anyhow::Result<R>,
+{
+    let _lock = lock_storage()?;
+    with_config(f)
+}
+
 pub fn with_mut_state<F, R>(f: F) -> anyhow::Result<R>
 where
     F: FnOnce(&mut UpdaterState) -> anyhow::Result<R>,
 {
-    with_config(|config| {
+    // Keep other processes from changing the state between our load and save.
+    with_config_and_storage_lock(|config| {
         let mut state = UpdaterState::load_or_new_on_error(
             &config.storage_dir,
             &config.release_version,
@@ -192,7 +339,7 @@ pub fn init(
         file_provider,
         libapp_path,
         &config,
-        NetworkHooks::default(),
+        None,
     );
 
     // set_config will return an error if the config is already initialized. This should not cause
@@ -205,31 +352,44 @@ pub fn init(
 }
 
 /// If, at initialization time, we detect that we were in the process of booting a patch, report a
-/// failure to boot for that patch and queue an event to report the failure.
+/// failure to boot for that patch and queue an event to report the failure. Also queues events for
+/// any state files which had to be recovered from backups.
 pub fn handle_prior_boot_failure_if_necessary() -> Result<(), InitError> {
-    with_config(|config| {
+    with_config_and_storage_lock(|config| {
         let mut state = UpdaterState::load_or_new_on_error(
             &config.storage_dir,
             &config.release_version,
             config.patch_public_key.as_deref(),
         );
-        if let Some(patch) = state.currently_booting_patch() {
-            state.record_boot_failure_for_patch(patch.number)?;
-            state.queue_event(PatchEvent::new(
-                config,
-                EventType::PatchInstallFailure,
-                patch.number,
-                Some(
-                    format!(
-                        "Patch {} was marked currently_booting in init",
-                        patch.number
-                    )
-                    .as_ref(),
-                ),
-            ))?;
-        }
+        // Commit the boot failure together with the events reporting it.
+        state.transaction(|state| {
+            for file_name in state.take_recovered_files() {
+                state.queue_event(PatchEvent::new(
+                    config,
+                    EventType::StateRecovered,
+                    // Not about any particular patch.
+                    0,
+                    Some(format!("Recovered {} from backup", file_name).as_ref()),
+                ))?;
+            }
+            if let Some(patch) = state.currently_booting_patch() {
+                state.record_boot_failure_for_patch(patch.number)?;
+                state.queue_event(PatchEvent::new(
+                    config,
+                    EventType::PatchInstallFailure,
+                    patch.number,
+                    Some(
+                        format!(
+                            "Patch {} was marked currently_booting in init",
+                            patch.number
+                        )
+                        .as_ref(),
+                    ),
+                ))?;
+            }
 
-        Ok(())
+            Ok(())
+        })
     })
     .map_err(|e| {
         shorebird_error!("Failed to clean up after a failed patch: {:?}", e);
@@ -250,40 +410,96 @@ pub fn should_auto_update() -> anyhow::Result<bool> {
 /// Returns true if an update is available for download. Will return false if the update is already
 /// downloaded and ready to install.
 pub fn check_for_downloadable_update(channel: Option<&str>) -> anyhow::Result<bool> {
-    let (request, url, request_fn) = with_config(|config| {
-        let mut config = config.clone();
+    Ok(check_for_update_details(channel)?.update_available)
+}
 
-        match channel {
-            Some(channel) => config.channel = channel.to_string(),
-            None => {}
-        }
+/// What the server told us about the latest patch, see
+/// [`check_for_update_details`].
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct UpdateDetails {
+    /// Whether there is a patch we would download, i.e. one which is neither
+    /// known to be bad nor already installed.
+    pub update_available: bool,
+    /// The latest patch for this release, if there is one.
+    pub patch: Option<PatchDetails>,
+    /// Patches the server has rolled back.
+    pub rolled_back_patch_numbers: Vec<usize>,
+}
 
-        Ok((
-            PatchCheckRequest::new(&config),
-            patches_check_url(&config.base_url),
-            config.network_hooks.patch_check_request_fn,
-        ))
-    })?;
+/// Describes a patch available from the server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct PatchDetails {
+    pub number: usize,
+    /// The size in bytes of the download, if the server told us.
+    pub size: Option<u64>,
+    /// The hex-encoded sha256 hash of the patched release.
+    pub hash: String,
+    pub is_signed: bool,
+    /// Whether this patch failed to boot on this device before.
+    pub is_known_bad: bool,
+    /// Whether this patch is already installed, ready for the next launch.
+    pub is_already_installed: bool,
+    /// Anything else the server attached to the patch, e.g. release notes.
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// Like [`check_for_downloadable_update`], but returns everything the server
+/// told us about the latest patch rather than just whether to download it.
+/// Like that function, this rolls back any patches the server has rolled
+/// back.
+pub fn check_for_update_details(channel: Option<&str>) -> anyhow::Result<UpdateDetails> {
+    let mut config = copy_update_config()?;
+    if let Some(channel) = channel {
+        config.channel = channel.to_string();
+    }
 
-    let response = request_fn(&url, request)?;
+    let (response, _) = check_for_patch(
+        &config,
+        &PatchCheckRequest::new(&config),
+        &CancellationToken::default(),
+    )?;
     shorebird_debug!("Patch check response: {:?}", response);
 
-    if let Some(rolled_back_patches) = response.rolled_back_patch_numbers {
-        roll_back_patches_if_needed(rolled_back_patches)?;
+    let rolled_back_patch_numbers = response.rolled_back_patch_numbers.unwrap_or_default();
+    if !rolled_back_patch_numbers.is_empty() {
+        roll_back_patches_if_needed(rolled_back_patch_numbers.clone())?;
     }
 
-    if let Some(patch) = response.patch {
-        match should_install_patch(patch.number)? {
-            ShouldInstallPatchCheckResult::PatchOkToInstall => Ok(true),
-            ShouldInstallPatchCheckResult::PatchKnownBad => Ok(false),
-            ShouldInstallPatchCheckResult::PatchAlreadyInstalled => Ok(false),
+    let patch = match response.patch {
+        Some(patch) => {
+            let should_install = should_install_patch(patch.number)?;
+            Some(PatchDetails {
+                number: patch.number,
+                size: patch.size,
+                hash: patch.hash,
+                is_signed: patch.hash_signature.is_some(),
+                is_known_bad: matches!(
+                    should_install,
+                    ShouldInstallPatchCheckResult::PatchKnownBad
+                ),
+                is_already_installed: matches!(
+                    should_install,
+                    ShouldInstallPatchCheckResult::PatchAlreadyInstalled
+                ),
+                metadata: patch.metadata,
+            })
         }
-    } else {
-        Ok(false)
-    }
+        None => None,
+    };
+    Ok(UpdateDetails {
+        update_available: patch
+            .as_ref()
+            .is_some_and(|patch| !patch.is_known_bad && !patch.is_already_installed),
+        patch,
+        rolled_back_patch_numbers,
+    })
 }
 
-fn check_hash(path: &Path, expected_string: &str) -> anyhow::Result<()> {
+fn check_hash(
+    path: &Path,
+    expected_string: &str,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<()> {
     use sha2::{Digest, Sha256}; // `Digest` is needed for `Sha256::new()`;
 
     let expected = hex::decode(expected_string).context("Invalid hash string from server.")?;
@@ -291,7 +507,7 @@ fn check_hash(path: &Path, expected_string: &str) -> anyhow::Result<()> {
     // Based on guidance from:
     // <https://github.com/RustCrypto/hashes#hashing-readable-objects>
 
-    let mut file = fs::File::open(path)?;
+    let mut file = CancellableReader::new(fs::File::open(path)?, cancellation.clone());
     let mut hasher = Sha256::new();
     std::io::copy(&mut file, &mut hasher)?;
     // Check that the length from copy is the same as the file size?
@@ -302,7 +518,7 @@ fn check_hash(path: &Path, expected_string: &str) -> anyhow::Result<()> {
     // server only send updates when the hash matches.
     // https://github.com/shorebirdtech/updater/issues/56
     if !hash_matches {
-        bail!(
+        return Err(anyhow::Error::new(UpdateFailure::HashMismatch).context(format!(
             "Update rejected: hash mismatch. Update was downloaded but \
             contents did not match the expected hash. This is most often \
             caused by using the same version number with a different app \
@@ -310,7 +526,7 @@ fn check_hash(path: &Path, expected_string: &str) -> anyhow::Result<()> {
             path,
             expected_string,
             hex::encode(hash)
-        );
+        )));
     }
     shorebird_debug!("Hash match: {:?}", path);
     Ok(())
@@ -341,9 +557,32 @@ fn copy_update_config() -> anyhow::Result<UpdateConfig> {
     with_config(|config: &UpdateConfig| Ok(config.clone()))
 }
 
-// Callers must possess the Updater lock, but we don't care about the contents
-// since they're empty.
-fn update_internal(_: &UpdaterLockState, channel: Option<&str>) -> anyhow::Result<UpdateStatus> {
+// Callers must possess the Updater lock. The result is shared with anyone
+// waiting on this update through `lock_state`.
+fn update_internal(
+    lock_state: &UpdaterLockState,
+    channel: Option<&str>,
+    ignore_check_interval: bool,
+) -> anyhow::Result<UpdateStatus> {
+    // The storage lock is only taken while reading or changing state, never
+    // across network requests, so that other processes can still boot.
+    let result = check_download_and_install(lock_state, channel, ignore_check_interval);
+    let result = match result {
+        Err(err) if is_cancelled_error(&err) => {
+            shorebird_info!("Update cancelled.");
+            Ok(UpdateStatus::Cancelled)
+        }
+        result => result,
+    };
+    lock_state.finish(result)
+}
+
+fn check_download_and_install(
+    lock_state: &UpdaterLockState,
+    channel: Option<&str>,
+    ignore_check_interval: bool,
+) -> anyhow::Result<UpdateStatus> {
+    let cancellation = &lock_state.cancellation;
     // Only one copy of Update can be running at a time.
     // Update will take the global Updater lock.
     // Update will need to take the Config lock at times, but will only
@@ -365,6 +604,12 @@ fn update_internal(_: &UpdaterLockState, channel: Option<&str>) -> anyhow::Resul
     if channel.is_some() {
         config.channel = channel.unwrap().to_string();
     }
+    // Keep track of which phase we're in for `running_update`.
+    config.progress = lock_state.track_progress(config.progress);
+
+    if !ignore_check_interval && checked_within_interval(&config)? {
+        return Ok(UpdateStatus::Throttled);
+    }
 
     // We discard any events if we have more than 3 queued to make sure
     // we don't stall the client.
@@ -387,9 +632,16 @@ fn update_internal(_: &UpdaterLockState, channel: Option<&str>) -> anyhow::Resul
     })?;
 
     // Check for update.
-    let patch_check_request_fn = &(config.network_hooks.patch_check_request_fn);
-    let response = patch_check_request_fn(&patches_check_url(&config.base_url), request)?;
+    config.progress.start(UpdatePhase::Checking);
+    let (response, check_base_url) = check_for_patch(&config, &request, cancellation)?;
     shorebird_info!("Patch check response: {:?}", response);
+    // Only updates count towards the check interval, so that looking up
+    // patch details doesn't hold back the next update.
+    let result = with_mut_state(|state| state.set_last_patch_check_time(time::unix_timestamp()));
+    if let Err(err) = result {
+        shorebird_error!("Failed to save patch check time: {:?}", err);
+    }
+    cancellation.check()?;
 
     if let Some(rolled_back_patches) = response.rolled_back_patch_numbers {
         roll_back_patches_if_needed(rolled_back_patches)?;
@@ -400,6 +652,7 @@ fn update_internal(_: &UpdaterLockState, channel: Option<&str>) -> anyhow::Resul
     }
 
     let patch = response.patch.ok_or(UpdateError::BadServerResponse)?;
+    lock_state.set_patch_number(patch.number);
 
     match should_install_patch(patch.number)? {
         ShouldInstallPatchCheckResult::PatchOkToInstall => {}
@@ -407,41 +660,109 @@ fn update_internal(_: &UpdaterLockState, channel: Option<&str>) -> anyhow::Resul
         ShouldInstallPatchCheckResult::PatchAlreadyInstalled => return Ok(UpdateStatus::NoUpdate),
     }
 
+    // Connectivity can change while we check, so use the latest we've heard.
+    let connectivity = with_config(|config| Ok(config.connectivity))?;
+    if !config.download_policy.allows(connectivity) {
+        shorebird_info!(
+            "Deferring download of patch {}, connectivity: {:?}",
+            patch.number,
+            connectivity
+        );
+        return Ok(UpdateStatus::UpdateDeferred);
+    }
+
     let download_dir = PathBuf::from(&config.download_dir);
     let download_path = download_dir.join(patch.number.to_string());
-    // Consider supporting allowing the system to download for us (e.g. iOS).
-    download_to_path_with_domain_replacement(&config.network_hooks, &patch.download_url, &download_path, Some(&config.base_url))?;
-
     let output_path = download_dir.join(format!("{}.full", patch.number));
-    let patch_base_rs = patch_base(&config)?;
-    inflate(&download_path, patch_base_rs, &output_path)?;
-
-    // Check the hash before moving into place.
-    check_hash(&output_path, &patch.hash).with_context(|| {
-        format!(
-            "This app reports version {}, but the binary is different from \
-        the version {} that was submitted to Shorebird.",
-            config.release_version, config.release_version
-        )
-    })?;
+    // Start with whichever base URL answered the patch check.
+    let download_base_urls = config.base_urls(Some(&check_base_url));
+    let download_result = with_failover(
+        &download_base_urls,
+        cancellation,
+        "Patch download",
+        |base_url| {
+            let result = download_and_inflate(
+                &config,
+                &patch,
+                base_url,
+                &download_path,
+                &output_path,
+                cancellation,
+            );
+            if let Err(err) = &result {
+                // Don't let the next mirror resume from a corrupt download.
+                if err.is::<CorruptDownload>() {
+                    let _ = fs::remove_file(&download_path);
+                }
+                let _ = fs::remove_file(&output_path);
+            }
+            result
+        },
+    );
+    if cancellation.is_cancelled() {
+        // Don't leave a partial download behind to be resumed; the patch
+        // may well have changed by the time we next try.
+        let _ = fs::remove_file(&download_path);
+        let _ = fs::remove_file(PartialDownload::record_path(&download_path));
+        let _ = fs::remove_file(&output_path);
+        bail!(UpdateError::Cancelled);
+    }
+    let (_, download_base_url) = download_result?;
+    remember_healthy_base_url(&download_base_url);
+
+    install_inflated_patch(
+        config,
+        output_path,
+        patch.number,
+        &patch.hash,
+        patch.hash_signature.as_deref(),
+    )
+}
 
+/// Moves the inflated patch at `output_path` into place for the next launch
+/// and tells the server.
+fn install_inflated_patch(
+    config: UpdateConfig,
+    output_path: PathBuf,
+    patch_number: usize,
+    hash: &str,
+    signature: Option<&str>,
+) -> anyhow::Result<UpdateStatus> {
     // We're abusing the config lock as a UpdateState lock for now.
     // This makes it so we never try to write to the UpdateState file from
     // two threads at once. We could give UpdateState its own lock instead.
+    config.progress.start(UpdatePhase::Installing);
     with_mut_state(|state| {
+        // Another process may have installed or rolled back this patch while
+        // we were downloading it.
+        let skipped = if state.is_known_bad_patch(patch_number) {
+            Some(UpdateStatus::UpdateIsBadPatch)
+        } else if state.next_boot_patch().map(|patch| patch.number) == Some(patch_number) {
+            Some(UpdateStatus::NoUpdate)
+        } else {
+            None
+        };
+        if let Some(status) = skipped {
+            shorebird_info!(
+                "Patch {} changed while downloading, skipping.",
+                patch_number
+            );
+            let _ = fs::remove_file(&output_path);
+            return Ok(status);
+        }
         let patch_info = PatchInfo {
             path: output_path,
-            number: patch.number,
+            number: patch_number,
         };
-        // Move/state update should be "atomic" (it isn't today).
-        state.install_patch(&patch_info, &patch.hash, patch.hash_signature.as_deref())?;
+        // The move and the state update are committed together.
+        state.install_patch(&patch_info, hash, signature)?;
         shorebird_info!(
             "Patch {} successfully downloaded. It will be launched when the app next restarts.",
-            patch.number
+            patch_number
         );
 
         std::thread::spawn(move || {
-            let event = PatchEvent::new(&config, EventType::PatchDownload, patch.number, None);
+            let event = PatchEvent::new(&config, EventType::PatchDownload, patch_number, None);
             let report_result = crate::network::send_patch_event(event, &config);
             if let Err(err) = report_result {
                 shorebird_error!("Failed to report patch download: {:?}", err);
@@ -455,6 +776,157 @@ fn update_internal(_: &UpdaterLockState, channel: Option<&str>) -> anyhow::Resul
     })
 }
 
+/// Sends `request` to each of our base URLs in turn (starting with the one
+/// which last worked) until one answers. Returns the response along with the
+/// base URL which sent it. Gives up waiting to retry once `cancellation` is
+/// cancelled.
+fn check_for_patch(
+    config: &UpdateConfig,
+    request: &PatchCheckRequest,
+    cancellation: &CancellationToken,
+) -> Result<(PatchCheckResponse, String)> {
+    let preferred = with_state(|state| Ok(state.preferred_base_url().map(str::to_owned)))?;
+    let base_urls = config.base_urls(preferred.as_deref());
+    let (response, base_url) = with_failover(&base_urls, cancellation, "Patch check", |base_url| {
+        let url = patches_check_url(base_url);
+        let cached =
+            with_state(|state| Ok(state.cached_patch_check(base_url, request).cloned()))?;
+        let validators = cached
+            .as_ref()
+            .map(|cached| cached.validators.clone())
+            .unwrap_or_default();
+        let outcome = with_retries(&config.retry_policy, cancellation, "Patch check", || {
+            config
+                .transport
+                .conditional_patch_check(&url, &config.headers, request, &validators)
+        })?;
+        match outcome {
+            PatchCheckOutcome::NotModified => {
+                shorebird_debug!("Patch check unchanged, using cached response.");
+                cached
+                    .map(|cached| cached.response)
+                    .context("Server sent Not Modified, but we have no cached patch check")
+            }
+            PatchCheckOutcome::Modified {
+                response,
+                validators,
+            } => {
+                cache_patch_check(base_url, request, &response, validators, cached.is_some());
+                Ok(*response)
+            }
+        }
+    })?;
+    remember_healthy_base_url(&base_url);
+    Ok((response, base_url))
+}
+
+/// Whether our last patch check was recent enough that we should skip this
+/// one.
+fn checked_within_interval(config: &UpdateConfig) -> Result<bool> {
+    if config.check_interval.is_zero() {
+        return Ok(false);
+    }
+    let Some(last_check_time) = with_state(|state| Ok(state.last_patch_check_time()))? else {
+        return Ok(false);
+    };
+    let now = time::unix_timestamp();
+    // If the clock has gone backwards, don't trust the last check time.
+    Ok(now >= last_check_time && now - last_check_time < config.check_interval.as_secs())
+}
+
+/// Caches `response` for the next patch check if the server sent validators
+/// with it, otherwise clears any now stale cached response.
+fn cache_patch_check(
+    base_url: &str,
+    request: &PatchCheckRequest,
+    response: &PatchCheckResponse,
+    validators: CacheValidators,
+    had_cached: bool,
+) {
+    if validators.is_empty() && !had_cached {
+        return;
+    }
+    let cached = (!validators.is_empty()).then(|| CachedPatchCheck {
+        base_url: base_url.to_owned(),
+        request: request.clone(),
+        response: response.clone(),
+        validators,
+    });
+    let result = with_mut_state(|state| state.set_cached_patch_check(cached));
+    if let Err(err) = result {
+        shorebird_error!("Failed to cache patch check response: {:?}", err);
+    }
+}
+
+/// Records `base_url` as the first to try on the next update.
+fn remember_healthy_base_url(base_url: &str) {
+    let result = with_mut_state(|state| state.set_preferred_base_url(base_url));
+    if let Err(err) = result {
+        shorebird_error!("Failed to save preferred base URL: {:?}", err);
+    }
+}
+
+/// Downloads `patch` from `base_url` to `download_path` and inflates it to
+/// `output_path`, checking the result against the patch's hash.
+fn download_and_inflate(
+    config: &UpdateConfig,
+    patch: &crate::network::Patch,
+    base_url: &str,
+    download_path: &Path,
+    output_path: &Path,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    // Consider supporting allowing the system to download for us (e.g. iOS).
+    download_to_path_with_domain_replacement(
+        config,
+        patch.number,
+        &patch.download_url,
+        download_path,
+        Some(base_url),
+        cancellation,
+    )?;
+    inflate_and_verify(config, download_path, &patch.hash, output_path, cancellation)
+}
+
+/// Applies the patch file at `patch_path` to the release, writing the result
+/// to `output_path` and checking that it has the hash `expected_hash`.
+fn inflate_and_verify(
+    config: &UpdateConfig,
+    patch_path: &Path,
+    expected_hash: &str,
+    output_path: &Path,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let patch_base_rs = patch_base(config)?;
+    // A cancelled inflate or hash check fails part way through, which must
+    // not be mistaken for a corrupt download.
+    let result = inflate(
+        patch_path,
+        patch_base_rs,
+        output_path,
+        &config.progress,
+        cancellation,
+    );
+    cancellation.check()?;
+    result
+        .context(UpdateFailure::InflateFailed)
+        .context(CorruptDownload)?;
+
+    // Check the hash before moving into place.
+    config.progress.start(UpdatePhase::Verifying);
+    let result = check_hash(output_path, expected_hash, cancellation);
+    cancellation.check()?;
+    result
+        .context(CorruptDownload)
+        .with_context(|| {
+            format!(
+                "This app reports version {}, but the binary is different from \
+            the version {} that was submitted to Shorebird.",
+                config.release_version, config.release_version
+            )
+        })
+}
+
 fn roll_back_patches_if_needed(patch_numbers: Vec<usize>) -> anyhow::Result<()> {
     with_mut_state(|state| {
         for patch_number in patch_numbers {
@@ -488,13 +960,149 @@ fn should_install_patch(patch_number: usize) -> Result<ShouldInstallPatchCheckRe
 }
 
 /// Synchronously checks for an update and downloads and installs it if available.
+/// Returns [`UpdateStatus::Throttled`] without checking if the last check was
+/// within the configured check interval.
 pub fn update(channel: Option<&str>) -> anyhow::Result<UpdateStatus> {
-    with_updater_thread_lock(|lock_state| update_internal(lock_state, channel))
+    with_updater_thread_lock(|lock_state| update_internal(lock_state, channel, false))
+}
+
+/// Registers (or with None, removes) an observer to be told how updates are
+/// progressing.
+pub fn set_progress_observer(observer: Option<Arc<dyn ProgressObserver>>) -> anyhow::Result<()> {
+    crate::config::set_progress_observer(observer)
+}
+
+/// Asks the update in progress, if there is one, to stop. It will return
+/// [`UpdateStatus::Cancelled`] and remove anything it had downloaded. Returns
+/// false if no update was running.
+pub fn cancel_update() -> bool {
+    cancel_running_update()
+}
+
+/// Describes the update in progress, or returns None if no update is running.
+/// Unlike [`update`], this never waits for or fails because of a running
+/// update.
+pub fn running_update() -> Option<RunningUpdate> {
+    running_update_status()
+}
+
+/// Like [`update`], but checks regardless of when we last checked, e.g.
+/// because the user asked us to.
+pub fn force_update(channel: Option<&str>) -> anyhow::Result<UpdateStatus> {
+    with_updater_thread_lock(|lock_state| update_internal(lock_state, channel, true))
+}
+
+/// Installs a patch delivered by something other than our servers, e.g. a
+/// device management system. `path` is the patch file as our servers would
+/// serve it, and is left in place. It is checked against `hash` (and, if the
+/// app requires signed patches, `signature`) and recorded just like a
+/// downloaded patch, so it is skipped if it is known to be bad or already
+/// installed. Fails with [`UpdateError::UpdateAlreadyInProgress`] if an
+/// update is running.
+pub fn install_patch_from_file(
+    path: &Path,
+    number: usize,
+    hash: &str,
+    signature: Option<&str>,
+) -> anyhow::Result<UpdateStatus> {
+    with_updater_thread_lock(|lock_state| {
+        let result = install_patch_from_file_internal(lock_state, path, number, hash, signature);
+        lock_state.finish(result)
+    })
+}
+
+fn install_patch_from_file_internal(
+    lock_state: &UpdaterLockState,
+    path: &Path,
+    number: usize,
+    hash: &str,
+    signature: Option<&str>,
+) -> anyhow::Result<UpdateStatus> {
+    let mut config = copy_update_config()?;
+    config.progress = lock_state.track_progress(config.progress);
+    lock_state.set_patch_number(number);
+    shorebird_info!("Installing patch {} from {}", number, path.display());
+
+    match should_install_patch(number)? {
+        ShouldInstallPatchCheckResult::PatchOkToInstall => {}
+        ShouldInstallPatchCheckResult::PatchKnownBad => return Ok(UpdateStatus::UpdateIsBadPatch),
+        ShouldInstallPatchCheckResult::PatchAlreadyInstalled => return Ok(UpdateStatus::NoUpdate),
+    }
+
+    let output_path = PathBuf::from(&config.download_dir).join(format!("{}.full", number));
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("create_dir_all failed for {}", parent.display()))?;
+    }
+    let result = inflate_and_verify(
+        &config,
+        path,
+        hash,
+        &output_path,
+        &lock_state.cancellation,
+    );
+    if let Err(err) = result {
+        let _ = fs::remove_file(&output_path);
+        if is_cancelled_error(&err) {
+            return Ok(UpdateStatus::Cancelled);
+        }
+        return Err(err);
+    }
+    install_inflated_patch(config, output_path, number, hash, signature)
+}
+
+/// Runs [`update`] on a new thread and returns immediately. Join the returned
+/// handle for the update's result.
+pub fn update_async(
+    channel: Option<String>,
+) -> anyhow::Result<JoinHandle<anyhow::Result<UpdateStatus>>> {
+    update_async_then(channel, |result| result)
+}
+
+/// Like [`update_async`], but also passes the update's result to
+/// `on_complete` on the update thread.
+pub fn update_async_then<F, R>(channel: Option<String>, on_complete: F) -> anyhow::Result<JoinHandle<R>>
+where
+    F: FnOnce(anyhow::Result<UpdateStatus>) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    std::thread::Builder::new()
+        .name("shorebird_update".to_string())
+        .spawn(move || on_complete(update(channel.as_deref())))
+        .context("Failed to start update thread")
+}
+
+/// Like [`update`], but if an update is already running, waits for it to
+/// finish and returns its result rather than failing with
+/// [`UpdateError::UpdateAlreadyInProgress`]. Note that the running update may
+/// have been for a different channel.
+/// Waits forever if `timeout` is None, otherwise fails with
+/// [`UpdateError::TimedOut`] once `timeout` has passed.
+pub fn update_or_join(
+    channel: Option<&str>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<UpdateStatus> {
+    loop {
+        match update(channel) {
+            Err(err) if err.downcast_ref() == Some(&UpdateError::UpdateAlreadyInProgress) => {}
+            result => return result,
+        }
+        if let Some(result) = wait_for_running_update(timeout) {
+            return result;
+        }
+        // The running update finished before we could join it, so try again.
+    }
 }
 
 /// Given a path to a patch file, and a base file, apply the patch to the base
 /// and write the result to the output path.
-fn inflate<RS>(patch_path: &Path, base_r: RS, output_path: &Path) -> anyhow::Result<()>
+fn inflate<RS>(
+    patch_path: &Path,
+    base_r: RS,
+    output_path: &Path,
+    progress: &ProgressReporter,
+    cancellation: &CancellationToken,
+) -> anyhow::Result<()>
 where
     RS: Read + Seek,
 {
@@ -505,10 +1113,18 @@ where
     // Open all our files first for error clarity.  Otherwise we might see
     // PipeReader/Writer errors instead of file open errors.
     shorebird_info!("Inflating patch from {:?}", patch_path);
-    let compressed_patch_r = BufReader::new(
-        fs::File::open(patch_path)
-            .context(format!("Failed to open patch file: {:?}", patch_path))?,
-    );
+    let patch_file = fs::File::open(patch_path)
+        .context(format!("Failed to open patch file: {:?}", patch_path))?;
+    let patch_len = patch_file.metadata().ok().map(|metadata| metadata.len());
+    // Progress is measured by how much of the (compressed) patch file has
+    // been consumed, as that's the only size we know up front.
+    progress.start(UpdatePhase::Inflating);
+    let compressed_patch_r = BufReader::new(ProgressReader::new(
+        patch_file,
+        progress.clone(),
+        UpdatePhase::Inflating,
+        patch_len,
+    ));
     let output_file_w = fs::File::create(output_path)?;
 
     // Set up a pipe to connect the writing from the decompression thread
@@ -529,7 +1145,8 @@ where
     });
 
     // Do the patch, using the uncompressed patch data from the pipe.
-    let mut fresh_r = bipatch::Reader::new(patch_r, base_r)?;
+    let fresh_r = bipatch::Reader::new(patch_r, base_r)?;
+    let mut fresh_r = CancellableReader::new(fresh_r, cancellation.clone());
 
     // Write out the resulting patched file to the new location.
     let mut output_w = BufWriter::new(output_file_w);
@@ -555,6 +1172,30 @@ pub fn current_boot_patch() -> anyhow::Result<Option<PatchInfo>> {
     with_state(|state| Ok(state.current_boot_patch()))
 }
 
+/// Stops booting a patch installed since the last successful launch, so that
+/// the next launch uses the last successfully booted patch (or the release, if
+/// no patch has launched successfully). If `mark_bad` is true, the abandoned
+/// patch is never installed again. Returns the abandoned patch number, or None
+/// if there was nothing newer than the last booted patch to roll back.
+///
+/// This does not stop an update in progress from installing a newer patch;
+/// call [`cancel_update`] first if that matters.
+pub fn roll_back_to_last_booted_patch(mark_bad: bool) -> anyhow::Result<Option<usize>> {
+    shorebird_info!("Rolling back to the last booted patch.");
+    with_mut_state(|state| state.roll_back_to_last_booted_patch(mark_bad))
+}
+
+/// Stops booting any patch, so that the next launch uses the release as
+/// shipped. If `mark_bad` is true, the abandoned patches are never installed
+/// again. Returns the abandoned patch numbers, newest first.
+///
+/// As with [`roll_back_to_last_booted_patch`], an update in progress may still
+/// install a newer patch.
+pub fn roll_back_to_release(mark_bad: bool) -> anyhow::Result<Vec<usize>> {
+    shorebird_info!("Rolling back to the release.");
+    with_mut_state(|state| state.roll_back_to_release(mark_bad))
+}
+
 pub fn report_launch_start() -> anyhow::Result<()> {
     // We previously set the "current" patch the value of the "next" patch, but no longer
     // do so because the semantics have changed:
@@ -576,7 +1217,7 @@ pub fn report_launch_start() -> anyhow::Result<()> {
 pub fn report_launch_failure() -> anyhow::Result<()> {
     shorebird_info!("Reporting failed launch.");
 
-    with_config(|config| {
+    with_config_and_storage_lock(|config| {
         let mut state = UpdaterState::load_or_new_on_error(
             &config.storage_dir,
             &config.release_version,
@@ -586,34 +1227,37 @@ pub fn report_launch_failure() -> anyhow::Result<()> {
         let patch = state.currently_booting_patch().ok_or(anyhow::Error::from(
             UpdateError::InvalidState("currently_booting_patch is None".to_string()),
         ))?;
-        // Ignore the error here, we'll try to activate the next best patch
-        // even if we fail to mark this one as bad (because it was already bad).
-        let mark_result = state.record_boot_failure_for_patch(patch.number);
-        if mark_result.is_err() {
-            shorebird_error!("Failed to mark patch as bad: {:?}", mark_result);
-        }
-        let event = PatchEvent::new(
-            config,
-            EventType::PatchInstallFailure,
-            patch.number,
-            Some(
-                format!(
-                    "Install failure reported from engine for patch {}",
-                    patch.number
-                )
-                .as_ref(),
-            ),
-        );
-        // Queue the failure event for later sending since right after this
-        // function returns the Flutter engine is likely to abort().
-        state.queue_event(event)
+        // Commit the boot failure together with the event reporting it.
+        state.transaction(|state| {
+            // Ignore the error here, we'll try to activate the next best patch
+            // even if we fail to mark this one as bad (because it was already bad).
+            let mark_result = state.record_boot_failure_for_patch(patch.number);
+            if mark_result.is_err() {
+                shorebird_error!("Failed to mark patch as bad: {:?}", mark_result);
+            }
+            let event = PatchEvent::new(
+                config,
+                EventType::PatchInstallFailure,
+                patch.number,
+                Some(
+                    format!(
+                        "Install failure reported from engine for patch {}",
+                        patch.number
+                    )
+                    .as_ref(),
+                ),
+            );
+            // Queue the failure event for later sending since right after this
+            // function returns the Flutter engine is likely to abort().
+            state.queue_event(event)
+        })
     })
 }
 
 pub fn report_launch_success() -> anyhow::Result<()> {
     shorebird_info!("Reporting successful launch.");
 
-    with_config(|config| {
+    with_config_and_storage_lock(|config| {
         // We can tell the UpdaterState that we have successfully booted from the "next" patch
         // and make that the "current" patch.
         let mut state = UpdaterState::load_or_new_on_error(
@@ -683,7 +1327,7 @@ pub fn start_update_thread() {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use serial_test::serial;
     use std::{fs, thread, time::Duration};
     use tempdir::TempDir;
@@ -692,7 +1336,7 @@ mod tests {
         cache::UpdaterState,
         config::{testing_reset_config, with_config},
         events::EventType,
-        network::{testing_set_network_hooks, NetworkHooks, PatchCheckResponse},
+        network::{testing_set_network_hooks, Connectivity, NetworkHooks, PatchCheckResponse},
         test_utils::{install_fake_patch, write_fake_apk},
         time, with_state, ExternalFileProvider, Patch,
     };
@@ -706,12 +1350,16 @@ mod tests {
     }
 
     pub fn init_for_testing(tmp_dir: &TempDir, base_url: Option<&str>) {
-        testing_reset_config();
-        let cache_dir = tmp_dir.path().to_str().unwrap().to_string();
         let mut yaml = "app_id: 1234".to_string();
         if let Some(url) = base_url {
             yaml += &format!("\nbase_url: {}", url);
         }
+        init_for_testing_with_yaml(tmp_dir, &yaml);
+    }
+
+    pub fn init_for_testing_with_yaml(tmp_dir: &TempDir, yaml: &str) {
+        testing_reset_config();
+        let cache_dir = tmp_dir.path().to_str().unwrap().to_string();
 
         let libapp_path = tmp_dir
             .path()
@@ -728,7 +1376,7 @@ mod tests {
                 original_libapp_paths: vec![libapp_path],
             },
             Box::new(FakeExternalFileProvider {}),
-            &yaml,
+            yaml,
         )
         .unwrap();
     }
@@ -858,6 +1506,80 @@ mod tests {
         Ok(())
     }
 
+    #[serial]
+    #[test]
+    fn reports_state_recovered_from_backup() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, None);
+
+        install_fake_patch(1)?;
+        install_fake_patch(2)?;
+        // Pretend the last write of each state file was cut short.
+        let storage_dir = with_config(|config| Ok(config.storage_dir.clone()))?;
+        fs::write(storage_dir.join("state.json"), "{")?;
+        fs::write(storage_dir.join("patches_state.json"), "{")?;
+
+        // Pretend we're starting the app a second time
+        init_for_testing(&tmp_dir, None);
+
+        super::with_mut_state(|state| {
+            // The previous generation of patches_state.json had patch 1.
+            assert_eq!(state.next_boot_patch().unwrap().number, 1);
+            let events = state.copy_events(3);
+            assert_eq!(events.len(), 2);
+            assert!(events
+                .iter()
+                .all(|event| event.identifier == EventType::StateRecovered));
+            assert_eq!(
+                events[0].message.as_deref(),
+                Some("Recovered state.json from backup")
+            );
+            assert_eq!(
+                events[1].message.as_deref(),
+                Some("Recovered patches_state.json from backup")
+            );
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_failure_from_error() {
+        use super::{CorruptDownload, UpdateError, UpdateFailure};
+
+        let tmp_dir = TempDir::new("example").unwrap();
+        let input_path = tmp_dir.path().join("input");
+        fs::write(&input_path, "hello world").unwrap();
+        let expected = "a94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let err = super::check_hash(&input_path, expected, &Default::default()).unwrap_err();
+        assert_eq!(UpdateFailure::from_error(&err), UpdateFailure::HashMismatch);
+        // Tags survive being wrapped in more context.
+        let err = err.context(CorruptDownload).context("Patch 1");
+        assert_eq!(UpdateFailure::from_error(&err), UpdateFailure::HashMismatch);
+
+        let err = anyhow::Error::new(UpdateError::BadServerResponse);
+        assert_eq!(
+            UpdateFailure::from_error(&err),
+            UpdateFailure::BadServerResponse
+        );
+
+        let err = anyhow::Error::new(crate::network::NetworkError::Connect).context("Checking");
+        assert_eq!(UpdateFailure::from_error(&err), UpdateFailure::Network);
+
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::StorageFull))
+            .context(UpdateFailure::InflateFailed);
+        assert_eq!(UpdateFailure::from_error(&err), UpdateFailure::DiskFull);
+
+        let err = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(UpdateFailure::from_error(&err), UpdateFailure::Storage);
+
+        assert_eq!(
+            UpdateFailure::from_error(&anyhow::anyhow!("Something else")),
+            UpdateFailure::Unknown
+        );
+    }
+
     #[test]
     fn hash_matches() {
         let tmp_dir = TempDir::new("example").unwrap();
@@ -866,13 +1588,13 @@ mod tests {
         fs::write(&input_path, "hello world").unwrap();
 
         let expected = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
-        assert!(super::check_hash(&input_path, expected).is_ok());
+        assert!(super::check_hash(&input_path, expected, &Default::default()).is_ok());
 
         // modify hash to not match
         let expected = "a94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
         // We don't check the full error string because it contains a path
         // which varies on each run.
-        assert!(super::check_hash(&input_path, expected)
+        assert!(super::check_hash(&input_path, expected, &Default::default())
             .unwrap_err()
             .to_string()
             .contains("Update rejected: hash mismatch. Update was downloaded"));
@@ -880,7 +1602,7 @@ mod tests {
         // invalid hashes should not match either
         let expected = "foo";
         assert_eq!(
-            super::check_hash(&input_path, expected)
+            super::check_hash(&input_path, expected, &Default::default())
                 .unwrap_err()
                 .to_string(),
             "Invalid hash string from server."
@@ -889,7 +1611,7 @@ mod tests {
         // Server used to send "#" and we'd allow it, but now we don't.
         let expected = "#";
         assert_eq!(
-            super::check_hash(&input_path, expected)
+            super::check_hash(&input_path, expected, &Default::default())
                 .unwrap_err()
                 .to_string(),
             "Invalid hash string from server."
@@ -933,6 +1655,8 @@ mod tests {
                 hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
                     .to_string(),
                 hash_signature: None,
+                size: None,
+                metadata: None,
             }),
             rolled_back_patch_numbers: Some(vec![2]),
         };
@@ -1007,6 +1731,8 @@ mod tests {
                 hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
                     .to_string(),
                 hash_signature: None,
+                size: None,
+                metadata: None,
             }),
             rolled_back_patch_numbers: Some(vec![2]),
         };
@@ -1148,6 +1874,8 @@ mod tests {
                 download_url: "download_url".to_string(),
                 hash: "hash".to_string(),
                 hash_signature: None,
+                size: None,
+                metadata: None,
             }),
             rolled_back_patch_numbers: None,
         };
@@ -1196,6 +1924,8 @@ mod tests {
                 hash: "#".to_string(),
                 download_url: "download_url".to_string(),
                 hash_signature: None,
+                size: None,
+                metadata: None,
             }),
             rolled_back_patch_numbers: None,
         };
@@ -1313,7 +2043,7 @@ mod tests {
                 // If we have not yet finished with the config lock, this test has failed.
                 unreachable!("If the test has not terminated before this, set_config is likely being blocked by a patch check request, which should not happen");
             },
-            download_file_fn: |_url| Ok([].to_vec()),
+            download_file_fn: |_request, _sink| Ok(()),
             report_event_fn: |_url, _event| Ok(()),
         };
 
@@ -1338,24 +2068,643 @@ mod tests {
         // take patch_check_delay (defined above) to complete and fail due to the unreachable!() in
         // the patch check callback.
     }
-}
+    #[cfg(unix)]
+    #[serial]
+    #[test]
+    fn storage_not_locked_while_waiting_for_patch_check() {
+        use std::os::unix::io::AsRawFd;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        static OTHER_PROCESS_LOCKED: AtomicBool = AtomicBool::new(false);
 
-#[cfg(test)]
-mod rollback_tests {
-    use anyhow::Result;
-    use serial_test::serial;
-    use tempdir::TempDir;
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, None);
 
-    use crate::{
-        network::PatchCheckResponse,
-        test_utils::{install_fake_patch, write_fake_apk},
-    };
+        testing_set_network_hooks(
+            |_url, _request| {
+                // Locks taken through a separately opened file exclude each
+                // other, so this is what another process booting would see.
+                let storage_dir = with_config(|config| Ok(config.storage_dir.clone()))?;
+                let file = fs::File::open(storage_dir.join("updater.lock"))?;
+                let locked =
+                    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+                OTHER_PROCESS_LOCKED.store(locked == 0, Ordering::SeqCst);
+                Ok(PatchCheckResponse {
+                    patch_available: false,
+                    patch: None,
+                    rolled_back_patch_numbers: None,
+                })
+            },
+            |_request, _sink| Ok(()),
+            |_url, _event| Ok(()),
+        );
 
-    use super::{
-        report_launch_start, report_launch_success, tests::init_for_testing, with_mut_state, Patch,
-    };
+        assert_eq!(super::update(None).unwrap(), crate::UpdateStatus::NoUpdate);
+        assert!(OTHER_PROCESS_LOCKED.load(Ordering::SeqCst));
+    }
 
-    #[serial]
+    fn mirror_yaml(primary: &mockito::Server, mirror: &mockito::Server) -> String {
+        format!(
+            "app_id: 1234\nbase_url: {}\nmirror_urls:\n  - {}\nretry:\n  max_attempts: 1",
+            primary.url(),
+            mirror.url()
+        )
+    }
+
+    #[serial]
+    #[test]
+    fn patch_check_fails_over_to_mirror() -> anyhow::Result<()> {
+        let mut primary = mockito::Server::new();
+        let mut mirror = mockito::Server::new();
+        let primary_check = primary
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(503)
+            .create();
+        let mirror_check = mirror
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(r#"{"patch_available": false}"#)
+            .expect(2)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, &mirror_yaml(&primary, &mirror));
+
+        assert_eq!(super::update(None)?, crate::UpdateStatus::NoUpdate);
+        with_state(|state| {
+            assert_eq!(state.preferred_base_url(), Some(mirror.url().as_str()));
+            Ok(())
+        })?;
+
+        // The next check starts with the mirror which worked last time.
+        assert_eq!(super::update(None)?, crate::UpdateStatus::NoUpdate);
+        primary_check.expect(1).assert();
+        mirror_check.assert();
+
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn patch_download_fails_over_to_mirror_on_corrupt_patch() -> anyhow::Result<()> {
+        let mut primary = mockito::Server::new();
+        let mut mirror = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                download_url: format!("{}/patch/1", primary.url()),
+                // Generated by `string_patch "hello world" "hello tests"`
+                hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                    .to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let _ = primary
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let _ = primary
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body("not a patch")
+            .create();
+        let _ = primary
+            .mock("POST", "/api/v1/patches/events")
+            .with_status(201)
+            .create();
+        let mirror_download = mirror
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body(
+                // Generated by `string_patch "hello world" "hello tests"`
+                [
+                    40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
+                    0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
+                ],
+            )
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(&tmp_dir, &mirror_yaml(&primary, &mirror));
+
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateInstalled);
+        mirror_download.assert();
+        with_state(|state| {
+            assert_eq!(state.preferred_base_url(), Some(mirror.url().as_str()));
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn update_is_throttled_within_check_interval() -> anyhow::Result<()> {
+        use mock_instant::global::MockClock;
+
+        let mut server = mockito::Server::new();
+        let check_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(r#"{"patch_available": false}"#)
+            .expect(3)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(
+            &tmp_dir,
+            &format!(
+                "app_id: 1234\nbase_url: {}\ncheck_interval_seconds: 60",
+                server.url()
+            ),
+        );
+
+        MockClock::set_system_time(Duration::from_secs(1000));
+        assert_eq!(super::update(None)?, crate::UpdateStatus::NoUpdate);
+        MockClock::set_system_time(Duration::from_secs(1059));
+        assert_eq!(super::update(None)?, crate::UpdateStatus::Throttled);
+        // Forcing an update ignores the interval.
+        assert_eq!(super::force_update(None)?, crate::UpdateStatus::NoUpdate);
+        MockClock::set_system_time(Duration::from_secs(1120));
+        assert_eq!(super::update(None)?, crate::UpdateStatus::NoUpdate);
+
+        check_mock.assert();
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn checking_for_details_does_not_throttle_update() -> anyhow::Result<()> {
+        use mock_instant::global::MockClock;
+
+        let mut server = mockito::Server::new();
+        let check_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(r#"{"patch_available": false}"#)
+            .expect(2)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(
+            &tmp_dir,
+            &format!(
+                "app_id: 1234\nbase_url: {}\ncheck_interval_seconds: 60",
+                server.url()
+            ),
+        );
+
+        MockClock::set_system_time(Duration::from_secs(1000));
+        assert!(!crate::check_for_update_details(None)?.update_available);
+        MockClock::set_system_time(Duration::from_secs(1001));
+        assert_eq!(super::update(None)?, crate::UpdateStatus::NoUpdate);
+
+        check_mock.assert();
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn download_is_deferred_until_unmetered() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                download_url: format!("{}/patch/1", server.url()),
+                // Generated by `string_patch "hello world" "hello tests"`
+                hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                    .to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let _ = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let download_mock = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body(
+                // Generated by `string_patch "hello world" "hello tests"`
+                [
+                    40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
+                    0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
+                ],
+            )
+            .create();
+        let _ = server
+            .mock("POST", "/api/v1/patches/events")
+            .with_status(201)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing_with_yaml(
+            &tmp_dir,
+            &format!(
+                "app_id: 1234\nbase_url: {}\ndownload_policy: unmetered_only",
+                server.url()
+            ),
+        );
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+
+        // We don't know what the connection is, so must assume it's metered.
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateDeferred);
+        crate::config::set_connectivity(Connectivity::Metered)?;
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateDeferred);
+        download_mock.expect(0).assert();
+
+        crate::config::set_connectivity(Connectivity::Unmetered)?;
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateInstalled);
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn update_reports_progress() -> anyhow::Result<()> {
+        use crate::progress::tests::RecordingObserver;
+        use crate::UpdatePhase;
+
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                download_url: format!("{}/patch/1", server.url()),
+                // Generated by `string_patch "hello world" "hello tests"`
+                hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                    .to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let _ = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body(
+                // Generated by `string_patch "hello world" "hello tests"`
+                [
+                    40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
+                    0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
+                ],
+            )
+            .create();
+        let _ = server
+            .mock("POST", "/api/v1/patches/events")
+            .with_status(201)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, Some(&server.url()));
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+
+        let observer = std::sync::Arc::new(RecordingObserver::default());
+        super::set_progress_observer(Some(observer.clone()))?;
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateInstalled);
+
+        assert_eq!(
+            observer.phases(),
+            vec![
+                UpdatePhase::Checking,
+                UpdatePhase::Downloading,
+                UpdatePhase::Inflating,
+                UpdatePhase::Verifying,
+                UpdatePhase::Installing,
+            ]
+        );
+        let progress = observer.0.lock().unwrap();
+        let last_download = progress
+            .iter()
+            .rfind(|p| p.phase == UpdatePhase::Downloading)
+            .unwrap();
+        assert_eq!(last_download.bytes_received, 31);
+        assert_eq!(last_download.total_bytes, Some(31));
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn cancelled_update_removes_partial_files() -> anyhow::Result<()> {
+        use crate::progress::UpdateProgress;
+        use crate::UpdatePhase;
+
+        /// Cancels the update as soon as any of the patch has downloaded.
+        #[derive(Debug)]
+        struct CancelOnDownload;
+
+        impl super::ProgressObserver for CancelOnDownload {
+            fn on_progress(&self, progress: UpdateProgress) {
+                if progress.phase == UpdatePhase::Downloading && progress.bytes_received > 0 {
+                    assert!(super::cancel_update());
+                }
+            }
+        }
+
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                download_url: format!("{}/patch/1", server.url()),
+                // Generated by `string_patch "hello world" "hello tests"`
+                hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                    .to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let _ = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body(
+                // Generated by `string_patch "hello world" "hello tests"`
+                [
+                    40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
+                    0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
+                ],
+            )
+            .create();
+        let _ = server
+            .mock("POST", "/api/v1/patches/events")
+            .with_status(201)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, Some(&server.url()));
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+
+        assert!(!super::cancel_update());
+        super::set_progress_observer(Some(std::sync::Arc::new(CancelOnDownload)))?;
+        assert_eq!(super::update(None)?, crate::UpdateStatus::Cancelled);
+
+        let download_dir = with_config(|config| Ok(config.download_dir.clone()))?;
+        let leftovers: Vec<_> = std::fs::read_dir(&download_dir)
+            .map(|entries| entries.map(|e| e.unwrap().path()).collect())
+            .unwrap_or_default();
+        assert!(leftovers.is_empty(), "Left behind {:?}", leftovers);
+        assert!(!super::cancel_update());
+
+        // Cancelling one update doesn't affect the next.
+        super::set_progress_observer(None)?;
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateInstalled);
+        Ok(())
+    }
+
+
+    #[serial]
+    #[test]
+    fn running_update_describes_update_in_progress() -> anyhow::Result<()> {
+        use crate::progress::UpdateProgress;
+        use mock_instant::global::MockClock;
+        use crate::UpdatePhase;
+        use std::sync::Mutex;
+
+        /// Records what `running_update` says while the patch downloads.
+        #[derive(Debug, Default)]
+        struct StatusRecorder(Mutex<Vec<Option<super::RunningUpdate>>>);
+
+        impl super::ProgressObserver for StatusRecorder {
+            fn on_progress(&self, progress: UpdateProgress) {
+                if progress.phase == UpdatePhase::Downloading {
+                    self.0.lock().unwrap().push(super::running_update());
+                }
+            }
+        }
+
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                download_url: format!("{}/patch/1", server.url()),
+                // Generated by `string_patch "hello world" "hello tests"`
+                hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                    .to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let _ = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body(
+                // Generated by `string_patch "hello world" "hello tests"`
+                [
+                    40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
+                    0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
+                ],
+            )
+            .create();
+        let _ = server
+            .mock("POST", "/api/v1/patches/events")
+            .with_status(201)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, Some(&server.url()));
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+        MockClock::set_system_time(Duration::from_secs(1_000));
+
+        assert_eq!(super::running_update(), None);
+        let recorder = std::sync::Arc::new(StatusRecorder::default());
+        super::set_progress_observer(Some(recorder.clone()))?;
+        assert_eq!(super::update(None)?, crate::UpdateStatus::UpdateInstalled);
+
+        let statuses = recorder.0.lock().unwrap();
+        assert!(!statuses.is_empty());
+        for status in statuses.iter() {
+            assert_eq!(
+                *status,
+                Some(super::RunningUpdate {
+                    phase: UpdatePhase::Downloading,
+                    started_at: 1_000,
+                    patch_number: Some(1),
+                })
+            );
+        }
+        assert_eq!(super::running_update(), None);
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn update_or_join_waits_for_running_update() -> anyhow::Result<()> {
+        use crate::progress::UpdateProgress;
+        use crate::UpdatePhase;
+        use std::sync::{mpsc, Mutex};
+
+        /// Holds the update mid-download until released.
+        #[derive(Debug)]
+        struct HoldDownload {
+            started: Mutex<mpsc::Sender<()>>,
+            release: Mutex<mpsc::Receiver<()>>,
+        }
+
+        impl super::ProgressObserver for HoldDownload {
+            fn on_progress(&self, progress: UpdateProgress) {
+                if progress.phase == UpdatePhase::Downloading && progress.bytes_received == 0 {
+                    let _ = self.started.lock().unwrap().send(());
+                    let _ = self.release.lock().unwrap().recv();
+                }
+            }
+        }
+
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                download_url: format!("{}/patch/1", server.url()),
+                // Generated by `string_patch "hello world" "hello tests"`
+                hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
+                    .to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let _ = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let _ = server
+            .mock("GET", "/patch/1")
+            .with_status(200)
+            .with_body(
+                // Generated by `string_patch "hello world" "hello tests"`
+                [
+                    40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0,
+                    0, 0, 0, 5, 116, 101, 115, 116, 115, 0,
+                ],
+            )
+            .create();
+        let _ = server
+            .mock("POST", "/api/v1/patches/events")
+            .with_status(201)
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, Some(&server.url()));
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        super::set_progress_observer(Some(std::sync::Arc::new(HoldDownload {
+            started: Mutex::new(started_tx),
+            release: Mutex::new(release_rx),
+        })))?;
+        let running = thread::spawn(|| super::update(None));
+        started_rx.recv()?;
+
+        assert!(super::update(None).is_err());
+        let err = super::update_or_join(None, Some(Duration::from_millis(10))).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&super::UpdateError::TimedOut));
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            release_tx.send(()).unwrap();
+        });
+        assert_eq!(
+            super::update_or_join(None, None)?,
+            crate::UpdateStatus::UpdateInstalled
+        );
+        assert_eq!(running.join().unwrap()?, crate::UpdateStatus::UpdateInstalled);
+        releaser.join().unwrap();
+        super::set_progress_observer(None)?;
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn install_patch_from_file_installs_like_a_download() -> anyhow::Result<()> {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, None);
+        let apk_path = tmp_dir.path().join("base.apk");
+        write_fake_apk(apk_path.to_str().unwrap(), "hello world".as_bytes());
+        let patch_path = tmp_dir.path().join("sideloaded.vmcode");
+        // Generated by `string_patch "hello world" "hello tests"`
+        fs::write(
+            &patch_path,
+            [
+                40, 181, 47, 253, 0, 128, 177, 0, 0, 223, 177, 0, 0, 0, 16, 0, 0, 6, 0, 0, 0, 0,
+                0, 0, 5, 116, 101, 115, 116, 115, 0,
+            ],
+        )?;
+        let hash = "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45";
+
+        // A patch which doesn't match its hash isn't installed.
+        let wrong_hash = "a94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let err = super::install_patch_from_file(&patch_path, 1, wrong_hash, None).unwrap_err();
+        assert_eq!(
+            super::UpdateFailure::from_error(&err),
+            super::UpdateFailure::HashMismatch
+        );
+        assert!(super::next_boot_patch()?.is_none());
+
+        assert_eq!(
+            super::install_patch_from_file(&patch_path, 1, hash, None)?,
+            crate::UpdateStatus::UpdateInstalled
+        );
+        let next_boot_patch = super::next_boot_patch()?.unwrap();
+        assert_eq!(next_boot_patch.number, 1);
+        assert_eq!(fs::read_to_string(next_boot_patch.path)?, "hello tests");
+        // The caller's file is left alone.
+        assert!(patch_path.exists());
+
+        assert_eq!(
+            super::install_patch_from_file(&patch_path, 1, hash, None)?,
+            crate::UpdateStatus::NoUpdate
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rollback_tests {
+    use anyhow::Result;
+    use serial_test::serial;
+    use tempdir::TempDir;
+
+    use crate::{
+        network::PatchCheckResponse,
+        test_utils::{install_fake_patch, write_fake_apk},
+    };
+
+    use super::{
+        report_launch_start, report_launch_success, tests::init_for_testing, with_mut_state, Patch,
+    };
+
+    #[serial]
     #[test]
     fn does_not_roll_back_when_rolled_back_patches_is_empty() -> Result<()> {
         let mut server = mockito::Server::new();
@@ -1392,6 +2741,55 @@ mod rollback_tests {
         Ok(())
     }
 
+    #[serial]
+    #[test]
+    fn manually_rolls_back_to_last_booted_patch() -> Result<()> {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, None);
+
+        install_fake_patch(1)?;
+        report_launch_start()?;
+        report_launch_success()?;
+        install_fake_patch(2)?;
+
+        assert_eq!(super::roll_back_to_last_booted_patch(true)?, Some(2));
+        assert_eq!(super::next_boot_patch()?.map(|p| p.number), Some(1));
+        with_mut_state(|state| {
+            assert!(state.is_known_bad_patch(2));
+            Ok(())
+        })?;
+
+        // Patch 1 is what we launched last, so there's nothing to roll back.
+        assert_eq!(super::roll_back_to_last_booted_patch(true)?, None);
+        assert_eq!(super::next_boot_patch()?.map(|p| p.number), Some(1));
+
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn manually_rolls_back_to_release() -> Result<()> {
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, None);
+
+        install_fake_patch(1)?;
+        report_launch_start()?;
+        report_launch_success()?;
+        install_fake_patch(2)?;
+
+        assert_eq!(super::roll_back_to_release(false)?, vec![2, 1]);
+        assert!(super::next_boot_patch()?.is_none());
+        with_mut_state(|state| {
+            assert!(state.last_successfully_booted_patch().is_none());
+            // Without mark_bad, the patches can be installed again later.
+            assert!(!state.is_known_bad_patch(1));
+            assert!(!state.is_known_bad_patch(2));
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
     /// If the next_boot_patch is rolled back, the updater should roll back to the release version
     /// if no other patches are available on disk.
     #[serial]
@@ -1447,6 +2845,8 @@ mod rollback_tests {
                 hash: "bb8f1d041a5cdc259055afe9617136799543e0a7a86f86db82f8c1fadbd8cc45"
                     .to_string(),
                 hash_signature: None,
+                size: None,
+                metadata: None,
             }),
             rolled_back_patch_numbers: Some(vec![2]),
         };
@@ -1530,6 +2930,8 @@ mod check_for_downloadable_update_tests {
                 hash: "#".to_string(),
                 download_url: "download_url".to_string(),
                 hash_signature: None,
+                size: None,
+                metadata: None,
             }),
             rolled_back_patch_numbers,
         };
@@ -1633,4 +3035,115 @@ mod check_for_downloadable_update_tests {
 
         Ok(())
     }
+
+    #[serial]
+    #[test]
+    fn details_describe_available_patch() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let _ = server
+            .mock("POST", "/api/v1/patches/check")
+            .with_status(200)
+            .with_body(
+                r#"{
+                    "patch_available": true,
+                    "patch": {
+                        "number": 2,
+                        "hash": "abc",
+                        "download_url": "download_url",
+                        "hash_signature": "signature",
+                        "size": 1234,
+                        "metadata": {"release_notes": "Fixed a crash."}
+                    },
+                    "rolled_back_patch_numbers": [1]
+                }"#,
+            )
+            .create();
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, Some(&server.url()));
+
+        let details = crate::check_for_update_details(None)?;
+        assert_eq!(
+            details,
+            crate::UpdateDetails {
+                update_available: true,
+                patch: Some(crate::PatchDetails {
+                    number: 2,
+                    size: Some(1234),
+                    hash: "abc".to_string(),
+                    is_signed: true,
+                    is_known_bad: false,
+                    is_already_installed: false,
+                    metadata: Some(serde_json::json!({"release_notes": "Fixed a crash."})),
+                }),
+                rolled_back_patch_numbers: vec![1],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn details_flag_known_bad_patch() -> Result<()> {
+        let patch_number = 1;
+        let server = mock_server(Some(patch_number), None);
+        let tmp_dir = TempDir::new("example").unwrap();
+        init_for_testing(&tmp_dir, Some(&server.url()));
+
+        install_fake_patch(patch_number)?;
+        report_launch_start()?;
+        report_launch_failure()?;
+
+        let details = crate::check_for_update_details(None)?;
+        assert!(!details.update_available);
+        let patch = details.patch.unwrap();
+        assert!(patch.is_known_bad);
+        assert!(!patch.is_signed);
+        assert_eq!(patch.size, None);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[test]
+    fn uses_cached_response_when_not_modified() -> Result<()> {
+        let mut server = mockito::Server::new();
+        let check_response = PatchCheckResponse {
+            patch_available: true,
+            patch: Some(Patch {
+                number: 1,
+                hash: "#".to_string(),
+                download_url: "download_url".to_string(),
+                hash_signature: None,
+                size: None,
+                metadata: None,
+            }),
+            rolled_back_patch_numbers: None,
+        };
+        let modified_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .match_header("if-none-match", mockito::Matcher::Missing)
+            .with_status(200)
+            .with_header("etag", "\"v1\"")
+            .with_body(serde_json::to_string(&check_response).unwrap())
+            .create();
+        let not_modified_mock = server
+            .mock("POST", "/api/v1/patches/check")
+            .match_header("if-none-match", "\"v1\"")
+            .with_status(304)
+            .expect(2)
+            .create();
+        let tmp_dir = TempDir::new("example")?;
+        init_for_testing(&tmp_dir, Some(&server.url()));
+
+        assert!(super::check_for_downloadable_update(None)?);
+        // Answered with 304s, so we need the cached response to know a patch
+        // is available.
+        assert!(super::check_for_downloadable_update(None)?);
+        assert!(super::check_for_downloadable_update(None)?);
+
+        modified_mock.assert();
+        not_modified_mock.assert();
+        Ok(())
+    }
 }
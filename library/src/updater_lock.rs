@@ -1,4 +1,10 @@
-use crate::updater::UpdateError;
+use std::io::Read;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::progress::{ProgressObserver, ProgressReporter, UpdatePhase, UpdateProgress};
+use crate::time;
+use crate::updater::{UpdateError, UpdateFailure, UpdateStatus};
 
 // This file's job is to handle the boilerplate around locking for the
 // updater thread.
@@ -15,6 +21,54 @@ fn updater_lock() -> &'static std::sync::Mutex<UpdaterLockState> {
     INSTANCE.get_or_init(|| Mutex::new(UpdaterLockState::empty()))
 }
 
+/// A copy of the state of the update in progress, if any. Kept outside of the
+/// Updater lock so that other threads can reach it while the update holds
+/// that lock.
+fn running_update() -> &'static Mutex<Option<UpdaterLockState>> {
+    use once_cell::sync::OnceCell;
+    static INSTANCE: OnceCell<Mutex<Option<UpdaterLockState>>> = OnceCell::new();
+    INSTANCE.get_or_init(|| Mutex::new(None))
+}
+
+/// Asks the update in progress (if any) to stop. Returns whether there was
+/// one to ask. The update stops the next time it checks its token, so it may
+/// still finish if it was nearly done.
+pub fn cancel_running_update() -> bool {
+    let running = running_update()
+        .lock()
+        .expect("Failed to acquire running update lock.");
+    match running.as_ref() {
+        Some(state) => {
+            state.cancellation.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Describes the update in progress, or returns None if there isn't one.
+/// Never waits for the update.
+pub fn running_update_status() -> Option<RunningUpdate> {
+    running_update()
+        .lock()
+        .expect("Failed to acquire running update lock.")
+        .as_ref()
+        .map(UpdaterLockState::status)
+}
+
+/// Waits for the update in progress, if any, to finish and returns its result.
+/// Returns None if no update was running. Waits forever if `timeout` is None,
+/// otherwise returns an [`UpdateError::TimedOut`] error once it has passed.
+pub fn wait_for_running_update(timeout: Option<Duration>) -> Option<anyhow::Result<UpdateStatus>> {
+    // Don't hold the running_update lock while waiting, the update needs it
+    // to finish.
+    let running = running_update()
+        .lock()
+        .expect("Failed to acquire running update lock.")
+        .clone()?;
+    Some(running.wait(timeout))
+}
+
 // Note: it is not OK to ever ask for the Updater lock *while* holding the
 // UpdateConfig lock because the updater thread *will* block on getting the
 // UpdateConfig lock while holding the Updater lock.  Allowing the inverse could
@@ -31,7 +85,15 @@ where
     // of lock to error out immediately.
     let lock = updater_lock().try_lock();
     match lock {
-        Ok(lock) => f(&lock),
+        Ok(mut lock) => {
+            // Each update gets fresh state so that cancelling one update
+            // can't affect the next.
+            *lock = UpdaterLockState::started_at(time::unix_timestamp());
+            set_running_update(Some(lock.clone()));
+            let result = f(&lock);
+            set_running_update(None);
+            result
+        }
         Err(std::sync::TryLockError::WouldBlock) => {
             anyhow::bail!(UpdateError::UpdateAlreadyInProgress)
         }
@@ -44,15 +106,225 @@ where
     }
 }
 
-#[derive(Debug)]
+fn set_running_update(state: Option<UpdaterLockState>) {
+    *running_update()
+        .lock()
+        .expect("Failed to acquire running update lock.") = state;
+}
+
+/// What an update in progress is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunningUpdate {
+    pub phase: UpdatePhase,
+    /// When the update started, in seconds since the Unix epoch.
+    pub started_at: u64,
+    /// The patch being installed, once the server has told us about one.
+    pub patch_number: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
 pub struct UpdaterLockState {
     // This is held by the thread doing the update, not by the thread launching
     // the update.  This is because in the case of start_update_thread, we
     // don't want to block on the calling thread while the update is running.
+
+    /// Set when someone asks for the update in progress to stop.
+    pub cancellation: CancellationToken,
+    /// Shared with `running_update()` so that it can be queried while the
+    /// update runs.
+    status: Arc<Mutex<RunningUpdate>>,
+    /// The result of the update once it has finished.
+    completion: Arc<Completion>,
+}
+
+/// An update's result, for anyone waiting on it. Errors are kept as why the
+/// update failed and their message, since `anyhow::Error` can't be shared.
+#[derive(Debug, Default)]
+struct Completion {
+    result: Mutex<Option<Result<UpdateStatus, (UpdateFailure, String)>>>,
+    finished: Condvar,
 }
 
 impl UpdaterLockState {
     pub fn empty() -> Self {
-        Self {}
+        Self::started_at(0)
+    }
+
+    fn started_at(started_at: u64) -> Self {
+        Self {
+            cancellation: CancellationToken::default(),
+            status: Arc::new(Mutex::new(RunningUpdate {
+                phase: UpdatePhase::Checking,
+                started_at,
+                patch_number: None,
+            })),
+            completion: Arc::default(),
+        }
+    }
+
+    /// Shares `result` with anyone waiting on this update and returns it.
+    pub fn finish(&self, result: anyhow::Result<UpdateStatus>) -> anyhow::Result<UpdateStatus> {
+        let shared = match &result {
+            Ok(status) => Ok(*status),
+            Err(err) => Err((UpdateFailure::from_error(err), format!("{err:#}"))),
+        };
+        *self
+            .completion
+            .result
+            .lock()
+            .expect("Failed to acquire update result lock.") = Some(shared);
+        self.completion.finished.notify_all();
+        result
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> anyhow::Result<UpdateStatus> {
+        let result = self
+            .completion
+            .result
+            .lock()
+            .expect("Failed to acquire update result lock.");
+        let finished = &self.completion.finished;
+        let result = match timeout {
+            Some(timeout) => {
+                finished
+                    .wait_timeout_while(result, timeout, |result| result.is_none())
+                    .expect("Failed to acquire update result lock.")
+                    .0
+            }
+            None => finished
+                .wait_while(result, |result| result.is_none())
+                .expect("Failed to acquire update result lock."),
+        };
+        match result.as_ref() {
+            Some(Ok(status)) => Ok(*status),
+            // Keep the reason, so that whoever joined the update can tell
+            // why it failed.
+            Some(Err((failure, message))) => {
+                Err(anyhow::Error::new(*failure).context(message.clone()))
+            }
+            None => anyhow::bail!(UpdateError::TimedOut),
+        }
+    }
+
+    pub fn status(&self) -> RunningUpdate {
+        *self.status.lock().expect("Failed to acquire update status lock.")
+    }
+
+    pub fn set_patch_number(&self, patch_number: usize) {
+        self.status
+            .lock()
+            .expect("Failed to acquire update status lock.")
+            .patch_number = Some(patch_number);
+    }
+
+    /// Returns a reporter which records the phase of this update before
+    /// passing progress on to `progress`.
+    pub fn track_progress(&self, progress: ProgressReporter) -> ProgressReporter {
+        ProgressReporter::new(Some(Arc::new(PhaseTracker {
+            status: self.status.clone(),
+            next: progress,
+        })))
+    }
+}
+
+#[derive(Debug)]
+struct PhaseTracker {
+    status: Arc<Mutex<RunningUpdate>>,
+    next: ProgressReporter,
+}
+
+impl ProgressObserver for PhaseTracker {
+    fn on_progress(&self, progress: UpdateProgress) {
+        self.status
+            .lock()
+            .expect("Failed to acquire update status lock.")
+            .phase = progress.phase;
+        self.next
+            .report(progress.phase, progress.bytes_received, progress.total_bytes);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// Shared flag an update checks between chunks of work to see whether it
+/// has been asked to stop.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<Cancellation>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        *self
+            .0
+            .cancelled
+            .lock()
+            .expect("Failed to acquire cancellation lock.") = true;
+        self.0.condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self
+            .0
+            .cancelled
+            .lock()
+            .expect("Failed to acquire cancellation lock.")
+    }
+
+    /// Waits for `timeout`, returning early with an [`UpdateError::Cancelled`]
+    /// error if cancelled, e.g. to back off between attempts without delaying
+    /// cancellation.
+    pub fn sleep(&self, timeout: Duration) -> anyhow::Result<()> {
+        let cancelled = self
+            .0
+            .cancelled
+            .lock()
+            .expect("Failed to acquire cancellation lock.");
+        let (cancelled, _) = self
+            .0
+            .condvar
+            .wait_timeout_while(cancelled, timeout, |cancelled| !*cancelled)
+            .expect("Failed to acquire cancellation lock.");
+        drop(cancelled);
+        self.check()
+    }
+
+    /// Returns an [`UpdateError::Cancelled`] error if cancelled.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.is_cancelled() {
+            anyhow::bail!(UpdateError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// Whether `error` was caused by the update being cancelled.
+pub fn is_cancelled_error(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| matches!(cause.downcast_ref(), Some(UpdateError::Cancelled)))
+}
+
+/// A reader which fails once `token` is cancelled, so that long reads of a
+/// file can be abandoned part way through.
+pub struct CancellableReader<R> {
+    inner: R,
+    token: CancellationToken,
+}
+
+impl<R: Read> CancellableReader<R> {
+    pub fn new(inner: R, token: CancellationToken) -> Self {
+        Self { inner, token }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.token.is_cancelled() {
+            return Err(std::io::Error::other(UpdateError::Cancelled));
+        }
+        self.inner.read(buf)
     }
 }
@@ -1,5 +1,9 @@
+use std::collections::BTreeMap;
+
 use serde::Deserialize;
 
+use crate::network::DownloadPolicy;
+
 /// Struct for parsing shorebird.yaml.
 #[derive(Deserialize)]
 pub struct YamlConfig {
@@ -10,10 +14,72 @@ pub struct YamlConfig {
     pub channel: Option<String>,
     /// Update URL.  Defaults to the default update URL if not set.
     pub base_url: Option<String>,
+    /// Additional base URLs (e.g. CDN mirrors) to try, in order, if
+    /// `base_url` can't be reached or serves a bad patch.
+    pub mirror_urls: Option<Vec<String>>,
     /// Update behavior. Defaults to true if not set.
     pub auto_update: Option<bool>,
     /// Base64-encoded public key for verifying patch hash signatures.
     pub patch_public_key: Option<String>,
+    /// Maximum size in bytes of a patch download. Downloads larger than this
+    /// are aborted. Defaults to 100MB if not set.
+    pub max_download_bytes: Option<u64>,
+    /// Minimum number of seconds between automatic patch checks. Checks
+    /// within this interval of the last one are skipped. Defaults to 0 (check
+    /// every time) if not set.
+    pub check_interval_seconds: Option<u64>,
+    /// How many minutes apart the background update scheduler checks for
+    /// patches, once the host starts it. The scheduler can't be started if
+    /// this is not set (or is 0).
+    pub scheduled_check_minutes: Option<u64>,
+    /// Which connections patches may be downloaded over: `any` or
+    /// `unmetered_only`. Defaults to `any` if not set. With `unmetered_only`
+    /// the host must report the device's connectivity, otherwise patches are
+    /// never downloaded.
+    pub download_policy: Option<DownloadPolicy>,
+    /// How to retry failed network requests. Uses the default retry policy
+    /// for anything not set.
+    pub retry: Option<RetryConfig>,
+    /// Network request timeouts. Uses the default timeouts for anything not
+    /// set.
+    pub timeouts: Option<TimeoutConfig>,
+    /// Extra HTTP headers to send with every request (patch checks, downloads
+    /// and events), e.g. an API key for a self-hosted update server.
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+/// The `retry` section of shorebird.yaml.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RetryConfig {
+    /// Total number of attempts per request, including the first. Defaults
+    /// to 3. Set to 1 to disable retries.
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry in milliseconds, doubling with each
+    /// further retry. Defaults to 500.
+    pub base_delay_ms: Option<u64>,
+    /// Longest delay between attempts in milliseconds. Defaults to 30000.
+    pub max_delay_ms: Option<u64>,
+    /// Fraction (0.0 - 1.0) of each delay to randomize. Defaults to 0.5.
+    pub jitter: Option<f64>,
+    /// HTTP status codes to retry. Defaults to 408, 429, 500, 502, 503 and
+    /// 504.
+    pub retryable_status_codes: Option<Vec<u16>>,
+    /// Whether to retry connection failures and timeouts. Defaults to true.
+    pub retry_connection_errors: Option<bool>,
+}
+
+/// The `timeouts` section of shorebird.yaml.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct TimeoutConfig {
+    /// Time allowed to connect to the server in milliseconds. Defaults to
+    /// 10000.
+    pub connect_ms: Option<u64>,
+    /// Time allowed for any single read from the server in milliseconds.
+    /// Defaults to 30000.
+    pub read_ms: Option<u64>,
+    /// Time allowed for a whole request, including receiving the response
+    /// body, in milliseconds. Defaults to 300000.
+    pub total_ms: Option<u64>,
 }
 
 impl YamlConfig {